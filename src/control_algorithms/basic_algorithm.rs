@@ -1,2 +1,236 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2024 Tuna Gül
+
+// Collective control, the reference every other dispatcher is compared with.
+// A car sweeps in one direction and stops at every car call and at every hall
+// call of its direction on the way (SCAN/LOOK of the disk heads):
+//   - LOOK turns around at the last call of the sweep
+//   - SCAN goes on to the last floor of the building before it turns
+// The hall calls on the way back are picked up on the way back, at the turning
+// point the car takes the farthest call of the other direction first.
+// A new hall call goes to the car that gets there with the shortest travel
+// (nearest car): a car that comes towards the call in the same direction only
+// has the distance to go, the others have to finish their sweep first. Every
+// stop the car already has costs some meters more.
+
+use serde::Deserialize;
+
+use super::{Assignment, Direction, DispatchState, Dispatcher, HallCall};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Sweep {
+    Scan,
+    #[default]
+    Look,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct CollectiveControl {
+    pub sweep: Sweep,
+    pub stop_cost: f32,    // meters of travel a planned stop is worth
+    pub free_load: f32,    // a car with less room than this (kg) is full
+}
+
+impl Default for CollectiveControl {
+    fn default() -> Self {
+        Self { sweep: Sweep::Look, stop_cost: 3., free_load: 80. }
+    }
+}
+
+impl CollectiveControl {
+    pub fn new(sweep: Sweep) -> Self {
+        Self { sweep, ..Default::default() }
+    }
+
+    // last floor of the building in the given direction
+    fn terminal(direction: Direction, state: &DispatchState) -> usize {
+        match direction {
+            Direction::Up => state.floors.len() - 1,
+            Direction::Down => 0,
+        }
+    }
+
+    // meters the car travels until it can answer the call
+    pub fn travel_distance(&self, car: usize, call: &HallCall, state: &DispatchState) -> f32 {
+        let reach = state.reach(car);
+        let height = state.floors[call.floor];
+        let Some(direction) = state.cars[car].direction else {
+            return (height - reach).abs();
+        };
+        let sign = direction.sign();
+        if call.direction == direction && (height - reach) * sign >= -0.01 {
+            return (height - reach).abs();
+        }
+
+        // to the end of the sweep and back to the call
+        let mut end = match self.sweep {
+            Sweep::Look => reach,
+            Sweep::Scan => state.floors[Self::terminal(direction, state)],
+        };
+        for floor in state.work_floors(car) {
+            if (state.floors[floor] - end) * sign > 0. {
+                end = state.floors[floor];
+            }
+        }
+        if call.direction != direction && (height - end) * sign > 0. {
+            end = height;
+        }
+        (end - reach).abs() + (end - height).abs()
+    }
+
+    fn cost(&self, car: usize, call: &HallCall, state: &DispatchState) -> f32 {
+        let elevator = state.cars[car].elevator;
+        let mut cost = self.travel_distance(car, call, state)
+            + self.stop_cost * state.work_floors(car).len() as f32;
+        if elevator.max_load - elevator.current_load < self.free_load {
+            // it would pass by full, going around the building is better
+            let height = state.floors.last().copied().unwrap_or(0.) - state.floors.first().copied().unwrap_or(0.);
+            cost += 2. * height;
+        }
+        cost
+    }
+
+    // the next stop of a sweep in the given direction, None when there is
+    // nothing to do that way. With here the floor the car stands on counts
+    fn sweep_stop(&self, car: usize, direction: Direction, here: bool, state: &DispatchState) -> Option<usize> {
+        let reach = state.reach(car);
+        let sign = direction.sign();
+        let ahead = |floor: usize| if here {
+            (state.floors[floor] - reach) * sign > -0.01
+        } else {
+            state.is_ahead(car, floor, direction)
+        };
+        let distance = |floor: &usize| (state.floors[*floor] - reach).abs();
+
+        // car calls and the hall calls of this direction on the way
+        let on_the_way = state.car_calls.iter()
+            .filter(|call| call.car == car)
+            .map(|call| call.floor)
+            .chain(state.landing_calls(car)
+                .filter(|(_, call_direction)| *call_direction == direction)
+                .map(|(floor, _)| floor))
+            .filter(|floor| ahead(*floor))
+            .min_by(|a, b| distance(a).total_cmp(&distance(b)));
+        if on_the_way.is_some() {
+            return on_the_way;
+        }
+
+        // calls of the other direction, the farthest one is where it turns
+        let farthest = state.landing_calls(car)
+            .map(|(floor, _)| floor)
+            .filter(|floor| ahead(*floor))
+            .max_by(|a, b| distance(a).total_cmp(&distance(b)));
+        match self.sweep {
+            Sweep::Look => farthest,
+            Sweep::Scan => farthest.map(|_| Self::terminal(direction, state)),
+        }
+    }
+}
+
+impl Dispatcher for CollectiveControl {
+    fn assign(&mut self, state: &DispatchState) -> Vec<Assignment> {
+        state.cheapest_cars(|car, call| self.cost(car, call, state))
+    }
+
+    fn next_stop(&mut self, car: usize, state: &DispatchState) -> Option<usize> {
+        let work = state.work_floors(car);
+        if work.is_empty() {
+            return None;
+        }
+        match state.cars[car].direction {
+            Some(direction) => self.sweep_stop(car, direction, false, state)
+                .or_else(|| self.sweep_stop(car, direction.opposite(), true, state)),
+            None => {
+                let reach = state.reach(car);
+                work.into_iter().min_by(|a, b| {
+                    (state.floors[*a] - reach).abs().total_cmp(&(state.floors[*b] - reach).abs())
+                })
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_algorithms::CarCall;
+    use crate::test_fixtures::{car, dispatch_state, floors};
+    use crate::machine::elevator_system::{CarStatus, ElevatorSystem};
+    use crate::population::population::{Passenger, PassengerState};
+
+    fn call(floor: usize, direction: Direction, car: Option<usize>) -> HallCall {
+        HallCall { floor, direction, time: 0., car }
+    }
+
+    #[test]
+    fn look_sweeps() {
+        let floors = floors(10);
+        let elevator = car(&floors, 4);
+        let hall_calls = [
+            call(6, Direction::Down, Some(0)),
+            call(8, Direction::Down, Some(0)),
+            call(2, Direction::Up, Some(0)),
+        ];
+        let car_calls = [CarCall { car: 0, floor: 7 }];
+        let state = |direction| {
+            let mut state = DispatchState {
+                hall_calls: &hall_calls,
+                car_calls: &car_calls,
+                ..dispatch_state(&floors, &[&elevator])
+            };
+            state.cars[0].direction = direction;
+            state.cars[0].passengers = 1;
+            state
+        };
+
+        let mut look = CollectiveControl::new(Sweep::Look);
+        // going up: the car call first, then up to the highest down call
+        assert!(look.next_stop(0, &state(Some(Direction::Up))) == Some(7));
+        let no_car_call = DispatchState { car_calls: &[], ..state(Some(Direction::Up)) };
+        assert!(look.next_stop(0, &no_car_call) == Some(8));
+        // going down the up call below is where it turns
+        assert!(look.next_stop(0, &state(Some(Direction::Down))) == Some(2));
+
+        let mut scan = CollectiveControl::new(Sweep::Scan);
+        assert!(scan.next_stop(0, &no_car_call) == Some(9));
+    }
+
+    #[test]
+    fn nearest_car() {
+        let floors = floors(10);
+        let low = car(&floors, 1);
+        let high = car(&floors, 7);
+        let hall_calls = [call(3, Direction::Up, None), call(6, Direction::Down, None)];
+        let state = DispatchState { hall_calls: &hall_calls, ..dispatch_state(&floors, &[&low, &high]) };
+        let assignments = CollectiveControl::default().assign(&state);
+        assert!(assignments == vec![Assignment { call: 0, car: 0 }, Assignment { call: 1, car: 1 }]);
+
+        // a car going up away from the call has to finish its sweep
+        let car_calls = [CarCall { car: 1, floor: 9 }];
+        let mut cars = state.cars.clone();
+        cars[1].direction = Some(Direction::Up);
+        let busy = DispatchState { cars, car_calls: &car_calls, ..state };
+        let far = CollectiveControl::default().travel_distance(1, &hall_calls[0], &busy);
+        assert!((far - (6. + 18.)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn stops_on_the_way() {
+        // the car going to the top stops for a call that comes up on the way
+        let mut system = ElevatorSystem::new(1, vec![0.0, 3.0, 6.0, 9.0, 12.0, 15.0]);
+        system.add_passenger(Passenger::new(0, 0, 5, 80., 0.));
+        system.run_for(9.);
+        assert!(matches!(system.car_status[0], CarStatus::Moving { floor: 5 }));
+        system.add_passenger(Passenger::new(1, 4, 5, 80., system.now()));
+        // one going down waits for the way back
+        system.add_passenger(Passenger::new(2, 3, 0, 80., system.now()));
+        system.run_for(60.);
+
+        let passengers = &system.passengers;
+        assert!(passengers.iter().all(|passenger| passenger.state == PassengerState::Arrived), "{:?}", passengers);
+        assert!(passengers[1].board_time.unwrap() < passengers[0].arrival_time.unwrap());
+        assert!(passengers[2].board_time.unwrap() > passengers[0].arrival_time.unwrap());
+    }
+}
@@ -0,0 +1,437 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Deep Q learning (DQN) of the car assignments. Like the tabular one
+// (q_learning.rs) the action is the car of a new hall call and the reward is
+// the waiting and the energy between two calls, but the state is not cut in
+// buckets: a vector of features of the call and of every car goes into a
+// small network (neural_network.rs) that gives the value of every car.
+//   call:  floor, direction, hall calls waiting
+//   a car: height, speed, direction, load, its hall calls, its car calls and
+//          the travel of collective control until the call
+// Every decision is kept in a replay buffer, the network learns on random
+// batches of it against a target network that is copied from it every
+// target_update steps:
+//   target = r + gamma^dt * max Q_target(s', .)
+// The weights are written as json and loaded again by DqnDispatcher.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+
+use super::basic_algorithm::CollectiveControl;
+use super::neural_network::{Adam, AdamParameters, Mlp, Sample};
+use super::q_learning::{mean_waiting, EpisodeParameters, EpisodeStats, EpsilonSchedule, RewardWeights};
+use super::{write_history, Assignment, DispatchState, Dispatcher, HallCall};
+
+pub const CALL_FEATURES: usize = 3;
+pub const CAR_FEATURES: usize = 7;
+
+pub fn num_features(num_cars: usize) -> usize {
+    CALL_FEATURES + CAR_FEATURES * num_cars
+}
+
+// the input of the network, every feature about between -1 and 1
+pub fn features(state: &DispatchState, call: &HallCall, sweep: &CollectiveControl) -> Vec<f32> {
+    let num_floors = state.floors.len().max(2) as f32;
+    let mut features = vec![
+        call.floor as f32 / (num_floors - 1.),
+        call.direction.sign(),
+        state.hall_calls.len() as f32 / num_floors,
+    ];
+    for car in 0..state.cars.len() {
+        features.extend(car_features(state, car, Some(call), sweep));
+    }
+    features
+}
+
+// the features of a car, the travel until the call is 0 without one
+pub fn car_features(state: &DispatchState, car: usize, call: Option<&HallCall>, sweep: &CollectiveControl) -> [f32; CAR_FEATURES] {
+    let num_floors = state.floors.len().max(2) as f32;
+    let top = state.floors.last().copied().unwrap_or(0.).max(1.);
+    let car_state = &state.cars[car];
+    let elevator = car_state.elevator;
+    let car_calls = state.car_calls.iter().filter(|call| call.car == car).count();
+    [
+        elevator.current_height / top,
+        elevator.current_speed / elevator.max_speed,
+        car_state.direction.map_or(0., |direction| direction.sign()),
+        elevator.current_load / elevator.max_load,
+        state.landing_calls(car).count() as f32 / num_floors,
+        car_calls as f32 / num_floors,
+        call.map_or(0., |call| sweep.travel_distance(car, call, state) / (2. * top)),
+    ]
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub state: Vec<f32>,
+    pub action: usize,
+    pub reward: f32,
+    pub elapsed: f32,           // seconds until the next decision
+    pub next: Option<Vec<f32>>, // None at the end of the episode
+}
+
+// the last capacity transitions
+#[derive(Debug, Clone)]
+pub struct ReplayBuffer {
+    pub capacity: usize,
+    pub transitions: Vec<Transition>,
+    next: usize, // the oldest one, written over when full
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1), transitions: Vec::new(), next: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transitions.is_empty()
+    }
+
+    pub fn push(&mut self, transition: Transition) {
+        if self.transitions.len() < self.capacity {
+            self.transitions.push(transition);
+        } else {
+            self.transitions[self.next] = transition;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    // random transitions, the same one may come twice
+    pub fn sample(&self, size: usize, rng: &mut StdRng) -> Vec<&Transition> {
+        if self.transitions.is_empty() {
+            return Vec::new();
+        }
+        (0..size).map(|_| &self.transitions[rng.gen_range(0..self.transitions.len())]).collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DqnParameters {
+    pub hidden: Vec<usize>, // sizes of the hidden layers
+    pub gamma: f32,         // discount of a second
+    pub episodes: usize,
+    pub epsilon: EpsilonSchedule,
+    pub reward: RewardWeights,
+    #[serde(default)]
+    pub adam: AdamParameters,
+    pub replay_capacity: usize,
+    pub batch_size: usize,
+    pub warmup: usize,        // transitions in the buffer before the learning starts
+    pub train_every: usize,   // decisions between two steps of the learning
+    pub target_update: usize, // steps of the learning between two copies to the target
+    pub episode: EpisodeParameters,
+    #[serde(default)]
+    pub seed: u64, // of the weights, the exploration and the batches
+}
+
+impl DqnParameters {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+}
+
+// the last decision, it becomes a transition at the next one
+#[derive(Debug, Clone)]
+struct Decision {
+    state: Vec<f32>,
+    action: usize,
+    time: f64,
+}
+
+pub struct DqnAgent {
+    pub network: Mlp,
+    pub target: Mlp,
+    pub buffer: ReplayBuffer,
+    pub epsilon: f32, // 0 is greedy
+    pub learning: bool,
+    pub updates: usize, // steps of the learning so far
+    pub last_loss: f32,
+    parameters: Option<DqnParameters>, // only for the learning
+    adam: Option<Adam>,
+    rng: StdRng,
+    sweep: CollectiveControl,
+    last: Option<Decision>,
+    reward: f32, // since the last decision
+    decisions: usize,
+}
+
+impl DqnAgent {
+    pub fn new(parameters: DqnParameters) -> Self {
+        let mut rng = StdRng::seed_from_u64(parameters.seed);
+        let mut sizes = vec![num_features(parameters.episode.num_elevators)];
+        sizes.extend(&parameters.hidden);
+        sizes.push(parameters.episode.num_elevators);
+        let network = Mlp::new(&sizes, &mut rng);
+        let mut agent = Self::greedy(network);
+        agent.adam = Some(Adam::new(parameters.adam, &agent.network));
+        agent.buffer = ReplayBuffer::new(parameters.replay_capacity);
+        agent.rng = rng;
+        agent.learning = true;
+        agent.parameters = Some(parameters);
+        agent
+    }
+
+    // plays a trained network, nothing is learned
+    pub fn greedy(network: Mlp) -> Self {
+        Self {
+            target: network.clone(),
+            network,
+            buffer: ReplayBuffer::new(1),
+            epsilon: 0.,
+            learning: false,
+            updates: 0,
+            last_loss: 0.,
+            parameters: None,
+            adam: None,
+            rng: StdRng::seed_from_u64(0),
+            sweep: CollectiveControl::default(),
+            last: None,
+            reward: 0.,
+            decisions: 0,
+        }
+    }
+
+    pub fn add_reward(&mut self, reward: f32) {
+        self.reward += reward;
+    }
+
+    fn remember(&mut self, next: Option<(Vec<f32>, f64)>) {
+        let Some(last) = self.last.take() else {
+            return;
+        };
+        let (next, elapsed) = match next {
+            Some((state, time)) => (Some(state), (time - last.time) as f32),
+            None => (None, 0.),
+        };
+        self.buffer.push(Transition { state: last.state, action: last.action, reward: self.reward, elapsed, next });
+        self.reward = 0.;
+    }
+
+    // one step of Adam on a batch of the buffer
+    fn learn(&mut self) {
+        let (Some(parameters), Some(adam)) = (&self.parameters, &mut self.adam) else {
+            return;
+        };
+        if self.buffer.len() < parameters.warmup.max(1) {
+            return;
+        }
+        let transitions = self.buffer.sample(parameters.batch_size, &mut self.rng);
+        let targets: Vec<f32> = transitions.iter()
+            .map(|transition| {
+                let future = transition.next.as_ref().map_or(0., |next| {
+                    let best = self.target.forward(next).into_iter().fold(f32::NEG_INFINITY, f32::max);
+                    parameters.gamma.powf(transition.elapsed) * best
+                });
+                transition.reward + future
+            })
+            .collect();
+        let batch: Vec<Sample> = transitions.iter().zip(targets)
+            .map(|(transition, target)| Sample { input: &transition.state, output: transition.action, target })
+            .collect();
+        self.last_loss = adam.step(&mut self.network, &batch);
+
+        self.updates += 1;
+        if self.updates.is_multiple_of(parameters.target_update.max(1)) {
+            self.target = self.network.clone();
+        }
+    }
+
+    // the car for the call
+    pub fn decide(&mut self, call: &HallCall, state: &DispatchState) -> usize {
+        let input = features(state, call, &self.sweep);
+        let num_cars = state.cars.len().min(self.network.outputs()).max(1);
+        let action = if self.rng.gen::<f32>() < self.epsilon {
+            self.rng.gen_range(0..num_cars)
+        } else {
+            self.network.forward(&input).into_iter()
+                .take(num_cars)
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(0, |(car, _)| car)
+        };
+
+        if self.learning {
+            self.remember(Some((input.clone(), state.now)));
+            self.last = Some(Decision { state: input, action, time: state.now });
+            self.decisions += 1;
+            let train_every = self.parameters.as_ref().map_or(1, |parameters| parameters.train_every.max(1));
+            if self.decisions.is_multiple_of(train_every) {
+                self.learn();
+            }
+        }
+        action
+    }
+
+    // the episode is over, the last decision gets the reward it has
+    pub fn end_episode(&mut self) {
+        if self.learning {
+            self.remember(None);
+        }
+        self.reward = 0.;
+    }
+}
+
+// the agent is shared with the trainer, which gives it the rewards while the
+// system runs
+pub struct DqnDispatcher {
+    pub agent: Rc<RefCell<DqnAgent>>,
+    sweep: CollectiveControl,
+}
+
+impl DqnDispatcher {
+    pub fn new(agent: Rc<RefCell<DqnAgent>>) -> Self {
+        Self { agent, sweep: CollectiveControl::default() }
+    }
+
+    // a checkpoint of the network, played greedy. An error when it was
+    // trained for another number of cars
+    pub fn from_file(file_path: &str, num_cars: usize) -> Result<Self, Box<dyn Error>> {
+        let network = Mlp::from_file(file_path)?;
+        if network.inputs() != num_features(num_cars) || network.outputs() != num_cars {
+            return Err(format!(
+                "the network has {} inputs and {} outputs, {} cars need {} and {}",
+                network.inputs(), network.outputs(), num_cars, num_features(num_cars), num_cars,
+            ).into());
+        }
+        Ok(Self::new(Rc::new(RefCell::new(DqnAgent::greedy(network)))))
+    }
+}
+
+impl Dispatcher for DqnDispatcher {
+    fn assign(&mut self, state: &DispatchState) -> Vec<Assignment> {
+        let mut agent = self.agent.borrow_mut();
+        state.hall_calls.iter().enumerate()
+            .filter(|(_, call)| call.car.is_none())
+            .map(|(idx, call)| Assignment { call: idx, car: agent.decide(call, state) })
+            .collect()
+    }
+
+    // the cars sweep like in collective control
+    fn next_stop(&mut self, car: usize, state: &DispatchState) -> Option<usize> {
+        self.sweep.next_stop(car, state)
+    }
+}
+
+pub struct DqnTrainer {
+    pub parameters: DqnParameters,
+    pub agent: Rc<RefCell<DqnAgent>>,
+    pub history: Vec<EpisodeStats>,
+}
+
+impl DqnTrainer {
+    pub fn new(parameters: DqnParameters) -> Self {
+        let agent = DqnAgent::new(parameters.clone());
+        Self { parameters, agent: Rc::new(RefCell::new(agent)), history: Vec::new() }
+    }
+
+    // one simulation with the epsilon of the episode
+    pub fn run_episode(&mut self) -> Result<EpisodeStats, Box<dyn Error>> {
+        let episode = self.history.len();
+        let epsilon = self.parameters.epsilon.epsilon(episode);
+        self.agent.borrow_mut().epsilon = epsilon;
+
+        let setup = &self.parameters.episode;
+        let mut system = setup.system(episode, Box::new(DqnDispatcher::new(self.agent.clone())))?;
+        let reward = setup.run(&mut system, &self.parameters.reward, |reward| self.agent.borrow_mut().add_reward(reward));
+        self.agent.borrow_mut().end_episode();
+
+        let stats = EpisodeStats { episode, epsilon, reward, mean_waiting: mean_waiting(&system) };
+        self.history.push(stats);
+        Ok(stats)
+    }
+
+    pub fn train(&mut self) -> Result<Mlp, Box<dyn Error>> {
+        while self.history.len() < self.parameters.episodes {
+            self.run_episode()?;
+        }
+        Ok(self.agent.borrow().network.clone())
+    }
+
+    // a checkpoint of the weights
+    pub fn save_network(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        self.agent.borrow().network.to_file(file_path)
+    }
+
+    // mean waiting time of the greedy network and of collective control on
+    // the episodes after the training ones
+    pub fn compare_with_collective(&self, episodes: usize) -> Result<(f32, f32), Box<dyn Error>> {
+        let setup = &self.parameters.episode;
+        let network = self.agent.borrow().network.clone();
+        let (mut dqn, mut collective) = (0., 0.);
+        for episode in self.parameters.episodes..self.parameters.episodes + episodes {
+            let agent = Rc::new(RefCell::new(DqnAgent::greedy(network.clone())));
+            dqn += setup.evaluate(episode, Box::new(DqnDispatcher::new(agent)))?;
+            collective += setup.evaluate(episode, Box::new(CollectiveControl::default()))?;
+        }
+        let episodes = episodes.max(1) as f32;
+        Ok((dqn / episodes, collective / episodes))
+    }
+
+    // episode, epsilon, reward and mean waiting time
+    pub fn write_history(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        write_history(file_path, &self.history)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::temp_path;
+
+    #[test]
+    fn replay_buffer_keeps_the_last() {
+        let transition = |reward| Transition { state: vec![0.], action: 0, reward, elapsed: 1., next: None };
+        let mut buffer = ReplayBuffer::new(3);
+        for reward in 0..5 {
+            buffer.push(transition(reward as f32));
+        }
+        assert!(buffer.len() == 3);
+        let mut rewards: Vec<f32> = buffer.transitions.iter().map(|transition| transition.reward).collect();
+        rewards.sort_by(f32::total_cmp);
+        assert!(rewards == vec![2., 3., 4.]);
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(buffer.sample(8, &mut rng).iter().all(|transition| transition.reward >= 2.));
+    }
+
+    #[test]
+    fn checkpoint_against_collective() {
+        let mut parameters = DqnParameters::from_file("param/dqn_parameters.yaml").unwrap();
+        parameters.episodes = 2;
+        parameters.warmup = 16;
+        parameters.episode.num_floors = 6;
+        parameters.episode.duration = 300.;
+        let mut trainer = DqnTrainer::new(parameters);
+        let network = trainer.train().unwrap();
+        assert!(trainer.history.len() == 2);
+        assert!(network.inputs() == num_features(3) && network.outputs() == 3);
+        {
+            let agent = trainer.agent.borrow();
+            assert!(agent.updates > 0 && agent.last_loss.is_finite());
+            assert!(!agent.buffer.is_empty());
+        }
+
+        let file_path = temp_path("elevator_dqn.json");
+        let file_path = file_path.as_str();
+        trainer.save_network(file_path).unwrap();
+        let dispatcher = DqnDispatcher::from_file(file_path, 3).unwrap();
+        assert!(DqnDispatcher::from_file(file_path, 2).is_err());
+        std::fs::remove_file(file_path).unwrap();
+        assert!(dispatcher.agent.borrow().network == network);
+
+        let (dqn, collective) = trainer.compare_with_collective(1).unwrap();
+        assert!(dqn > 0. && dqn.is_finite());
+        assert!(collective > 0. && collective.is_finite());
+    }
+}
@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Destination control. The people type their floor on the keypad in the hall
+// and are told which car to take, so the people going to the same floors can
+// be put in the same car and the cars make fewer stops. Every car plays its
+// plan forward like in the ETA control (eta_algorithm.rs), but the
+// destinations are known: a person going to a floor the car stops at anyway
+// adds no stop and costs the others little. The car of a new destination call
+// is the one whose predicted waiting and riding time grows the least, plus
+// spread_weight for every floor between the destination and the closest
+// destination the car already has, so the people going near each other ride
+// together. A full car (max_load / person_mass people) takes nobody else.
+// The letter shown on the keypad does not change while the car is coming, a
+// person the car leaves behind (full, or it goes the other way) is given a
+// new one. The cars sweep like in collective control (LOOK).
+
+use serde::Deserialize;
+
+use super::basic_algorithm::CollectiveControl;
+use super::eta_algorithm::{EtaDispatcher, EtaParameters, Pickup};
+use super::{Assignment, DestinationCall, DispatchState, Dispatcher};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct DestinationParameters {
+    pub eta: EtaParameters,
+    pub spread_weight: f32, // seconds per floor between the destinations of a car, about a floor of riding
+    pub person_mass: f32,   // kg, the number of people a car takes comes from it
+}
+
+impl Default for DestinationParameters {
+    fn default() -> Self {
+        Self { eta: EtaParameters::default(), spread_weight: 4., person_mass: 75. }
+    }
+}
+
+// what a car will have to do with the calls given to it so far
+#[derive(Debug, Clone, Default)]
+struct CarGroup {
+    people: usize,
+    destinations: Vec<usize>,
+    pickups: Vec<Pickup>, // given to the car in this batch
+    cost: f32,            // predicted cost of the plan with them
+}
+
+pub struct DestinationDispatcher {
+    pub parameters: DestinationParameters,
+    sweep: CollectiveControl,
+    eta: EtaDispatcher,
+}
+
+impl DestinationDispatcher {
+    pub fn new(parameters: DestinationParameters) -> Self {
+        Self { parameters, sweep: CollectiveControl::default(), eta: EtaDispatcher::new(parameters.eta) }
+    }
+
+    fn group(&self, car: usize, state: &DispatchState) -> CarGroup {
+        let assigned: Vec<&DestinationCall> = state.destination_calls.iter()
+            .filter(|call| call.car == Some(car))
+            .collect();
+        let destinations = state.car_calls.iter()
+            .filter(|call| call.car == car)
+            .map(|call| call.floor)
+            .chain(assigned.iter().map(|call| call.destination))
+            .collect();
+        CarGroup {
+            people: state.cars[car].passengers + assigned.len(),
+            destinations,
+            pickups: Vec::new(),
+            cost: self.eta.plan_cost_with(car, state, &[], None),
+        }
+    }
+
+    fn capacity(&self, car: usize, state: &DispatchState) -> usize {
+        (state.cars[car].elevator.max_load / self.parameters.person_mass).floor() as usize
+    }
+
+    // the plan of the car with the call, and what it adds
+    fn cost(&self, car: usize, call: &DestinationCall, group: &CarGroup, state: &DispatchState) -> (f32, f32) {
+        let mut pickups = group.pickups.clone();
+        pickups.push(Pickup::destination(call, state));
+        let plan = self.eta.plan_cost_with(car, state, &pickups, None);
+
+        let spread = group.destinations.iter()
+            .map(|destination| destination.abs_diff(call.destination))
+            .min()
+            .unwrap_or(0) as f32;
+        (plan, plan - group.cost + self.parameters.spread_weight * spread)
+    }
+}
+
+impl Default for DestinationDispatcher {
+    fn default() -> Self {
+        Self::new(DestinationParameters::default())
+    }
+}
+
+impl Dispatcher for DestinationDispatcher {
+    // there are no hall buttons with keypads, any call still gets a car
+    fn assign(&mut self, state: &DispatchState) -> Vec<Assignment> {
+        self.sweep.assign(state)
+    }
+
+    fn next_stop(&mut self, car: usize, state: &DispatchState) -> Option<usize> {
+        self.sweep.next_stop(car, state)
+    }
+
+    fn uses_destinations(&self) -> bool {
+        true
+    }
+
+    fn assign_destinations(&mut self, state: &DispatchState) -> Vec<Assignment> {
+        let mut groups: Vec<CarGroup> = (0..state.cars.len()).map(|car| self.group(car, state)).collect();
+        let mut assignments = Vec::new();
+        for (idx, call) in state.destination_calls.iter().enumerate() {
+            if call.car.is_some() {
+                continue;
+            }
+            let costs: Vec<(usize, (f32, f32), bool)> = (0..state.cars.len())
+                .map(|car| {
+                    let fits = groups[car].people < self.capacity(car, state);
+                    (car, self.cost(car, call, &groups[car], state), fits)
+                })
+                .collect();
+            // every car full, the cheapest one comes back for it
+            let any_fits = costs.iter().any(|(_, _, fits)| *fits);
+            let best = costs.into_iter()
+                .filter(|(_, _, fits)| *fits || !any_fits)
+                .min_by(|a, b| a.1.1.total_cmp(&b.1.1));
+            let Some((car, (plan, _), _)) = best else {
+                continue;
+            };
+
+            // the next calls of this batch see it
+            let group = &mut groups[car];
+            group.people += 1;
+            group.destinations.push(call.destination);
+            group.pickups.push(Pickup::destination(call, state));
+            group.cost = plan;
+            assignments.push(Assignment { call: idx, car });
+        }
+        assignments
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{car, dispatch_state, floors, population};
+    use crate::machine::elevator_system::ElevatorSystem;
+    use crate::population::generator::{ArrivalProcess, PassengerGenerator};
+    use crate::population::population::PassengerState;
+    use crate::population::traffic::TrafficPreset;
+
+    #[test]
+    fn same_destinations_ride_together() {
+        let floors = floors(10);
+        let first = car(&floors, 0);
+        let second = car(&floors, 0);
+        let call = |passenger, destination| DestinationCall { passenger, floor: 0, destination, time: 0., car: None };
+        let destination_calls = [call(0, 8), call(1, 3), call(2, 8), call(3, 2)];
+        let state = DispatchState { destination_calls: &destination_calls, ..dispatch_state(&floors, &[&first, &second]) };
+
+        let assignments = DestinationDispatcher::default().assign_destinations(&state);
+        assert!(assignments.len() == 4);
+        let car_of = |passenger: usize| assignments[passenger].car;
+        assert!(car_of(0) == car_of(2), "{:?}", assignments);
+        assert!(car_of(1) == car_of(3), "{:?}", assignments);
+        assert!(car_of(0) != car_of(1), "{:?}", assignments);
+    }
+
+    #[test]
+    fn everyone_takes_their_letter() {
+        let parameters = population(ArrivalProcess::Preset { preset: TrafficPreset::UpPeak, peak_rate: 600. }, 2, 8.5);
+        let mut system = ElevatorSystem::new(3, floors(8));
+        system.set_dispatcher(Box::new(DestinationDispatcher::default()));
+        system.set_generator(PassengerGenerator::new(parameters, 8).unwrap());
+        for _ in 0..600 {
+            system.run_for(1.);
+            assert!(system.hall_calls.is_empty());
+            for passenger in &system.passengers {
+                if let PassengerState::Riding { car } = passenger.state {
+                    assert!(passenger.assigned_car == Some(car), "{:?}", passenger);
+                }
+            }
+        }
+        system.generator = None;
+        system.run_for(300.);
+
+        assert!(system.passengers.len() > 50);
+        assert!(system.passengers.iter().all(|passenger| passenger.state == PassengerState::Arrived));
+        assert!(system.passengers.iter().all(|passenger| passenger.car_letter().is_some()));
+        assert!(system.destination_calls.is_empty() && system.car_calls.is_empty());
+    }
+}
@@ -0,0 +1,394 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// The elevator group as an environment of reinforcement learning, in the way
+// of gym: reset(seed) starts an episode and gives the first observation,
+// step(action) runs the system until the next decision and gives the
+// observation, the reward on the way, whether the episode is over and some
+// information. The decisions depend on the action space:
+//   assignment  a new hall call waits for its car, the action is the car
+//   next_stop   a car stands with work to do, the action is its next floor,
+//               the hall calls go to the nearest car (collective control)
+// Otherwise the cars sweep like in collective control. The observation is
+// a vector of features or one discrete state (the encoding of q_learning.rs):
+//   features    for a call the input of the dqn network (deep_q_learning.rs),
+//               for a stop the car, the floors of its car calls and the car
+//               features of every car. Then the up and down buttons of every
+//               floor
+// The reward is the one of q_learning.rs, the waiting and the energy, the
+// system is run with RewardWeights::update like the episodes of the trainers.
+
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+
+use crate::machine::elevator_system::{CarStatus, ElevatorSystem};
+use super::basic_algorithm::CollectiveControl;
+use super::deep_q_learning::{self, car_features, num_features, CAR_FEATURES};
+use super::q_learning::{EpisodeParameters, RewardWeights, StateEncoding};
+use super::{Assignment, Direction, DispatchState, Dispatcher, HallCall};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionSpace {
+    Assignment,
+    NextStop,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ObservationSpace {
+    Features,
+    Discrete { encoding: StateEncoding },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Observation {
+    Features(Vec<f32>),
+    Discrete(u64),
+}
+
+// what the agent decides about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Call(HallCall),
+    Stop { car: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepInfo {
+    pub time: f64,
+    pub elapsed: f64,               // simulated seconds of the step
+    pub decision: Option<Decision>, // None at the end of the episode
+    pub waiting: usize,
+    pub arrived: usize,
+    pub energy: f32, // total_energy_consumed
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EnvironmentParameters {
+    pub action: ActionSpace,
+    pub observation: ObservationSpace,
+    pub reward: RewardWeights,
+    pub episode: EpisodeParameters,
+}
+
+impl EnvironmentParameters {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+}
+
+// the dispatcher of the system in the environment, it leaves the decisions
+// of the action space to the agent
+struct EnvironmentDispatcher {
+    action: ActionSpace,
+    sweep: CollectiveControl,
+    stops: Rc<RefCell<Vec<Option<usize>>>>, // chosen by the agent, taken at the next step
+}
+
+impl Dispatcher for EnvironmentDispatcher {
+    fn assign(&mut self, state: &DispatchState) -> Vec<Assignment> {
+        match self.action {
+            ActionSpace::Assignment => Vec::new(),
+            ActionSpace::NextStop => self.sweep.assign(state),
+        }
+    }
+
+    fn next_stop(&mut self, car: usize, state: &DispatchState) -> Option<usize> {
+        match (self.action, state.cars[car].status) {
+            (ActionSpace::NextStop, CarStatus::Idle) => self.stops.borrow_mut()[car].take(),
+            // the car goes where it was told
+            (ActionSpace::NextStop, _) => None,
+            (ActionSpace::Assignment, _) => self.sweep.next_stop(car, state),
+        }
+    }
+}
+
+pub struct ElevatorEnvironment {
+    pub parameters: EnvironmentParameters,
+    system: ElevatorSystem,
+    stops: Rc<RefCell<Vec<Option<usize>>>>,
+    decision: Option<Decision>,
+    sweep: CollectiveControl,
+}
+
+impl ElevatorEnvironment {
+    pub fn new(parameters: EnvironmentParameters) -> Result<Self, Box<dyn Error>> {
+        let mut environment = Self {
+            system: ElevatorSystem::new(0, Vec::new()),
+            stops: Rc::new(RefCell::new(Vec::new())),
+            decision: None,
+            sweep: CollectiveControl::default(),
+            parameters,
+        };
+        environment.reset(environment.parameters.episode.population.seed)?;
+        Ok(environment)
+    }
+
+    // number of the actions, the cars or the floors
+    pub fn action_size(&self) -> usize {
+        let episode = &self.parameters.episode;
+        match self.parameters.action {
+            ActionSpace::Assignment => episode.num_elevators,
+            ActionSpace::NextStop => episode.num_floors,
+        }
+    }
+
+    // length of the features, 1 for a discrete observation
+    pub fn observation_size(&self) -> usize {
+        let episode = &self.parameters.episode;
+        let (cars, floors) = (episode.num_elevators, episode.num_floors);
+        match (self.parameters.observation, self.parameters.action) {
+            (ObservationSpace::Discrete { .. }, _) => 1,
+            (ObservationSpace::Features, ActionSpace::Assignment) => num_features(cars) + 2 * floors,
+            (ObservationSpace::Features, ActionSpace::NextStop) => cars + floors + CAR_FEATURES * cars + 2 * floors,
+        }
+    }
+
+    pub fn system(&self) -> &ElevatorSystem {
+        &self.system
+    }
+
+    pub fn decision(&self) -> Option<Decision> {
+        self.decision
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.system.now() >= self.parameters.episode.duration
+    }
+
+    // a new episode with the passengers of the seed
+    pub fn reset(&mut self, seed: u64) -> Result<Observation, Box<dyn Error>> {
+        let mut episode = self.parameters.episode.clone();
+        episode.population.seed = seed;
+        self.stops = Rc::new(RefCell::new(vec![None; episode.num_elevators]));
+        let dispatcher = EnvironmentDispatcher {
+            action: self.parameters.action,
+            sweep: CollectiveControl::default(),
+            stops: self.stops.clone(),
+        };
+        self.system = episode.system(0, Box::new(dispatcher))?;
+        self.decision = None;
+        self.run();
+        Ok(self.observe())
+    }
+
+    pub fn step(&mut self, action: usize) -> Result<(Observation, f32, bool, StepInfo), Box<dyn Error>> {
+        let Some(decision) = self.decision else {
+            return Err("the episode is over, it has to be reset".into());
+        };
+        if action >= self.action_size() {
+            return Err(format!("action {} is not in 0..{}", action, self.action_size()).into());
+        }
+        match decision {
+            Decision::Call(call) => {
+                let waiting = self.system.hall_calls.iter_mut()
+                    .find(|other| other.floor == call.floor && other.direction == call.direction && other.car.is_none());
+                if let Some(waiting) = waiting {
+                    waiting.car = Some(action);
+                }
+            },
+            Decision::Stop { car } => self.stops.borrow_mut()[car] = Some(action),
+        }
+
+        let start = self.system.now();
+        let reward = self.run();
+        let info = StepInfo {
+            time: self.system.now(),
+            elapsed: self.system.now() - start,
+            decision: self.decision,
+            waiting: self.system.waiting_passengers().count(),
+            arrived: self.system.arrived_passengers().count(),
+            energy: self.system.total_energy_consumed,
+        };
+        Ok((self.observe(), reward, self.decision.is_none(), info))
+    }
+
+    // the next decision to make, without running the system
+    fn find_decision(&self) -> Option<Decision> {
+        let state = self.system.dispatch_state();
+        match self.parameters.action {
+            ActionSpace::Assignment => state.hall_calls.iter()
+                .find(|call| call.car.is_none())
+                .map(|call| Decision::Call(*call)),
+            ActionSpace::NextStop => {
+                let stops = self.stops.borrow();
+                (0..state.cars.len())
+                    .find(|car| {
+                        state.cars[*car].status == CarStatus::Idle
+                            && stops[*car].is_none()
+                            && !state.work_floors(*car).is_empty()
+                    })
+                    .map(|car| Decision::Stop { car })
+            },
+        }
+    }
+
+    // runs the system until a decision or the end, gives the reward
+    fn run(&mut self) -> f32 {
+        let weights = self.parameters.reward;
+        let mut reward = 0.;
+        loop {
+            self.decision = self.find_decision();
+            if self.decision.is_some() || self.is_done() {
+                if self.is_done() {
+                    self.decision = None;
+                }
+                return reward;
+            }
+            reward += weights.update(&mut self.system);
+        }
+    }
+
+    pub fn observe(&self) -> Observation {
+        let state = self.system.dispatch_state();
+        match self.parameters.observation {
+            ObservationSpace::Discrete { encoding } => Observation::Discrete(match self.decision {
+                Some(Decision::Call(call)) => encoding.encode(&state, &call),
+                Some(Decision::Stop { car }) => encoding.encode_stop(&state, car),
+                None => 0,
+            }),
+            ObservationSpace::Features => Observation::Features(self.features(&state)),
+        }
+    }
+
+    fn features(&self, state: &DispatchState) -> Vec<f32> {
+        let mut features = Vec::with_capacity(self.observation_size());
+        match (self.parameters.action, self.decision) {
+            // the input of the dqn network
+            (ActionSpace::Assignment, Some(Decision::Call(call))) => {
+                features.extend(deep_q_learning::features(state, &call, &self.sweep));
+            },
+            (ActionSpace::Assignment, _) => features.resize(num_features(state.cars.len()), 0.),
+            (ActionSpace::NextStop, decision) => {
+                let car = match decision {
+                    Some(Decision::Stop { car }) => Some(car),
+                    _ => None,
+                };
+                features.extend((0..state.cars.len()).map(|other| (Some(other) == car) as u8 as f32));
+                features.extend((0..state.floors.len()).map(|floor| {
+                    state.car_calls.iter().any(|call| Some(call.car) == car && call.floor == floor) as u8 as f32
+                }));
+                for car in 0..state.cars.len() {
+                    features.extend(car_features(state, car, None, &self.sweep));
+                }
+            },
+        }
+
+        for floor in 0..state.floors.len() {
+            for direction in [Direction::Up, Direction::Down] {
+                let pressed = state.hall_calls.iter().any(|call| call.floor == floor && call.direction == direction);
+                features.push(pressed as u8 as f32);
+            }
+        }
+        features
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::population::population::PassengerState;
+
+    fn parameters(action: ActionSpace, observation: ObservationSpace) -> EnvironmentParameters {
+        let mut parameters = EnvironmentParameters::from_file("param/environment_parameters.yaml").unwrap();
+        parameters.action = action;
+        parameters.observation = observation;
+        parameters.episode.num_floors = 6;
+        parameters.episode.duration = 600.;
+        parameters
+    }
+
+    // the lowest action, the first car or the lowest floor
+    fn play(environment: &mut ElevatorEnvironment, seed: u64) -> (Vec<Observation>, f32, usize) {
+        let mut observations = vec![environment.reset(seed).unwrap()];
+        let (mut total, mut steps) = (0., 0);
+        let mut energy = environment.system().total_energy_consumed;
+        loop {
+            let (observation, reward, done, info) = environment.step(0).unwrap();
+            // the waiting is a cost, the energy may come back while a car regenerates
+            let waiting = reward + environment.parameters.reward.energy * (info.energy - energy);
+            assert!(waiting <= 1e-3 && info.elapsed >= 0.);
+            energy = info.energy;
+            observations.push(observation);
+            total += reward;
+            steps += 1;
+            if done {
+                assert!(info.decision.is_none());
+                return (observations, total, steps);
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_same_episode() {
+        let mut environment = ElevatorEnvironment::new(parameters(ActionSpace::Assignment, ObservationSpace::Features)).unwrap();
+        let (observations, total, steps) = play(&mut environment, 4);
+        assert!(steps > 10 && total < 0.);
+        assert!(environment.is_done() && environment.step(0).is_err());
+        for observation in &observations {
+            let Observation::Features(features) = observation else {
+                panic!("{:?}", observation);
+            };
+            assert!(features.len() == environment.observation_size());
+        }
+        // the first car took every call
+        let system = environment.system();
+        assert!(system.passengers.iter().all(|passenger| match passenger.state {
+            PassengerState::Riding { car } => car == 0,
+            _ => true,
+        }));
+
+        let (again, again_total, _) = play(&mut environment, 4);
+        assert!(again == observations && again_total == total);
+        let (_, other_total, _) = play(&mut environment, 5);
+        assert!(other_total != total);
+    }
+
+    #[test]
+    fn features_of_the_dqn() {
+        let mut environment = ElevatorEnvironment::new(parameters(ActionSpace::Assignment, ObservationSpace::Features)).unwrap();
+        let Observation::Features(observation) = environment.reset(2).unwrap() else {
+            panic!("not features");
+        };
+        let Some(Decision::Call(call)) = environment.decision() else {
+            panic!("{:?}", environment.decision());
+        };
+        let state = environment.system().dispatch_state();
+        let network_input = deep_q_learning::features(&state, &call, &CollectiveControl::default());
+        assert!(observation[..network_input.len()] == network_input[..]);
+    }
+
+    #[test]
+    fn next_stops_from_the_agent() {
+        let encoding = StateEncoding::default();
+        let mut environment = ElevatorEnvironment::new(parameters(ActionSpace::NextStop, ObservationSpace::Discrete { encoding })).unwrap();
+        assert!(environment.action_size() == 6 && environment.observation_size() == 1);
+        let first = environment.reset(1).unwrap();
+        assert!(matches!(first, Observation::Discrete(_)));
+        assert!(matches!(environment.decision(), Some(Decision::Stop { .. })));
+        assert!(environment.step(6).is_err());
+
+        // every car goes to the closest floor it has to stop at
+        let mut done = false;
+        while !done {
+            let Some(Decision::Stop { car }) = environment.decision() else {
+                panic!("{:?}", environment.decision());
+            };
+            let state = environment.system().dispatch_state();
+            let height = state.cars[car].elevator.current_height;
+            let floor = state.work_floors(car).into_iter()
+                .min_by(|a, b| (state.floors[*a] - height).abs().total_cmp(&(state.floors[*b] - height).abs()))
+                .unwrap();
+            done = environment.step(floor).unwrap().2;
+        }
+        let system = environment.system();
+        let arrived = system.arrived_passengers().count();
+        assert!(arrived > 0 && arrived * 10 > system.passengers.len() * 8, "{} of {}", arrived, system.passengers.len());
+    }
+}
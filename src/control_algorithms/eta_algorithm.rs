@@ -0,0 +1,408 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Estimated time of arrival group control. For a new hall call every car
+// plays its committed stops forward: the trips take the time of the S-curve
+// profile of the car, every stop takes the dwell time and the boarding and
+// alighting durations of the people getting in and out there. The cost of a plan is the predicted waiting time of the
+// hall calls plus the riding time of the people in the car, the call goes to
+// the car whose cost grows the least with it. The people behind a hall call
+// have not told where they go yet, they are expected to ride half of the way
+// to the end of the building. With destination control it is known.
+// The assigned calls are looked at again every reassign_interval seconds, a
+// call goes to another car when it wins more than the hysteresis there. A call
+// the car is already stopping for stays with it.
+// The cars sweep like in collective control (LOOK), the plans assume the same.
+
+use serde::Deserialize;
+
+use crate::machine::elevator_system::CarStatus;
+use crate::machine::motion_profile::{MotionLimits, MotionProfile};
+use super::basic_algorithm::CollectiveControl;
+use super::{departure_direction, Assignment, DestinationCall, Direction, DispatchState, Dispatcher, HallCall};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct EtaParameters {
+    pub waiting_weight: f32,
+    pub riding_weight: f32,
+    pub reassign_interval: f32, // seconds between two looks at the assigned calls
+    pub hysteresis: f32,        // seconds a call has to win to change its car
+}
+
+impl Default for EtaParameters {
+    fn default() -> Self {
+        Self {
+            waiting_weight: 1.,
+            riding_weight: 1.,
+            reassign_interval: 5.,
+            hysteresis: 5.,
+        }
+    }
+}
+
+// people to be picked up, from a hall call or from a destination call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pickup {
+    pub floor: usize,
+    pub direction: Direction,
+    pub time: f64,
+    pub destination: Option<usize>,
+    pub boarding: f32,  // seconds they take to get in
+    pub alighting: f32, // and to get out at their floor
+}
+
+impl Pickup {
+    // everyone waiting behind the button who may take the car
+    pub fn hall(call: &HallCall, car: usize, state: &DispatchState) -> Self {
+        let (boarding, alighting) = state.waiting.iter()
+            .map(|idx| &state.passengers[*idx])
+            .filter(|passenger| passenger.origin == call.floor
+                && passenger.direction() == call.direction
+                && passenger.can_board(car))
+            .fold((0., 0.), |(boarding, alighting), passenger| {
+                (boarding + passenger.boarding_duration, alighting + passenger.alighting_duration)
+            });
+        Self { floor: call.floor, direction: call.direction, time: call.time, destination: None, boarding, alighting }
+    }
+
+    pub fn destination(call: &DestinationCall, state: &DispatchState) -> Self {
+        let (boarding, alighting) = state.passengers.get(call.passenger)
+            .map_or((0., 0.), |passenger| (passenger.boarding_duration, passenger.alighting_duration));
+        Self {
+            floor: call.floor,
+            direction: call.direction(),
+            time: call.time,
+            destination: Some(call.destination),
+            boarding,
+            alighting,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StopKind {
+    // waited until now
+    Hall { direction: Direction, waited: f32, destination: Option<usize>, boarding: f32, alighting: f32 },
+    // the people got in at this time of the plan
+    Car { since: f32, alighting: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stop {
+    floor: usize,
+    kind: StopKind,
+}
+
+impl Stop {
+    // the stop is made on the way in the given direction
+    fn is_on_the_way(&self, direction: Direction) -> bool {
+        match self.kind {
+            StopKind::Hall { direction: call_direction, .. } => call_direction == direction,
+            StopKind::Car { .. } => true,
+        }
+    }
+}
+
+pub struct EtaDispatcher {
+    pub parameters: EtaParameters,
+    sweep: CollectiveControl,
+    last_reassignment: f64,
+}
+
+impl EtaDispatcher {
+    pub fn new(parameters: EtaParameters) -> Self {
+        Self { parameters, sweep: CollectiveControl::default(), last_reassignment: 0. }
+    }
+
+    // time of a trip from rest to rest
+    fn trip_time(limits: MotionLimits, from: f32, to: f32) -> f32 {
+        let mut profile = MotionProfile::new(limits, from);
+        profile.set_target(to);
+        profile.duration()
+    }
+
+    // the floor a person behind a hall call is expected to go to
+    fn expected_destination(floor: usize, direction: Direction, num_floors: usize) -> usize {
+        match direction {
+            Direction::Up => ((floor + num_floors) / 2).clamp(floor + 1, num_floors - 1),
+            Direction::Down => (floor / 2).min(floor.saturating_sub(1)),
+        }
+    }
+
+    // the next stop of a LOOK sweep over the planned stops
+    fn next_floor(stops: &[Stop], floors: &[f32], reach: f32, direction: Option<Direction>, moving: bool) -> Option<usize> {
+        let distance = |floor: &usize| (floors[*floor] - reach).abs();
+        let Some(direction) = direction else {
+            return stops.iter().map(|stop| stop.floor).min_by(|a, b| distance(a).total_cmp(&distance(b)));
+        };
+
+        let sweep = |direction: Direction, here: bool| {
+            let margin = if here || moving { -0.01 } else { 0.01 };
+            let ahead = |floor: usize| (floors[floor] - reach) * direction.sign() > margin;
+            stops.iter()
+                .filter(|stop| ahead(stop.floor) && stop.is_on_the_way(direction))
+                .map(|stop| stop.floor)
+                .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+                .or_else(|| stops.iter()
+                    .filter(|stop| ahead(stop.floor))
+                    .map(|stop| stop.floor)
+                    .max_by(|a, b| distance(a).total_cmp(&distance(b))))
+        };
+        sweep(direction, false).or_else(|| sweep(direction.opposite(), true))
+    }
+
+    // the way the car goes on from the floor, like ElevatorSystem does it
+    fn departure_direction(stops: &[Stop], floor: usize, direction: Option<Direction>) -> Option<Direction> {
+        let call_here = |direction: Direction| stops.iter().any(|stop| {
+            stop.floor == floor
+                && matches!(stop.kind, StopKind::Hall { direction: call_direction, .. } if call_direction == direction)
+        });
+        let work_towards = |direction: Direction| stops.iter().any(|stop| match direction {
+            Direction::Up => stop.floor > floor,
+            Direction::Down => stop.floor < floor,
+        });
+        departure_direction(direction, call_here, work_towards)
+    }
+
+    // predicted waiting and riding time of everyone the car has to serve,
+    // with an extra hall call or without one of its own calls
+    pub fn plan_cost(&self, car: usize, state: &DispatchState, extra: Option<&HallCall>, without: Option<usize>) -> f32 {
+        let extra: Vec<Pickup> = extra.map(|call| Pickup::hall(call, car, state)).into_iter().collect();
+        self.plan_cost_with(car, state, &extra, without)
+    }
+
+    // the same with any number of extra pickups, the destination calls of the
+    // car are in the plan too
+    pub fn plan_cost_with(&self, car: usize, state: &DispatchState, extra: &[Pickup], without: Option<usize>) -> f32 {
+        let car_state = &state.cars[car];
+        let elevator = car_state.elevator;
+        let num_floors = state.floors.len();
+
+        let alighting = |floor: usize| car_state.riding.iter()
+            .map(|idx| &state.passengers[*idx])
+            .filter(|passenger| passenger.destination == floor)
+            .map(|passenger| passenger.alighting_duration)
+            .sum();
+        let mut stops: Vec<Stop> = state.car_calls.iter()
+            .filter(|call| call.car == car)
+            .map(|call| Stop { floor: call.floor, kind: StopKind::Car { since: 0., alighting: alighting(call.floor) } })
+            .collect();
+        let pickups = state.hall_calls.iter().enumerate()
+            .filter(|(idx, call)| call.car == Some(car) && Some(*idx) != without)
+            .map(|(_, call)| Pickup::hall(call, car, state))
+            .chain(state.destination_calls.iter()
+                .filter(|call| call.car == Some(car))
+                .map(|call| Pickup::destination(call, state)))
+            .chain(extra.iter().copied());
+        for pickup in pickups {
+            let kind = StopKind::Hall {
+                direction: pickup.direction,
+                waited: (state.now - pickup.time) as f32,
+                destination: pickup.destination,
+                boarding: pickup.boarding,
+                alighting: pickup.alighting,
+            };
+            stops.push(Stop { floor: pickup.floor, kind });
+        }
+
+        let mut time = 0.;
+        let mut moving = false;
+        match car_state.status {
+            CarStatus::Moving { .. } => moving = true,
+            CarStatus::DoorsOpen { until, .. } => time = (until - state.now).max(0.) as f32,
+            CarStatus::Idle => (),
+        }
+        let mut height = elevator.current_height;
+        let mut direction = car_state.direction;
+        let mut cost = 0.;
+
+        // every stop serves at least one, the limit is only a guard
+        for _ in 0..4 * (stops.len() + 1) {
+            let reach = if moving { elevator.stopping_height() } else { height };
+            let Some(floor) = Self::next_floor(&stops, state.floors, reach, direction, moving) else {
+                break;
+            };
+            let target = state.floors[floor];
+            time += if moving {
+                let mut profile = elevator.profile.clone();
+                profile.set_target(target);
+                profile.duration()
+            } else if (target - height).abs() > 0.01 {
+                Self::trip_time(elevator.profile.limits(), height, target)
+            } else {
+                0.
+            };
+            height = target;
+            moving = false;
+
+            let departure = Self::departure_direction(&stops, floor, direction);
+            let mut door_time = 0.;
+            let mut boarded = Vec::new();
+            stops.retain(|stop| {
+                if stop.floor != floor {
+                    return true;
+                }
+                match stop.kind {
+                    StopKind::Car { since, alighting } => {
+                        cost += self.parameters.riding_weight * (time - since);
+                        door_time += alighting;
+                    },
+                    StopKind::Hall { direction, waited, destination, boarding, alighting } if Some(direction) == departure => {
+                        cost += self.parameters.waiting_weight * (time + waited);
+                        door_time += boarding;
+                        boarded.push(Stop {
+                            floor: destination.unwrap_or_else(|| Self::expected_destination(floor, direction, num_floors)),
+                            kind: StopKind::Car { since: time, alighting },
+                        });
+                    },
+                    StopKind::Hall { .. } => return true,
+                }
+                false
+            });
+            stops.extend(boarded);
+            time += state.dwell_time + door_time;
+            direction = departure;
+        }
+        cost
+    }
+
+    // what the call adds to the plan of the car
+    fn marginal_cost(&self, car: usize, call: &HallCall, state: &DispatchState, base: f32) -> f32 {
+        self.plan_cost(car, state, Some(call), None) - base
+    }
+}
+
+impl Default for EtaDispatcher {
+    fn default() -> Self {
+        Self::new(EtaParameters::default())
+    }
+}
+
+impl Dispatcher for EtaDispatcher {
+    fn assign(&mut self, state: &DispatchState) -> Vec<Assignment> {
+        let reassign = state.now - self.last_reassignment >= self.parameters.reassign_interval as f64;
+        let has_new_calls = state.hall_calls.iter().any(|call| call.car.is_none());
+        if !reassign && !has_new_calls {
+            return Vec::new();
+        }
+
+        let base: Vec<f32> = (0..state.cars.len()).map(|car| self.plan_cost(car, state, None, None)).collect();
+        let best_car = |call: &HallCall, skip: Option<usize>| (0..state.cars.len())
+            .filter(|car| Some(*car) != skip)
+            .map(|car| (car, self.marginal_cost(car, call, state, base[car])))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+
+        let mut assignments = Vec::new();
+        for (idx, call) in state.hall_calls.iter().enumerate() {
+            match call.car {
+                None => {
+                    if let Some((car, _)) = best_car(call, None) {
+                        assignments.push(Assignment { call: idx, car });
+                    }
+                },
+                Some(car) if reassign && !state.is_stopping_at(car, call.floor) => {
+                    let current = base[car] - self.plan_cost(car, state, None, Some(idx));
+                    if let Some((other, cost)) = best_car(call, Some(car)) {
+                        if cost + self.parameters.hysteresis < current {
+                            assignments.push(Assignment { call: idx, car: other });
+                        }
+                    }
+                },
+                Some(_) => (),
+            }
+        }
+        if reassign {
+            self.last_reassignment = state.now;
+        }
+        assignments
+    }
+
+    fn next_stop(&mut self, car: usize, state: &DispatchState) -> Option<usize> {
+        self.sweep.next_stop(car, state)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_algorithms::CarCall;
+    use crate::test_fixtures::{car, dispatch_state, floors, population};
+    use crate::machine::elevator_system::ElevatorSystem;
+    use crate::population::generator::{ArrivalProcess, PassengerGenerator};
+    use crate::population::population::{Passenger, PassengerState};
+
+    #[test]
+    fn busy_car_is_passed_over() {
+        let floors = floors(10);
+        let near = car(&floors, 2);
+        let far = car(&floors, 6);
+        // the near car goes down to the ground floor first
+        let car_calls = [CarCall { car: 0, floor: 1 }, CarCall { car: 0, floor: 0 }];
+        let hall_calls = [HallCall { floor: 3, direction: Direction::Up, time: 0., car: None }];
+        let state = |hall_calls| {
+            let mut state = DispatchState { hall_calls, car_calls: &car_calls, ..dispatch_state(&floors, &[&near, &far]) };
+            state.cars[0].direction = Some(Direction::Down);
+            state.cars[0].passengers = 2;
+            state
+        };
+
+        let mut eta = EtaDispatcher::default();
+        let assignments = eta.assign(&state(&hall_calls));
+        assert!(assignments == vec![Assignment { call: 0, car: 1 }]);
+
+        // the far car waits 3 floors, the near one has to go down and back
+        let dispatcher = EtaDispatcher::default();
+        let idle = dispatcher.plan_cost(1, &state(&hall_calls), Some(&hall_calls[0]), None);
+        let busy = dispatcher.plan_cost(0, &state(&hall_calls), Some(&hall_calls[0]), None)
+            - dispatcher.plan_cost(0, &state(&hall_calls), None, None);
+        assert!(idle < busy, "{} {}", idle, busy);
+
+        // given to the wrong car, it is moved after the interval
+        let assigned = [HallCall { car: Some(0), ..hall_calls[0] }];
+        let mut eta = EtaDispatcher::default();
+        assert!(eta.assign(&state(&assigned)).is_empty());
+        let later = DispatchState { now: 10., ..state(&assigned) };
+        assert!(eta.assign(&later) == vec![Assignment { call: 0, car: 1 }]);
+    }
+
+    #[test]
+    fn slow_people_delay_the_plan() {
+        let floors = floors(10);
+        let elevator = car(&floors, 0);
+        let hall_calls = [
+            HallCall { floor: 3, direction: Direction::Up, time: 0., car: Some(0) },
+            HallCall { floor: 6, direction: Direction::Up, time: 0., car: Some(0) },
+        ];
+        let mut passengers = vec![Passenger::new(0, 3, 8, 75., 0.), Passenger::new(1, 6, 9, 75., 0.)];
+        let plan = |passengers: &[Passenger]| {
+            let state = DispatchState {
+                hall_calls: &hall_calls,
+                passengers,
+                waiting: &[0, 1],
+                ..dispatch_state(&floors, &[&elevator])
+            };
+            EtaDispatcher::default().plan_cost(0, &state, None, None)
+        };
+        let quick = plan(&passengers);
+        // the people on the 6th floor wait for the one getting in on the 3rd
+        passengers[0].boarding_duration = 20.;
+        assert!(plan(&passengers) - quick >= 20., "{} {}", plan(&passengers), quick);
+    }
+
+    #[test]
+    fn carries_everyone() {
+        let parameters = population(ArrivalProcess::Poisson { rate: 600. }, 5, 0.);
+        let mut system = ElevatorSystem::new(3, floors(8));
+        system.set_dispatcher(Box::new(EtaDispatcher::default()));
+        system.set_generator(PassengerGenerator::new(parameters, 8).unwrap());
+        system.run_for(600.);
+        system.generator = None;
+        system.run_for(300.);
+
+        assert!(system.passengers.len() > 50);
+        assert!(system.passengers.iter().all(|passenger| passenger.state == PassengerState::Arrived));
+        assert!(system.hall_calls.is_empty() && system.car_calls.is_empty());
+    }
+}
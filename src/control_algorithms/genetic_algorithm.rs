@@ -0,0 +1,438 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Dispatch policies found by a genetic algorithm. A policy gives a hall call
+// to the car with the lowest weighted cost:
+//   distance  meters of travel until the car can answer it (as in LOOK)
+//   direction the car sweeps the other way or has passed the call
+//   load      part of the rated load already in the car
+//   waiting   seconds the calls the car already has have waited
+//   stops     stops the car already has
+//   zone      the floor is in the zone of another car
+// The weights and the zone of every floor are the genes. The cost of a policy
+// is the mean waiting plus riding time of the passengers of seeded
+// ElevatorSystem simulations, every policy is run with the same seeds so the
+// costs can be compared. The next generation keeps the elites and the rest
+// are children of parents picked by tournament, mixed gene by gene and
+// mutated. The best policy is written as yaml and loaded again by
+// PolicyDispatcher::from_file.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use crate::machine::elevator_system::ElevatorSystem;
+use crate::population::generator::{PassengerGenerator, PopulationParameters};
+use crate::random::normal;
+use super::basic_algorithm::CollectiveControl;
+use super::{write_history, Assignment, DispatchState, Dispatcher, HallCall};
+
+pub const GENE_NAMES: [&str; 6] = ["distance", "direction", "load", "waiting", "stops", "zone"];
+// the weights are searched in these bounds
+pub const GENE_BOUNDS: [(f32, f32); 6] = [(0., 5.), (0., 60.), (0., 60.), (0., 2.), (0., 30.), (0., 60.)];
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct DispatchPolicy {
+    pub distance_weight: f32,  // per meter
+    pub direction_weight: f32, // once
+    pub load_weight: f32,      // per full car
+    pub waiting_weight: f32,   // per second
+    pub stop_weight: f32,      // per stop
+    pub zone_weight: f32,      // once
+    pub zones: Vec<usize>,     // car of every floor, empty without zoning
+}
+
+impl Default for DispatchPolicy {
+    // the nearest car of collective control
+    fn default() -> Self {
+        Self {
+            distance_weight: 1.,
+            direction_weight: 0.,
+            load_weight: 0.,
+            waiting_weight: 0.,
+            stop_weight: 3.,
+            zone_weight: 0.,
+            zones: Vec::new(),
+        }
+    }
+}
+
+impl DispatchPolicy {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+
+    pub fn to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
+
+    // the weights in the order of GENE_NAMES
+    pub fn genes(&self) -> [f32; 6] {
+        [
+            self.distance_weight,
+            self.direction_weight,
+            self.load_weight,
+            self.waiting_weight,
+            self.stop_weight,
+            self.zone_weight,
+        ]
+    }
+
+    pub fn set_genes(&mut self, genes: [f32; 6]) {
+        [
+            self.distance_weight,
+            self.direction_weight,
+            self.load_weight,
+            self.waiting_weight,
+            self.stop_weight,
+            self.zone_weight,
+        ] = genes;
+    }
+
+    fn zone(&self, floor: usize, num_cars: usize) -> Option<usize> {
+        self.zones.get(floor).map(|car| car % num_cars)
+    }
+
+    pub fn cost(&self, car: usize, call: &HallCall, state: &DispatchState, sweep: &CollectiveControl) -> f32 {
+        let elevator = state.cars[car].elevator;
+        let against = state.cars[car].direction.is_some_and(|direction| {
+            direction != call.direction || !state.is_ahead(car, call.floor, direction)
+        });
+        let waited: f64 = state.hall_calls.iter()
+            .filter(|other| other.car == Some(car))
+            .map(|other| state.now - other.time)
+            .sum();
+        let outside = self.zone(call.floor, state.cars.len()).is_some_and(|zone| zone != car);
+
+        self.distance_weight * sweep.travel_distance(car, call, state)
+            + self.direction_weight * against as u8 as f32
+            + self.load_weight * elevator.current_load / elevator.max_load
+            + self.waiting_weight * waited as f32
+            + self.stop_weight * state.work_floors(car).len() as f32
+            + self.zone_weight * outside as u8 as f32
+    }
+}
+
+pub struct PolicyDispatcher {
+    pub policy: DispatchPolicy,
+    sweep: CollectiveControl,
+}
+
+impl PolicyDispatcher {
+    pub fn new(policy: DispatchPolicy) -> Self {
+        Self { policy, sweep: CollectiveControl::default() }
+    }
+
+    // a policy trained before
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::new(DispatchPolicy::from_file(file_path)?))
+    }
+}
+
+impl Dispatcher for PolicyDispatcher {
+    fn assign(&mut self, state: &DispatchState) -> Vec<Assignment> {
+        state.cheapest_cars(|car, call| self.policy.cost(car, call, state, &self.sweep))
+    }
+
+    // the cars sweep like in collective control
+    fn next_stop(&mut self, car: usize, state: &DispatchState) -> Option<usize> {
+        self.sweep.next_stop(car, state)
+    }
+}
+
+// the simulations a policy is judged with
+#[derive(Debug, Deserialize, Clone)]
+pub struct Evaluation {
+    pub num_elevators: usize,
+    pub num_floors: usize,
+    pub floor_height: f32,
+    pub duration: f64,   // seconds passengers come
+    pub drain_time: f64, // seconds more to carry the rest
+    pub seeds: Vec<u64>, // one simulation with every seed
+    pub population: PopulationParameters,
+    pub riding_weight: f32,    // a second of riding against a second of waiting
+    pub unserved_penalty: f32, // seconds on top for everyone not arrived at the end
+}
+
+impl Evaluation {
+    pub fn floors(&self) -> Vec<f32> {
+        (0..self.num_floors).map(|floor| floor as f32 * self.floor_height).collect()
+    }
+
+    // mean cost of a passenger in one simulation
+    pub fn simulate(&self, policy: &DispatchPolicy, seed: u64) -> Result<f32, Box<dyn Error>> {
+        let mut population = self.population.clone();
+        population.seed = seed;
+        let mut system = ElevatorSystem::new(self.num_elevators, self.floors());
+        system.set_dispatcher(Box::new(PolicyDispatcher::new(policy.clone())));
+        system.set_generator(PassengerGenerator::new(population, self.num_floors)?);
+        system.run_for(self.duration);
+        system.generator = None;
+        system.run_for(self.drain_time);
+
+        let now = system.now();
+        let costs: Vec<f32> = system.passengers.iter()
+            .map(|passenger| match (passenger.waiting_time(), passenger.journey_time()) {
+                (Some(waiting), Some(journey)) => {
+                    (waiting + self.riding_weight as f64 * (journey - waiting)) as f32
+                },
+                _ => (now - passenger.spawn_time) as f32 + self.unserved_penalty,
+            })
+            .collect();
+        if costs.is_empty() {
+            return Ok(0.);
+        }
+        Ok(costs.iter().sum::<f32>() / costs.len() as f32)
+    }
+
+    // mean over the seeds
+    pub fn cost(&self, policy: &DispatchPolicy) -> Result<f32, Box<dyn Error>> {
+        if self.seeds.is_empty() {
+            return Ok(0.);
+        }
+        let mut total = 0.;
+        for seed in &self.seeds {
+            total += self.simulate(policy, *seed)?;
+        }
+        Ok(total / self.seeds.len() as f32)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GeneticParameters {
+    pub population_size: usize,
+    pub generations: usize,
+    pub tournament_size: usize,
+    pub crossover_rate: f32, // chance of two parents to be mixed, else the child is the first one
+    pub mutation_rate: f32,  // chance of every gene to change
+    pub mutation_std: f32,   // part of the bounds of the gene
+    pub elitism: usize,      // best ones that go to the next generation as they are
+    pub zoning: bool,        // the zones of the floors are genes too
+    pub seed: u64,
+    pub evaluation: Evaluation,
+}
+
+impl GeneticParameters {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Individual {
+    pub policy: DispatchPolicy,
+    pub cost: f32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct GenerationStats {
+    pub generation: usize,
+    pub best_cost: f32,
+    pub mean_cost: f32,
+}
+
+pub struct GeneticTrainer {
+    pub parameters: GeneticParameters,
+    pub population: Vec<Individual>, // best first
+    pub history: Vec<GenerationStats>,
+    rng: StdRng,
+}
+
+impl GeneticTrainer {
+    // random policies and the one of collective control, so the result is
+    // never worse than it on the seeds of the evaluation
+    pub fn new(parameters: GeneticParameters) -> Result<Self, Box<dyn Error>> {
+        // the zones are cars, random_policy and mutate pick one of them
+        if parameters.evaluation.num_elevators == 0 {
+            return Err("the evaluation has no elevators".into());
+        }
+        let mut rng = StdRng::seed_from_u64(parameters.seed);
+        let mut policies = vec![DispatchPolicy::default()];
+        if parameters.zoning {
+            policies[0].zones = vec![0; parameters.evaluation.num_floors];
+        }
+        while policies.len() < parameters.population_size.max(1) {
+            let policy = Self::random_policy(&parameters, &mut rng);
+            policies.push(policy);
+        }
+
+        let mut trainer = Self { parameters, population: Vec::new(), history: Vec::new(), rng };
+        for policy in policies {
+            let individual = trainer.evaluate(policy)?;
+            trainer.population.push(individual);
+        }
+        trainer.record();
+        Ok(trainer)
+    }
+
+    fn random_policy(parameters: &GeneticParameters, rng: &mut StdRng) -> DispatchPolicy {
+        let mut policy = DispatchPolicy::default();
+        policy.set_genes(GENE_BOUNDS.map(|(lower, upper)| rng.gen_range(lower..=upper)));
+        if parameters.zoning {
+            let evaluation = &parameters.evaluation;
+            policy.zones = (0..evaluation.num_floors).map(|_| rng.gen_range(0..evaluation.num_elevators)).collect();
+        }
+        policy
+    }
+
+    fn evaluate(&self, policy: DispatchPolicy) -> Result<Individual, Box<dyn Error>> {
+        let cost = self.parameters.evaluation.cost(&policy)?;
+        Ok(Individual { policy, cost })
+    }
+
+    fn record(&mut self) {
+        self.population.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+        let mean_cost = self.population.iter().map(|individual| individual.cost).sum::<f32>()
+            / self.population.len() as f32;
+        self.history.push(GenerationStats {
+            generation: self.history.len(),
+            best_cost: self.population[0].cost,
+            mean_cost,
+        });
+    }
+
+    pub fn best(&self) -> &Individual {
+        &self.population[0]
+    }
+
+    // the best of tournament_size random individuals
+    fn tournament(&mut self) -> usize {
+        (0..self.parameters.tournament_size.max(1))
+            .map(|_| self.rng.gen_range(0..self.population.len()))
+            .min()
+            .unwrap_or(0) // sorted, the lowest index is the best
+    }
+
+    // every gene from one of the parents
+    fn crossover(&mut self, first: &DispatchPolicy, second: &DispatchPolicy) -> DispatchPolicy {
+        let mut child = first.clone();
+        if self.rng.gen::<f32>() >= self.parameters.crossover_rate {
+            return child;
+        }
+        let (first_genes, second_genes) = (first.genes(), second.genes());
+        let mut genes = first_genes;
+        for (i, gene) in genes.iter_mut().enumerate() {
+            if self.rng.gen::<bool>() {
+                *gene = second_genes[i];
+            }
+        }
+        child.set_genes(genes);
+        for (i, zone) in child.zones.iter_mut().enumerate() {
+            if self.rng.gen::<bool>() {
+                *zone = second.zones.get(i).copied().unwrap_or(*zone);
+            }
+        }
+        child
+    }
+
+    fn mutate(&mut self, policy: &mut DispatchPolicy) {
+        let mut genes = policy.genes();
+        for (i, gene) in genes.iter_mut().enumerate() {
+            if self.rng.gen::<f32>() < self.parameters.mutation_rate {
+                let (lower, upper) = GENE_BOUNDS[i];
+                let std = self.parameters.mutation_std * (upper - lower);
+                *gene = normal(&mut self.rng, *gene, std).clamp(lower, upper);
+            }
+        }
+        policy.set_genes(genes);
+        let num_elevators = self.parameters.evaluation.num_elevators;
+        for zone in policy.zones.iter_mut() {
+            if self.rng.gen::<f32>() < self.parameters.mutation_rate {
+                *zone = self.rng.gen_range(0..num_elevators);
+            }
+        }
+    }
+
+    // one generation
+    pub fn step(&mut self) -> Result<(), Box<dyn Error>> {
+        let size = self.population.len();
+        let mut next: Vec<Individual> = self.population.iter().take(self.parameters.elitism.min(size)).cloned().collect();
+        while next.len() < size {
+            let first = self.tournament();
+            let second = self.tournament();
+            let mut child = self.crossover(&self.population[first].policy.clone(), &self.population[second].policy.clone());
+            self.mutate(&mut child);
+            next.push(self.evaluate(child)?);
+        }
+        self.population = next;
+        self.record();
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<&Individual, Box<dyn Error>> {
+        for _ in 0..self.parameters.generations {
+            self.step()?;
+        }
+        Ok(self.best())
+    }
+
+    // generation, best and mean cost
+    pub fn write_history(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        write_history(file_path, &self.history)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_algorithms::Direction;
+    use crate::test_fixtures::{car, dispatch_state, floors, temp_path};
+
+    #[test]
+    fn zones_and_saved_policy() {
+        let floors = floors(6);
+        let first = car(&floors, 0);
+        let second = car(&floors, 0);
+        let hall_calls = [HallCall { floor: 4, direction: Direction::Down, time: 0., car: None }];
+        let state = DispatchState { hall_calls: &hall_calls, ..dispatch_state(&floors, &[&first, &second]) };
+        // both cars are as far, the upper floors are the zone of the second
+        let policy = DispatchPolicy { zone_weight: 20., zones: vec![0, 0, 0, 1, 1, 1], ..Default::default() };
+        assert!(PolicyDispatcher::new(policy.clone()).assign(&state) == vec![Assignment { call: 0, car: 1 }]);
+
+        let file_path = temp_path("elevator_dispatch_policy.yaml");
+        let file_path = file_path.as_str();
+        policy.to_file(file_path).unwrap();
+        let dispatcher = PolicyDispatcher::from_file(file_path).unwrap();
+        assert!(dispatcher.policy == policy);
+        std::fs::remove_file(file_path).unwrap();
+        assert!(DispatchPolicy::from_file("param/dispatch_policy.yaml").unwrap().zones.len() == 10);
+    }
+
+    #[test]
+    fn training_keeps_the_best() {
+        let mut parameters = GeneticParameters::from_file("param/genetic_parameters.yaml").unwrap();
+        parameters.population_size = 4;
+        parameters.generations = 2;
+        parameters.elitism = 1;
+        parameters.evaluation.num_floors = 6;
+        parameters.evaluation.duration = 120.;
+        parameters.evaluation.drain_time = 120.;
+        parameters.evaluation.seeds = vec![1];
+        let look = parameters.evaluation.cost(&DispatchPolicy::default()).unwrap();
+
+        let mut trainer = GeneticTrainer::new(parameters).unwrap();
+        let best = trainer.run().unwrap().clone();
+        assert!(trainer.history.len() == 3);
+        assert!(trainer.history.windows(2).all(|pair| pair[1].best_cost <= pair[0].best_cost));
+        assert!(best.cost <= look);
+        assert!(best.policy.zones.len() == 6);
+        // the costs are from seeded simulations
+        assert!(trainer.parameters.evaluation.cost(&best.policy).unwrap() == best.cost);
+    }
+
+    #[test]
+    fn rejects_no_elevators() {
+        let mut parameters = GeneticParameters::from_file("param/genetic_parameters.yaml").unwrap();
+        parameters.evaluation.num_elevators = 0;
+        assert!(GeneticTrainer::new(parameters).is_err());
+    }
+}
@@ -1,2 +1,255 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
-// Copyright (C) 2024 Tuna Gül
\ No newline at end of file
+// Copyright (C) 2024 Tuna Gül
+
+// Group control of the cars. The people waiting on a floor press the up or
+// down button (hall call), the people in a car press the buttons of their
+// floors (car call). A dispatcher gets the calls and the state of every car,
+// gives every hall call to a car and tells each car where to stop next. The
+// ElevatorSystem does the rest: it moves the cars, opens the doors and lets
+// the people in and out.
+// With destination control the people type their floor on a keypad in the
+// hall instead (destination call) and are shown the letter of the car they
+// have to take, the dispatcher knows where everyone goes before they get in.
+
+pub mod basic_algorithm;
+pub mod eta_algorithm;
+pub mod destination_algorithm;
+pub mod genetic_algorithm;
+pub mod q_learning;
+pub mod neural_network;
+pub mod deep_q_learning;
+pub mod environment;
+
+use serde::Serialize;
+use std::error::Error;
+
+use crate::machine::elevator::Elevator;
+use crate::machine::elevator_system::CarStatus;
+use crate::population::population::Passenger;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+impl Direction {
+    pub fn of_trip(origin: usize, destination: usize) -> Self {
+        if destination > origin { Direction::Up } else { Direction::Down }
+    }
+
+    pub fn opposite(&self) -> Self {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+        }
+    }
+
+    // +1 going up, -1 going down
+    pub fn sign(&self) -> f32 {
+        match self {
+            Direction::Up => 1.,
+            Direction::Down => -1.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HallCall {
+    pub floor: usize,
+    pub direction: Direction,
+    pub time: f64,          // the button was pressed at this time
+    pub car: Option<usize>, // the car that answers it
+}
+
+// a person typed the destination on the keypad of the floor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DestinationCall {
+    pub passenger: usize, // index in ElevatorSystem::passengers
+    pub floor: usize,
+    pub destination: usize,
+    pub time: f64,
+    pub car: Option<usize>, // the car shown on the keypad, a new one when it leaves the person behind
+}
+
+impl DestinationCall {
+    pub fn direction(&self) -> Direction {
+        Direction::of_trip(self.floor, self.destination)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CarCall {
+    pub car: usize,
+    pub floor: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assignment {
+    pub call: usize, // index in DispatchState::hall_calls or destination_calls
+    pub car: usize,
+}
+
+// what the dispatcher sees of a car
+#[derive(Clone, Copy)]
+pub struct CarState<'a> {
+    pub elevator: &'a Elevator,
+    pub status: CarStatus,
+    pub direction: Option<Direction>, // direction of the sweep, None while it has nothing to do
+    pub passengers: usize,
+    pub riding: &'a [usize], // indices in DispatchState::passengers of the people in the car
+}
+
+pub struct DispatchState<'a> {
+    pub now: f64,
+    pub floors: &'a [f32],
+    pub dwell_time: f32, // doors open and close, without the people
+    pub cars: Vec<CarState<'a>>,
+    pub hall_calls: &'a [HallCall],
+    pub destination_calls: &'a [DestinationCall], // the people still waiting
+    pub car_calls: &'a [CarCall],
+    // everyone so far and the indices of the people waiting
+    pub passengers: &'a [Passenger],
+    pub waiting: &'a [usize],
+}
+
+impl DispatchState<'_> {
+    // the floors and the directions of the people the car picks up, from the
+    // hall calls and the destination calls
+    pub fn landing_calls(&self, car: usize) -> impl Iterator<Item = (usize, Direction)> + '_ {
+        self.hall_calls.iter()
+            .filter(move |call| call.car == Some(car))
+            .map(|call| (call.floor, call.direction))
+            .chain(self.destination_calls.iter()
+                .filter(move |call| call.car == Some(car))
+                .map(|call| (call.floor, call.direction())))
+    }
+
+    // floors the car has to stop at: its car calls and its landing calls
+    pub fn work_floors(&self, car: usize) -> Vec<usize> {
+        let mut floors: Vec<usize> = self.car_calls.iter()
+            .filter(|call| call.car == car)
+            .map(|call| call.floor)
+            .chain(self.landing_calls(car).map(|(floor, _)| floor))
+            .collect();
+        floors.sort();
+        floors.dedup();
+        floors
+    }
+
+    // the closest height the car can still stop at
+    pub fn reach(&self, car: usize) -> f32 {
+        self.cars[car].elevator.stopping_height()
+    }
+
+    // the floor is in front of the car in the given direction. A standing car
+    // does not count its own floor, a moving one counts the floor it is
+    // stopping at
+    pub fn is_ahead(&self, car: usize, floor: usize, direction: Direction) -> bool {
+        let margin = match self.cars[car].status {
+            CarStatus::Moving { .. } => -0.01,
+            _ => 0.01,
+        };
+        (self.floors[floor] - self.reach(car)) * direction.sign() > margin
+    }
+
+    // the car already goes there or stands there with open doors
+    pub fn is_stopping_at(&self, car: usize, floor: usize) -> bool {
+        match self.cars[car].status {
+            CarStatus::Moving { floor: target } | CarStatus::DoorsOpen { floor: target, .. } => target == floor,
+            CarStatus::Idle => false,
+        }
+    }
+
+    // the car going to target can still stop at the floor before it
+    pub fn is_on_the_way(&self, car: usize, floor: usize, target: usize) -> bool {
+        let sign = self.cars[car].direction.map_or(0., |direction| direction.sign());
+        floor != target
+            && (self.floors[floor] - self.reach(car)) * sign >= 0.
+            && (self.floors[target] - self.floors[floor]) * sign > 0.
+    }
+
+    // every hall call without a car goes to the car it costs the least
+    pub fn cheapest_cars(&self, cost: impl Fn(usize, &HallCall) -> f32) -> Vec<Assignment> {
+        self.hall_calls.iter().enumerate()
+            .filter(|(_, call)| call.car.is_none())
+            .filter_map(|(idx, call)| {
+                (0..self.cars.len())
+                    .map(|car| (car, cost(car, call)))
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .map(|(car, _)| Assignment { call: idx, car })
+            })
+            .collect()
+    }
+
+    // the way the car goes on from the floor, see departure_direction
+    pub fn departure_direction(&self, car: usize, floor: usize) -> Option<Direction> {
+        let call_here = |direction: Direction| {
+            self.hall_calls.iter().any(|call| call.floor == floor && call.direction == direction)
+                || self.destination_calls.iter()
+                    .any(|call| call.floor == floor && call.car == Some(car) && call.direction() == direction)
+        };
+        let work_towards = |direction: Direction| {
+            let ahead = |other: usize| match direction {
+                Direction::Up => other > floor,
+                Direction::Down => other < floor,
+            };
+            self.car_calls.iter().any(|call| call.car == car && ahead(call.floor))
+                || self.hall_calls.iter().any(|call| call.car == Some(car) && ahead(call.floor))
+                || self.destination_calls.iter().any(|call| call.car == Some(car) && ahead(call.floor))
+        };
+        departure_direction(self.cars[car].direction, call_here, work_towards)
+    }
+}
+
+// the way a car sweeping in the direction goes on from a floor: on in the same
+// direction while it has calls that way, the other way when it turns, None
+// without calls. call_here tells if somebody on the floor goes the way,
+// work_towards if the car has somewhere to stop that way
+pub fn departure_direction(
+    direction: Option<Direction>,
+    call_here: impl Fn(Direction) -> bool,
+    work_towards: impl Fn(Direction) -> bool,
+) -> Option<Direction> {
+    match direction {
+        Some(direction) if call_here(direction) || work_towards(direction) => Some(direction),
+        Some(direction) if call_here(direction.opposite()) || work_towards(direction.opposite()) => {
+            Some(direction.opposite())
+        },
+        Some(_) => None,
+        None => [Direction::Up, Direction::Down].into_iter()
+            .find(|direction| call_here(*direction))
+            .or_else(|| [Direction::Up, Direction::Down].into_iter().find(|direction| work_towards(*direction))),
+    }
+}
+
+// the history of a training, one row per episode or generation
+pub fn write_history<T: Serialize>(file_path: &str, history: &[T]) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(file_path)?;
+    for stats in history {
+        writer.serialize(stats)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub trait Dispatcher {
+    // cars for the hall calls without one, it is asked on every step. A
+    // dispatcher may also give a call that has a car to another one
+    fn assign(&mut self, state: &DispatchState) -> Vec<Assignment>;
+
+    // the floor the car stops at next, None parks it. It is asked again while
+    // the car moves, a closer floor it can still stop at becomes the new stop
+    fn next_stop(&mut self, car: usize, state: &DispatchState) -> Option<usize>;
+
+    // the halls have keypads instead of up and down buttons
+    fn uses_destinations(&self) -> bool {
+        false
+    }
+
+    // cars for the destination calls without one, the calls keep their car
+    // until it leaves them behind
+    fn assign_destinations(&mut self, _state: &DispatchState) -> Vec<Assignment> {
+        Vec::new()
+    }
+}
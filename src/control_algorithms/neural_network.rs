@@ -0,0 +1,253 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// A small multilayer perceptron for the deep Q learning, on the cpu without
+// any library. The hidden layers are ReLU, the output is linear. It learns
+// with Adam on the Huber loss of one output of every sample (the value of the
+// action that was taken), the other outputs get no gradient. The weights are
+// written as json, the state of Adam is not kept.
+
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use crate::random::normal;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Layer {
+    pub inputs: usize,
+    pub outputs: usize,
+    pub weights: Vec<f32>, // outputs x inputs, a row for every output
+    pub biases: Vec<f32>,
+}
+
+impl Layer {
+    // He initialization, for the ReLU
+    pub fn new(inputs: usize, outputs: usize, rng: &mut StdRng) -> Self {
+        let std = (2. / inputs.max(1) as f32).sqrt();
+        Self {
+            inputs,
+            outputs,
+            weights: (0..inputs * outputs).map(|_| normal(rng, 0., std)).collect(),
+            biases: vec![0.; outputs],
+        }
+    }
+
+    fn forward(&self, input: &[f32]) -> Vec<f32> {
+        (0..self.outputs)
+            .map(|out| {
+                let row = &self.weights[out * self.inputs..(out + 1) * self.inputs];
+                self.biases[out] + row.iter().zip(input).map(|(weight, x)| weight * x).sum::<f32>()
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Mlp {
+    pub layers: Vec<Layer>,
+}
+
+// a sample of the training, the output to move and where to
+#[derive(Debug, Clone)]
+pub struct Sample<'a> {
+    pub input: &'a [f32],
+    pub output: usize,
+    pub target: f32,
+}
+
+impl Mlp {
+    // sizes of the input, the hidden layers and the output
+    pub fn new(sizes: &[usize], rng: &mut StdRng) -> Self {
+        Self { layers: sizes.windows(2).map(|pair| Layer::new(pair[0], pair[1], rng)).collect() }
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.layers.first().map_or(0, |layer| layer.inputs)
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.layers.last().map_or(0, |layer| layer.outputs)
+    }
+
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_json::from_reader(file)?;
+        Ok(result)
+    }
+
+    pub fn to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+
+    // the input of every layer and the output of the last one
+    fn activations(&self, input: &[f32]) -> Vec<Vec<f32>> {
+        let mut activations = vec![input.to_vec()];
+        for (i, layer) in self.layers.iter().enumerate() {
+            let mut output = layer.forward(&activations[i]);
+            if i + 1 < self.layers.len() {
+                output.iter_mut().for_each(|value| *value = value.max(0.));
+            }
+            activations.push(output);
+        }
+        activations
+    }
+
+    pub fn forward(&self, input: &[f32]) -> Vec<f32> {
+        self.activations(input).pop().unwrap_or_default()
+    }
+
+    // gradients of the mean Huber loss of the batch, in the shape of the
+    // layers, and the loss
+    fn gradients(&self, batch: &[Sample], huber_delta: f32) -> (Vec<Layer>, f32) {
+        let mut gradients: Vec<Layer> = self.layers.iter()
+            .map(|layer| Layer { weights: vec![0.; layer.weights.len()], biases: vec![0.; layer.biases.len()], ..*layer })
+            .collect();
+        let mut loss = 0.;
+        for sample in batch {
+            let activations = self.activations(sample.input);
+            let output = activations.last().unwrap();
+            let error = output[sample.output] - sample.target;
+            loss += if error.abs() <= huber_delta {
+                0.5 * error * error
+            } else {
+                huber_delta * (error.abs() - 0.5 * huber_delta)
+            };
+
+            let mut delta = vec![0.; output.len()];
+            delta[sample.output] = error.clamp(-huber_delta, huber_delta);
+            for (i, layer) in self.layers.iter().enumerate().rev() {
+                let input = &activations[i];
+                let gradient = &mut gradients[i];
+                for (out, delta) in delta.iter().enumerate() {
+                    if *delta == 0. {
+                        continue;
+                    }
+                    gradient.biases[out] += delta;
+                    let row = &mut gradient.weights[out * layer.inputs..(out + 1) * layer.inputs];
+                    row.iter_mut().zip(input).for_each(|(weight, x)| *weight += delta * x);
+                }
+                if i == 0 {
+                    break;
+                }
+                // back through the weights and the ReLU of the layer below
+                delta = (0..layer.inputs)
+                    .map(|j| {
+                        if input[j] <= 0. {
+                            return 0.;
+                        }
+                        (0..layer.outputs).map(|out| layer.weights[out * layer.inputs + j] * delta[out]).sum()
+                    })
+                    .collect();
+            }
+        }
+
+        let scale = 1. / batch.len().max(1) as f32;
+        for gradient in &mut gradients {
+            gradient.weights.iter_mut().chain(gradient.biases.iter_mut()).for_each(|value| *value *= scale);
+        }
+        (gradients, loss * scale)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AdamParameters {
+    pub learning_rate: f32,
+    pub beta1: f32,
+    pub beta2: f32,
+    pub epsilon: f32,
+    pub huber_delta: f32, // the error is clipped to this in the gradient
+}
+
+impl Default for AdamParameters {
+    fn default() -> Self {
+        Self { learning_rate: 1e-3, beta1: 0.9, beta2: 0.999, epsilon: 1e-8, huber_delta: 1. }
+    }
+}
+
+// Adam, with the moments of every weight and bias of the network
+pub struct Adam {
+    pub parameters: AdamParameters,
+    first: Vec<Vec<f32>>,  // of every layer, the weights then the biases
+    second: Vec<Vec<f32>>,
+    steps: i32,
+}
+
+impl Adam {
+    pub fn new(parameters: AdamParameters, network: &Mlp) -> Self {
+        let zeros: Vec<Vec<f32>> = network.layers.iter()
+            .map(|layer| vec![0.; layer.weights.len() + layer.biases.len()])
+            .collect();
+        Self { parameters, first: zeros.clone(), second: zeros, steps: 0 }
+    }
+
+    // one step on the batch, gives the loss before it
+    pub fn step(&mut self, network: &mut Mlp, batch: &[Sample]) -> f32 {
+        let AdamParameters { learning_rate, beta1, beta2, epsilon, huber_delta } = self.parameters;
+        let (gradients, loss) = network.gradients(batch, huber_delta);
+        self.steps += 1;
+        let correction1 = 1. - beta1.powi(self.steps);
+        let correction2 = 1. - beta2.powi(self.steps);
+
+        for (i, (layer, gradient)) in network.layers.iter_mut().zip(&gradients).enumerate() {
+            let values = layer.weights.iter_mut().chain(layer.biases.iter_mut());
+            let grads = gradient.weights.iter().chain(gradient.biases.iter());
+            for (k, (value, grad)) in values.zip(grads).enumerate() {
+                let first = &mut self.first[i][k];
+                let second = &mut self.second[i][k];
+                *first = beta1 * *first + (1. - beta1) * grad;
+                *second = beta2 * *second + (1. - beta2) * grad * grad;
+                *value -= learning_rate * (*first / correction1) / ((*second / correction2).sqrt() + epsilon);
+            }
+        }
+        loss
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn learns_xor() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut network = Mlp::new(&[2, 16, 1], &mut rng);
+        let mut adam = Adam::new(AdamParameters { learning_rate: 0.01, ..Default::default() }, &network);
+        let inputs = [[0., 0.], [0., 1.], [1., 0.], [1., 1.]];
+        let targets = [0., 1., 1., 0.];
+        let batch: Vec<Sample> = inputs.iter().zip(targets)
+            .map(|(input, target)| Sample { input, output: 0, target })
+            .collect();
+        let first = adam.step(&mut network, &batch);
+        for _ in 0..2000 {
+            adam.step(&mut network, &batch);
+        }
+        let last = adam.step(&mut network, &batch);
+        assert!(last < first * 0.01, "{} {}", first, last);
+        for (input, target) in inputs.iter().zip(targets) {
+            assert!((network.forward(input)[0] - target).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn gradient_matches_difference() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let network = Mlp::new(&[3, 5, 2], &mut rng);
+        let input: Vec<f32> = (0..3).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let sample = Sample { input: &input, output: 1, target: network.forward(&input)[1] + 0.5 };
+        let (gradients, loss) = network.gradients(std::slice::from_ref(&sample), 10.);
+
+        // a weight of the first layer, moved a little
+        let step = 1e-3;
+        let mut moved = network.clone();
+        moved.layers[0].weights[4] += step;
+        let (_, moved_loss) = moved.gradients(&[sample], 10.);
+        let numeric = (moved_loss - loss) / step;
+        assert!((numeric - gradients[0].weights[4]).abs() < 1e-2, "{} {}", numeric, gradients[0].weights[4]);
+    }
+}
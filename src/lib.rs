@@ -0,0 +1,9 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+pub mod machine;
+pub mod control_algorithms;
+pub mod population;
+pub mod random;
+#[cfg(test)]
+mod test_fixtures;
@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// The parts every controller that is not the pid shares: target and its
+// limits, the update frequency and the output limits. The pid has its own
+// copy of these in pid_controller.rs.
+
+pub struct ControllerCommon {
+    pub target: f32,
+    pub tolerance: f32,
+    update_freq: f32,
+    accumulated_time: f32,
+    target_limits: Option<(f32, f32)>,
+    output_limits: Option<(f32, f32)>,
+    change_limit: f32,
+    pub prev_output: f32,
+}
+
+impl ControllerCommon {
+    pub fn new(update_freq: f32, tolerance: f32) -> Self {
+        Self {
+            target: 0.,
+            tolerance,
+            update_freq,
+            accumulated_time: 0.,
+            target_limits: None,
+            output_limits: None,
+            change_limit: 0.,
+            prev_output: 0.,
+        }
+    }
+
+    pub fn set_target(&mut self, target: f32) -> bool {
+        self.target = match self.target_limits {
+            Some((min_target, max_target)) => target.clamp(min_target, max_target),
+            None => target,
+        };
+        self.target == target
+    }
+
+    pub fn has_reached_target(&self, measurement: f32) -> bool {
+        (self.target - measurement).abs() < self.tolerance
+    }
+
+    // the time step of this update when it is time to update, None in between
+    pub fn tick(&mut self, delta_time: f32) -> Option<f32> {
+        self.accumulated_time += delta_time;
+        // same slack as the pid against the rounding of the summed steps
+        if self.accumulated_time < (1.0 / self.update_freq) * 0.999 {
+            return None;
+        }
+        let delta_time = self.accumulated_time;
+        self.accumulated_time = 0.;
+        Some(delta_time)
+    }
+
+    pub fn limit_output(&mut self, mut output: f32, delta_time: f32) -> f32 {
+        if self.change_limit != 0. {
+            let max_change = self.change_limit * delta_time;
+            output = output.clamp(self.prev_output - max_change, self.prev_output + max_change);
+        }
+        if let Some((min_output, max_output)) = self.output_limits {
+            output = output.clamp(min_output, max_output);
+        }
+        self.prev_output = output;
+        output
+    }
+
+    pub fn reset(&mut self) {
+        self.accumulated_time = 0.;
+        self.prev_output = 0.;
+    }
+
+    pub fn set_output_limits(&mut self, min_output: f32, max_output: f32) {
+        self.output_limits = Some((min_output, max_output));
+    }
+
+    pub fn set_target_limits(&mut self, min_target: f32, max_target: f32) {
+        self.target_limits = Some((min_target, max_target));
+    }
+
+    pub fn set_change_limit(&mut self, change_limit: f32) {
+        self.change_limit = change_limit;
+    }
+}
@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Fuzzy PD controller. The error and the rate of the error are scaled into
+// [-1, 1] and fuzzified with five triangular sets (negative big, negative
+// small, zero, positive small, positive big). The rule table is the usual
+// diagonal one, the output level is the sum of the two input levels, and the
+// output is the weighted average of the rule levels (Sugeno singletons).
+
+use serde::{Deserialize, Serialize};
+
+use super::common::ControllerCommon;
+use super::Controller;
+
+const LEVELS: [f32; 5] = [-1., -0.5, 0., 0.5, 1.];
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FuzzyParameters {
+    pub error_scale: f32,  // error that is "big"
+    pub rate_scale: f32,   // error rate that is "big"
+    pub output_scale: f32, // output of a "big" rule
+    pub update_freq: f32,
+    pub tolerance: f32,
+}
+
+// membership of every set, the sets at the ends are open to the outside
+fn fuzzify(value: f32) -> [f32; 5] {
+    let value = value.clamp(-1., 1.);
+    let mut memberships = [0.; 5];
+    for (i, level) in LEVELS.iter().enumerate() {
+        memberships[i] = (1. - (value - level).abs() / 0.5).max(0.);
+    }
+    memberships
+}
+
+pub struct FuzzyController {
+    common: ControllerCommon,
+    parameters: FuzzyParameters,
+    prev_error: Option<f32>,
+}
+
+impl FuzzyController {
+    pub fn new(parameters: FuzzyParameters) -> Self {
+        Self {
+            common: ControllerCommon::new(parameters.update_freq, parameters.tolerance),
+            parameters,
+            prev_error: None,
+        }
+    }
+
+    // output of the rule base for the scaled inputs, in [-1, 1]
+    fn infer(error: f32, rate: f32) -> f32 {
+        let error_memberships = fuzzify(error);
+        let rate_memberships = fuzzify(rate);
+
+        let mut weighted_sum = 0.;
+        let mut weight_sum = 0.;
+        for (i, error_membership) in error_memberships.iter().enumerate() {
+            for (j, rate_membership) in rate_memberships.iter().enumerate() {
+                let weight = error_membership.min(*rate_membership);
+                if weight > 0. {
+                    let level = (LEVELS[i] + LEVELS[j]).clamp(-1., 1.);
+                    weighted_sum += weight * level;
+                    weight_sum += weight;
+                }
+            }
+        }
+
+        if weight_sum > 0. { weighted_sum / weight_sum } else { 0. }
+    }
+}
+
+impl Controller for FuzzyController {
+    fn set_target(&mut self, target: f32) -> bool {
+        self.common.set_target(target)
+    }
+
+    fn target(&self) -> f32 {
+        self.common.target
+    }
+
+    fn update(&mut self, measurement: f32, delta_time: f32) -> f32 {
+        let Some(delta_time) = self.common.tick(delta_time) else {
+            return self.common.prev_output;
+        };
+
+        let error = self.common.target - measurement;
+        let rate = match self.prev_error {
+            Some(prev_error) => (error - prev_error) / delta_time,
+            None => 0.,
+        };
+        self.prev_error = Some(error);
+
+        let output = Self::infer(
+            error / self.parameters.error_scale,
+            rate / self.parameters.rate_scale,
+        ) * self.parameters.output_scale;
+        self.common.limit_output(output, delta_time)
+    }
+
+    fn has_reached_target(&self, measurement: f32) -> bool {
+        self.common.has_reached_target(measurement)
+    }
+
+    fn reset(&mut self) {
+        self.common.reset();
+        self.prev_error = None;
+    }
+
+    fn set_output_limits(&mut self, min_output: f32, max_output: f32) {
+        self.common.set_output_limits(min_output, max_output);
+    }
+
+    fn set_target_limits(&mut self, min_target: f32, max_target: f32) {
+        self.common.set_target_limits(min_target, max_target);
+    }
+
+    fn set_change_limit(&mut self, change_limit: f32) {
+        self.common.set_change_limit(change_limit);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memberships_sum_to_one() {
+        for value in [-1., -0.8, -0.3, 0., 0.1, 0.5, 0.99] {
+            let sum: f32 = fuzzify(value).iter().sum();
+            assert!((sum - 1.).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn rule_base() {
+        assert!(FuzzyController::infer(0., 0.) == 0.);
+        assert!(FuzzyController::infer(1., 0.) == 1.);
+        assert!(FuzzyController::infer(-1., 0.) == -1.);
+        // moving towards the target fast enough, nothing to do
+        assert!(FuzzyController::infer(0.5, -0.5) == 0.);
+        // odd, the negative error gives the negative output
+        assert!(FuzzyController::infer(-0.3, 0.2) == -FuzzyController::infer(0.3, -0.2));
+    }
+}
@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Control laws of the loops. The car and the motor only know the Controller
+// trait, which law runs in a loop is chosen in the yaml with the type field:
+//   height_controller:
+//       type: lqr
+//       time_constant: 0.2
+//       ...
+
+pub mod common;
+pub mod state_feedback;
+pub mod fuzzy;
+pub mod qp;
+pub mod mpc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::machine::pid_controller::{PIDController, PIDParameters};
+use state_feedback::{LqrParameters, StateFeedbackController, StateFeedbackParameters};
+use fuzzy::{FuzzyController, FuzzyParameters};
+
+pub trait Controller {
+    // returns false when the target was out of the target limits and clamped
+    fn set_target(&mut self, target: f32) -> bool;
+    fn target(&self) -> f32;
+    // runs at the update frequency of the controller, returns the last output
+    // in between
+    fn update(&mut self, measurement: f32, delta_time: f32) -> f32;
+    fn has_reached_target(&self, measurement: f32) -> bool;
+    // forgets the history (integral, previous error, filters, previous
+    // output), the target and the parameters stay
+    fn reset(&mut self);
+
+    fn set_output_limits(&mut self, min_output: f32, max_output: f32);
+    fn set_target_limits(&mut self, min_target: f32, max_target: f32);
+    // how much the output can change in a second, zero is no limit
+    fn set_change_limit(&mut self, change_limit: f32);
+
+    // the pid tuners change the gains in place
+    fn as_pid_mut(&mut self) -> Option<&mut PIDController> {
+        None
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControllerParameters {
+    Pid(PIDParameters),
+    StateFeedback(StateFeedbackParameters),
+    Lqr(LqrParameters),
+    Fuzzy(FuzzyParameters),
+}
+
+impl ControllerParameters {
+    pub fn build(self) -> Box<dyn Controller> {
+        match self {
+            ControllerParameters::Pid(parameters) => Box::new(PIDController::from_parameters(parameters)),
+            ControllerParameters::StateFeedback(parameters) => Box::new(StateFeedbackController::new(parameters)),
+            ControllerParameters::Lqr(parameters) => Box::new(StateFeedbackController::from_lqr(parameters)),
+            ControllerParameters::Fuzzy(parameters) => Box::new(FuzzyController::new(parameters)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // height of a car whose speed follows the command with a lag
+    fn run_car(mut controller: Box<dyn Controller>) -> f32 {
+        controller.set_output_limits(-1., 1.);
+        controller.set_target(3.);
+        let mut height = 0.;
+        let mut speed = 0.;
+        for _ in 0..3000 {
+            let command = controller.update(height, 0.01);
+            speed += (command - speed) / 0.2 * 0.01;
+            height += speed * 0.01;
+        }
+        height
+    }
+
+    fn parse(yaml: &str) -> Box<dyn Controller> {
+        serde_yaml::from_str::<ControllerParameters>(yaml).unwrap().build()
+    }
+
+    #[test]
+    fn every_controller_reaches_target() {
+        let controllers = [
+            "
+            type: pid
+            kp: 1.
+            ki: 0.
+            kd: 0.
+            integral_limit: 0.
+            update_freq: 100.
+            tolerance: 0.01
+            ",
+            "
+            type: state_feedback
+            k_position: 1.5
+            k_speed: 0.3
+            update_freq: 100.
+            tolerance: 0.01
+            ",
+            "
+            type: lqr
+            time_constant: 0.2
+            q_position: 10.
+            q_speed: 1.
+            r: 1.
+            update_freq: 100.
+            tolerance: 0.01
+            ",
+            "
+            type: fuzzy
+            error_scale: 1.
+            rate_scale: 1.
+            output_scale: 1.
+            update_freq: 100.
+            tolerance: 0.01
+            ",
+        ];
+        for yaml in controllers {
+            let height = run_car(parse(yaml));
+            assert!((height - 3.).abs() < 0.02, "{} {}", yaml, height);
+        }
+    }
+
+    #[test]
+    fn only_pid_is_pid() {
+        let mut pid = parse("
+            type: pid
+            kp: 1.
+            ki: 0.
+            kd: 0.
+            integral_limit: 0.
+            update_freq: 100.
+            tolerance: 0.01
+        ");
+        assert!(pid.as_pid_mut().is_some());
+
+        let mut fuzzy = parse("
+            type: fuzzy
+            error_scale: 1.
+            rate_scale: 1.
+            output_scale: 1.
+            update_freq: 100.
+            tolerance: 0.01
+        ");
+        assert!(fuzzy.as_pid_mut().is_none());
+    }
+}
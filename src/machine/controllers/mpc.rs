@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Model predictive control of the car position. The car is linearised as a
+// triple integrator, the plan is a list of constant jerk steps:
+//   position' = speed, speed' = accel, accel' = jerk
+// The force balance of the car is linear in the acceleration,
+//   force = (car + load - counterweight) g + moving mass * accel
+// so the current limit of the motor is an acceleration limit that depends on
+// the load; the car gives it (Elevator::get_accel_limits) on every step.
+// Every step_time a QP is solved over the horizon:
+//   minimise  sum q_position (position - target)^2 + q_speed speed^2
+//                 + q_accel accel^2 + r_jerk jerk^2
+//                 + terminal weights on the speed and accel at the end
+//   subject to |jerk| <= max_jerk, accel_min <= accel <= accel_max,
+//              |speed| <= max_speed
+// and the first jerk of the plan is applied. The car follows the planned speed
+// with the speed loop of the motor and the planned acceleration as the feed
+// forward force, the measured height starts the next plan.
+
+use serde::Deserialize;
+
+use crate::machine::motion_profile::MotionState;
+use super::qp::QpSolver;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MpcParameters {
+    pub horizon: usize,  // number of steps predicted
+    pub step_time: f32,  // length of a step, the plan is solved again after every step
+    pub q_position: f32,
+    pub q_speed: f32,
+    pub q_accel: f32,
+    pub r_jerk: f32,
+    pub q_terminal_speed: f32,
+    pub q_terminal_accel: f32,
+    // the plan may use this part of the current limit, the rest is left to the
+    // speed loop for the corrections
+    pub current_margin: f32,
+    pub rho: f32, // first step size of the qp solver, it is adapted while solving
+    pub max_iterations: usize,
+    pub solver_tolerance: f32,
+    pub tolerance: f32,       // position
+    pub speed_tolerance: f32, // the car has to be stopped too
+}
+
+impl Default for MpcParameters {
+    fn default() -> Self {
+        Self {
+            horizon: 30,
+            step_time: 0.1,
+            q_position: 1.,
+            q_speed: 0.3,
+            q_accel: 0.01,
+            r_jerk: 0.01,
+            q_terminal_speed: 10.,
+            q_terminal_accel: 10.,
+            current_margin: 0.8,
+            rho: 1.,
+            max_iterations: 500,
+            solver_tolerance: 1e-3,
+            tolerance: 0.01,
+            speed_tolerance: 0.01,
+        }
+    }
+}
+
+pub struct MpcController {
+    pub parameters: MpcParameters,
+    max_speed: f32,
+    max_jerk: f32,
+    target: f32,
+    // effect of the jerk of step i on the state at the end of step k, [k][i]
+    gamma_position: Vec<Vec<f64>>,
+    gamma_speed: Vec<Vec<f64>>,
+    gamma_accel: Vec<Vec<f64>>,
+    row_scales: Vec<f64>, // the constraint rows are normalised for the solver
+    solver: QpSolver,
+    plan_start: MotionState, // state the plan was solved from
+    elapsed: f32,            // time since the plan was solved
+    solved: bool,
+    pub iterations: usize,   // iterations of the last solve
+}
+
+impl MpcController {
+    pub fn new(parameters: MpcParameters, max_speed: f32, max_jerk: f32) -> Self {
+        let n = parameters.horizon;
+        let dt = parameters.step_time as f64;
+
+        let mut gamma_position = vec![vec![0.; n]; n];
+        let mut gamma_speed = vec![vec![0.; n]; n];
+        let mut gamma_accel = vec![vec![0.; n]; n];
+        for k in 0..n {
+            for i in 0..=k {
+                // the jerk acts for one step, then the car coasts with what it left
+                let t = (k - i) as f64 * dt;
+                gamma_accel[k][i] = dt;
+                gamma_speed[k][i] = dt * dt / 2. + dt * t;
+                gamma_position[k][i] = dt * dt * dt / 6. + dt * dt / 2. * t + dt * t * t / 2.;
+            }
+        }
+
+        // P = sum gamma' Q gamma + R
+        let weight = |k: usize, stage: f32, terminal: f32| -> f64 {
+            (stage + if k == n - 1 { terminal } else { 0. }) as f64
+        };
+        let mut p = vec![vec![0.; n]; n];
+        for (i, p_row) in p.iter_mut().enumerate() {
+            for (j, value) in p_row.iter_mut().enumerate() {
+                for k in 0..n {
+                    *value += parameters.q_position as f64 * gamma_position[k][i] * gamma_position[k][j]
+                        + weight(k, parameters.q_speed, parameters.q_terminal_speed) * gamma_speed[k][i] * gamma_speed[k][j]
+                        + weight(k, parameters.q_accel, parameters.q_terminal_accel) * gamma_accel[k][i] * gamma_accel[k][j];
+                }
+            }
+            p_row[i] += parameters.r_jerk as f64;
+        }
+
+        // rows: jerk of every step, accel and speed at the end of every step
+        let mut a = Vec::with_capacity(3 * n);
+        for i in 0..n {
+            let mut row = vec![0.; n];
+            row[i] = 1.;
+            a.push(row);
+        }
+        a.extend(gamma_accel.iter().cloned());
+        a.extend(gamma_speed.iter().cloned());
+        let row_scales: Vec<f64> = a.iter()
+            .map(|row| 1. / row.iter().map(|v| v * v).sum::<f64>().sqrt())
+            .collect();
+        for (row, scale) in a.iter_mut().zip(&row_scales) {
+            row.iter_mut().for_each(|v| *v *= scale);
+        }
+
+        let solver = QpSolver::new(p, a, parameters.rho as f64);
+
+        Self {
+            parameters,
+            max_speed,
+            max_jerk,
+            target: 0.,
+            gamma_position,
+            gamma_speed,
+            gamma_accel,
+            row_scales,
+            solver,
+            plan_start: MotionState::default(),
+            elapsed: 0.,
+            solved: false,
+            iterations: 0,
+        }
+    }
+
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn has_reached_target(&self, position: f32, speed: f32) -> bool {
+        (self.target - position).abs() < self.parameters.tolerance
+            && speed.abs() < self.parameters.speed_tolerance
+    }
+
+    // forgets the plan, the next update solves from scratch
+    pub fn reset(&mut self) {
+        self.solved = false;
+        self.elapsed = 0.;
+        self.plan_start = MotionState::default();
+        self.solver.warm_start(vec![0.; self.parameters.horizon]);
+    }
+
+    // the jerks of the last plan
+    pub fn plan(&self) -> Vec<f32> {
+        self.solver.x.iter().map(|jerk| (*jerk as f32).clamp(-self.max_jerk, self.max_jerk)).collect()
+    }
+
+    // the states at the end of every step of the last plan
+    pub fn predict(&self) -> Vec<MotionState> {
+        let mut state = self.plan_start;
+        self.plan().iter()
+            .map(|jerk| {
+                state = state.after(*jerk, self.parameters.step_time);
+                state
+            })
+            .collect()
+    }
+
+    // state the plan commands now
+    pub fn command(&self) -> MotionState {
+        let jerk = self.plan().first().copied().unwrap_or(0.);
+        self.plan_start.after(jerk, self.elapsed)
+    }
+
+    // plans the trip from the given state with the acceleration limits the
+    // motor has with the current load
+    pub fn solve(&mut self, start: MotionState, accel_min: f32, accel_max: f32) {
+        let n = self.parameters.horizon;
+        let dt = self.parameters.step_time;
+
+        // where the car goes without any jerk
+        let mut free = Vec::with_capacity(n);
+        let mut state = start;
+        for _ in 0..n {
+            state = state.after(0., dt);
+            free.push(state);
+        }
+
+        let weight = |k: usize, stage: f32, terminal: f32| -> f64 {
+            (stage + if k == n - 1 { terminal } else { 0. }) as f64
+        };
+        let mut q = vec![0.; n];
+        for (i, q) in q.iter_mut().enumerate() {
+            for (k, free) in free.iter().enumerate() {
+                *q += self.parameters.q_position as f64 * self.gamma_position[k][i] * (free.position - self.target) as f64
+                    + weight(k, self.parameters.q_speed, self.parameters.q_terminal_speed) * self.gamma_speed[k][i] * free.speed as f64
+                    + weight(k, self.parameters.q_accel, self.parameters.q_terminal_accel) * self.gamma_accel[k][i] * free.accel as f64;
+            }
+        }
+
+        let mut lower = Vec::with_capacity(3 * n);
+        let mut upper = Vec::with_capacity(3 * n);
+        for _ in 0..n {
+            lower.push(-self.max_jerk as f64);
+            upper.push(self.max_jerk as f64);
+        }
+        for free in &free {
+            lower.push((accel_min - free.accel) as f64);
+            upper.push((accel_max - free.accel) as f64);
+        }
+        for free in &free {
+            lower.push((-self.max_speed - free.speed) as f64);
+            upper.push((self.max_speed - free.speed) as f64);
+        }
+        for ((lower, upper), scale) in lower.iter_mut().zip(upper.iter_mut()).zip(&self.row_scales) {
+            *lower *= scale;
+            *upper *= scale;
+        }
+
+        // the last plan moved one step forward is a good guess
+        let mut guess: Vec<f64> = self.solver.x.iter().skip(1).copied().collect();
+        guess.push(0.);
+        self.solver.warm_start(guess);
+        self.iterations = self.solver.solve(
+            &q,
+            &lower,
+            &upper,
+            self.parameters.max_iterations,
+            self.parameters.solver_tolerance as f64,
+        );
+
+        self.plan_start = start;
+        self.elapsed = 0.;
+        self.solved = true;
+    }
+
+    // solves a new plan every step_time from the measured position, returns
+    // the state the car should be in at the end of this time step
+    pub fn update(&mut self, measured: MotionState, accel_min: f32, accel_max: f32, delta_time: f32) -> MotionState {
+        if !self.solved {
+            self.plan_start = MotionState { accel: 0., ..measured };
+        }
+        if !self.solved || self.elapsed >= self.parameters.step_time * 0.999 {
+            // the speed and the acceleration go on from the last plan, the
+            // speed loop makes the car follow them. A plan that starts from a
+            // measured speed a little over the limit has no solution, the
+            // error of the speed loop is seen in the position anyway
+            let start = MotionState {
+                position: measured.position,
+                ..self.command()
+            };
+            self.solve(start, accel_min, accel_max);
+        }
+        self.elapsed += delta_time;
+        self.command()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_respects_limits() {
+        let mut mpc = MpcController::new(MpcParameters::default(), 1., 1.);
+        mpc.set_target(6.);
+        mpc.solve(MotionState::default(), -0.8, 0.6);
+        let predicted = mpc.predict();
+        for state in &predicted {
+            assert!(state.speed.abs() <= 1. + 0.02, "{:?}", state);
+            assert!(state.accel <= 0.6 + 0.02 && state.accel >= -0.8 - 0.02, "{:?}", state);
+        }
+        // it is going up as fast as it may
+        assert!(predicted[5].accel > 0.5);
+    }
+
+    #[test]
+    fn follows_its_plan_to_target() {
+        // a car that moves exactly as commanded
+        let mut mpc = MpcController::new(MpcParameters::default(), 1., 1.);
+        mpc.set_target(6.);
+        let mut state = MotionState::default();
+        for _ in 0..2000 {
+            state = mpc.update(state, -1., 1., 0.01);
+        }
+        assert!(mpc.has_reached_target(state.position, state.speed), "{:?}", state);
+    }
+}
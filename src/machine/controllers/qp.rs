@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Small dense quadratic program solver for the mpc,
+//   minimise 1/2 x' P x + q' x   subject to   lower <= A x <= upper
+// with ADMM (the OSQP iteration). P and A do not change between the steps of
+// the mpc, only q and the bounds do, so the linear system is factorised again
+// only when the step size rho is adapted to the residuals.
+// The problems are tiny (a few tens of variables), dense matrices and f64 are
+// fine here.
+
+pub struct QpSolver {
+    p: Vec<Vec<f64>>,
+    a: Vec<Vec<f64>>,
+    a_square: Vec<Vec<f64>>, // A'A
+    factor: Vec<Vec<f64>>,   // cholesky factor of P + sigma I + rho A'A
+    rho: f64,
+    sigma: f64,
+    alpha: f64, // over-relaxation
+    // the last solution, the next solve starts from here
+    pub x: Vec<f64>,
+    z: Vec<f64>,
+    y: Vec<f64>,
+}
+
+// lower triangular l with l l' = m, m has to be positive definite
+fn cholesky(m: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let n = m.len();
+    let mut l = vec![vec![0.; n]; n];
+    for i in 0..n {
+        for j in 0..=i {
+            let sum = m[i][j] - l[i][..j].iter().zip(&l[j][..j]).map(|(a, b)| a * b).sum::<f64>();
+            if i == j {
+                l[i][i] = sum.max(1e-12).sqrt();
+            } else {
+                l[i][j] = sum / l[j][j];
+            }
+        }
+    }
+    l
+}
+
+fn cholesky_solve(l: &[Vec<f64>], b: &[f64]) -> Vec<f64> {
+    let n = l.len();
+    let mut y = vec![0.; n];
+    for i in 0..n {
+        let mut sum = b[i];
+        for k in 0..i {
+            sum -= l[i][k] * y[k];
+        }
+        y[i] = sum / l[i][i];
+    }
+    let mut x = vec![0.; n];
+    for i in (0..n).rev() {
+        let mut sum = y[i];
+        for k in i + 1..n {
+            sum -= l[k][i] * x[k];
+        }
+        x[i] = sum / l[i][i];
+    }
+    x
+}
+
+fn multiply(m: &[Vec<f64>], x: &[f64]) -> Vec<f64> {
+    m.iter().map(|row| row.iter().zip(x).map(|(a, b)| a * b).sum()).collect()
+}
+
+fn multiply_transposed(m: &[Vec<f64>], y: &[f64]) -> Vec<f64> {
+    let mut result = vec![0.; m.first().map_or(0, |row| row.len())];
+    for (row, y) in m.iter().zip(y) {
+        for (r, a) in result.iter_mut().zip(row) {
+            *r += a * y;
+        }
+    }
+    result
+}
+
+fn max_abs(values: impl Iterator<Item = f64>) -> f64 {
+    values.fold(0., |max, value| max.max(value.abs()))
+}
+
+impl QpSolver {
+    pub fn new(p: Vec<Vec<f64>>, a: Vec<Vec<f64>>, rho: f64) -> Self {
+        let n = p.len();
+        let m = a.len();
+        let mut a_square = vec![vec![0.; n]; n];
+        for (i, a_square_row) in a_square.iter_mut().enumerate() {
+            for (j, value) in a_square_row.iter_mut().enumerate() {
+                *value = a.iter().map(|row| row[i] * row[j]).sum();
+            }
+        }
+
+        let mut solver = Self {
+            p,
+            a,
+            a_square,
+            factor: Vec::new(),
+            rho,
+            sigma: 1e-6,
+            alpha: 1.6,
+            x: vec![0.; n],
+            z: vec![0.; m],
+            y: vec![0.; m],
+        };
+        solver.factorise();
+        solver
+    }
+
+    fn factorise(&mut self) {
+        let mut kkt = self.p.clone();
+        for (i, kkt_row) in kkt.iter_mut().enumerate() {
+            kkt_row[i] += self.sigma;
+            for (value, a_square) in kkt_row.iter_mut().zip(&self.a_square[i]) {
+                *value += self.rho * a_square;
+            }
+        }
+        self.factor = cholesky(&kkt);
+    }
+
+    // starts the next solve from the given point, the duals are kept
+    pub fn warm_start(&mut self, x: Vec<f64>) {
+        self.z = multiply(&self.a, &x);
+        self.x = x;
+    }
+
+    // returns the number of iterations, stops when both residuals are below
+    // the tolerance (absolute and relative to the size of the terms)
+    pub fn solve(&mut self, q: &[f64], lower: &[f64], upper: &[f64], max_iterations: usize, tolerance: f64) -> usize {
+        for iteration in 1..=max_iterations {
+            // x~ = (P + sigma I + rho A'A)^-1 (sigma x - q + A'(rho z - y))
+            let dual_term: Vec<f64> = self.z.iter().zip(&self.y).map(|(z, y)| self.rho * z - y).collect();
+            let a_dual = multiply_transposed(&self.a, &dual_term);
+            let rhs: Vec<f64> = (0..self.x.len())
+                .map(|i| self.sigma * self.x[i] - q[i] + a_dual[i])
+                .collect();
+            let x_tilde = cholesky_solve(&self.factor, &rhs);
+            let z_tilde = multiply(&self.a, &x_tilde);
+
+            for (x, x_tilde) in self.x.iter_mut().zip(&x_tilde) {
+                *x = self.alpha * x_tilde + (1. - self.alpha) * *x;
+            }
+            for i in 0..self.z.len() {
+                let relaxed = self.alpha * z_tilde[i] + (1. - self.alpha) * self.z[i];
+                let z = (relaxed + self.y[i] / self.rho).clamp(lower[i], upper[i].max(lower[i]));
+                self.y[i] += self.rho * (relaxed - z);
+                self.z[i] = z;
+            }
+
+            if iteration % 10 == 0 {
+                let ax = multiply(&self.a, &self.x);
+                let primal = max_abs(ax.iter().zip(&self.z).map(|(ax, z)| ax - z));
+                let px = multiply(&self.p, &self.x);
+                let a_y = multiply_transposed(&self.a, &self.y);
+                let dual = max_abs((0..self.x.len()).map(|i| px[i] + q[i] + a_y[i]));
+                let primal_scale = max_abs(ax.iter().copied()).max(max_abs(self.z.iter().copied()));
+                let dual_scale = max_abs(px.iter().copied())
+                    .max(max_abs(a_y.iter().copied()))
+                    .max(max_abs(q.iter().copied()));
+                if primal < tolerance * (1. + primal_scale) && dual < tolerance * (1. + dual_scale) {
+                    return iteration;
+                }
+
+                // rho balances the two residuals, the system is factorised
+                // again only for a large change
+                let ratio = ((primal / primal_scale.max(1e-12)) / (dual / dual_scale.max(1e-12)).max(1e-12)).sqrt();
+                let rho = (self.rho * ratio).clamp(1e-6, 1e6);
+                if rho > self.rho * 5. || rho < self.rho / 5. {
+                    self.rho = rho;
+                    self.factorise();
+                }
+            }
+        }
+        max_iterations
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cholesky_solves() {
+        let m = vec![vec![4., 2., 0.], vec![2., 5., 1.], vec![0., 1., 3.]];
+        let x = cholesky_solve(&cholesky(&m), &[2., 3., 4.]);
+        let b = multiply(&m, &x);
+        assert!((b[0] - 2.).abs() < 1e-9 && (b[1] - 3.).abs() < 1e-9 && (b[2] - 4.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn box_constrained_minimum() {
+        // (x0 - 2)^2 + (x1 + 1)^2 with x0 <= 1 and x0 + x1 >= 0.5
+        let p = vec![vec![2., 0.], vec![0., 2.]];
+        let q = [-4., 2.];
+        let a = vec![vec![1., 0.], vec![1., 1.]];
+        let mut solver = QpSolver::new(p, a, 1.);
+        solver.solve(&q, &[f64::MIN, 0.5], &[1., f64::MAX], 1000, 1e-8);
+        // x0 on its bound, x1 as close to -1 as x0 + x1 >= 0.5 allows
+        assert!((solver.x[0] - 1.).abs() < 1e-4);
+        assert!((solver.x[1] + 0.5).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// State feedback on the error and the rate of the error,
+//   u = k_position e + k_speed de/dt + k_integral integral(e)
+// The rate is the difference of the errors, so a moving target (the motion
+// profile) is not fought against. The gains are given directly or found with
+// LQR on the model of the car seen by the height loop: the speed of the car
+// follows the command with a first order lag,
+//   dy/dt = v,  dv/dt = (gain u - v) / time_constant
+// The discrete Riccati equation is iterated at the update frequency.
+
+use serde::{Deserialize, Serialize};
+
+use super::common::ControllerCommon;
+use super::Controller;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StateFeedbackParameters {
+    pub k_position: f32,
+    pub k_speed: f32,
+    #[serde(default)]
+    pub k_integral: f32,
+    // low-pass filter on the error rate, zero is no filter
+    #[serde(default)]
+    pub rate_filter_time: f32,
+    pub update_freq: f32,
+    pub tolerance: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LqrParameters {
+    pub time_constant: f32,
+    #[serde(default = "default_plant_gain")]
+    pub plant_gain: f32,
+    pub q_position: f32,
+    pub q_speed: f32,
+    pub r: f32,
+    #[serde(default)]
+    pub rate_filter_time: f32,
+    pub update_freq: f32,
+    pub tolerance: f32,
+}
+
+fn default_plant_gain() -> f32 { 1. }
+
+impl LqrParameters {
+    // gains of the infinite horizon discrete LQR, [k_position, k_speed]
+    pub fn gains(&self) -> (f32, f32) {
+        let dt = 1. / self.update_freq;
+        // state is [e, de/dt] with e = target - y, so the model is mirrored
+        let a = [[1., dt], [0., 1. - dt / self.time_constant]];
+        let b = [0., -self.plant_gain * dt / self.time_constant];
+        let q = [self.q_position, self.q_speed];
+
+        let mut p = [[q[0], 0.], [0., q[1]]];
+        let mut k = [0., 0.];
+        for _ in 0..100_000 {
+            // k = (r + b' p b)^-1 b' p a
+            let pb = [p[0][0] * b[0] + p[0][1] * b[1], p[1][0] * b[0] + p[1][1] * b[1]];
+            let denominator = self.r + b[0] * pb[0] + b[1] * pb[1];
+            let bpa = [
+                pb[0] * a[0][0] + pb[1] * a[1][0],
+                pb[0] * a[0][1] + pb[1] * a[1][1],
+            ];
+            k = [bpa[0] / denominator, bpa[1] / denominator];
+
+            // p = q + a' p (a - b k)
+            let closed = [
+                [a[0][0] - b[0] * k[0], a[0][1] - b[0] * k[1]],
+                [a[1][0] - b[1] * k[0], a[1][1] - b[1] * k[1]],
+            ];
+            let mut next = [[0.; 2]; 2];
+            for i in 0..2 {
+                for j in 0..2 {
+                    let mut sum = 0.;
+                    for m in 0..2 {
+                        for n in 0..2 {
+                            sum += a[m][i] * p[m][n] * closed[n][j];
+                        }
+                    }
+                    next[i][j] = sum + if i == j { q[i] } else { 0. };
+                }
+            }
+
+            let change = (next[0][0] - p[0][0]).abs() + (next[1][1] - p[1][1]).abs();
+            p = next;
+            if change < 1e-6 * (p[0][0].abs() + p[1][1].abs()) {
+                break;
+            }
+        }
+
+        // u = -k x, the output of the controller is the command itself
+        (-k[0], -k[1])
+    }
+}
+
+pub struct StateFeedbackController {
+    common: ControllerCommon,
+    k_position: f32,
+    k_speed: f32,
+    k_integral: f32,
+    rate_filter_time: f32,
+    prev_error: Option<f32>,
+    rate: f32,
+    integral: f32,
+}
+
+impl StateFeedbackController {
+    pub fn new(parameters: StateFeedbackParameters) -> Self {
+        Self {
+            common: ControllerCommon::new(parameters.update_freq, parameters.tolerance),
+            k_position: parameters.k_position,
+            k_speed: parameters.k_speed,
+            k_integral: parameters.k_integral,
+            rate_filter_time: parameters.rate_filter_time,
+            prev_error: None,
+            rate: 0.,
+            integral: 0.,
+        }
+    }
+
+    pub fn from_lqr(parameters: LqrParameters) -> Self {
+        let (k_position, k_speed) = parameters.gains();
+        Self::new(StateFeedbackParameters {
+            k_position,
+            k_speed,
+            k_integral: 0.,
+            rate_filter_time: parameters.rate_filter_time,
+            update_freq: parameters.update_freq,
+            tolerance: parameters.tolerance,
+        })
+    }
+
+    pub fn gains(&self) -> (f32, f32, f32) {
+        (self.k_position, self.k_speed, self.k_integral)
+    }
+}
+
+impl Controller for StateFeedbackController {
+    fn set_target(&mut self, target: f32) -> bool {
+        self.common.set_target(target)
+    }
+
+    fn target(&self) -> f32 {
+        self.common.target
+    }
+
+    fn update(&mut self, measurement: f32, delta_time: f32) -> f32 {
+        let Some(delta_time) = self.common.tick(delta_time) else {
+            return self.common.prev_output;
+        };
+
+        let error = self.common.target - measurement;
+        let rate = match self.prev_error {
+            Some(prev_error) => (error - prev_error) / delta_time,
+            None => 0.,
+        };
+        self.prev_error = Some(error);
+        if self.rate_filter_time > 0. {
+            self.rate += (rate - self.rate) * delta_time / (self.rate_filter_time + delta_time);
+        } else {
+            self.rate = rate;
+        }
+        self.integral += error * delta_time;
+
+        let output = self.k_position * error + self.k_speed * self.rate + self.k_integral * self.integral;
+        let limited = self.common.limit_output(output, delta_time);
+        // the integral does not wind up while the output is limited
+        if limited != output {
+            self.integral -= error * delta_time;
+        }
+        limited
+    }
+
+    fn has_reached_target(&self, measurement: f32) -> bool {
+        self.common.has_reached_target(measurement)
+    }
+
+    fn reset(&mut self) {
+        self.common.reset();
+        self.prev_error = None;
+        self.rate = 0.;
+        self.integral = 0.;
+    }
+
+    fn set_output_limits(&mut self, min_output: f32, max_output: f32) {
+        self.common.set_output_limits(min_output, max_output);
+    }
+
+    fn set_target_limits(&mut self, min_target: f32, max_target: f32) {
+        self.common.set_target_limits(min_target, max_target);
+    }
+
+    fn set_change_limit(&mut self, change_limit: f32) {
+        self.common.set_change_limit(change_limit);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lqr(q_position: f32) -> LqrParameters {
+        LqrParameters {
+            time_constant: 0.2,
+            plant_gain: 1.,
+            q_position,
+            q_speed: 1.,
+            r: 1.,
+            rate_filter_time: 0.,
+            update_freq: 100.,
+            tolerance: 0.01,
+        }
+    }
+
+    #[test]
+    fn lqr_gains() {
+        let (k_position, k_speed) = lqr(10.).gains();
+        // pushes towards the target and damps the rate
+        assert!(k_position > 0.);
+        assert!(k_speed > 0.);
+        // a heavier position weight asks for a stiffer controller
+        assert!(lqr(100.).gains().0 > k_position);
+    }
+
+    #[test]
+    fn reset_forgets_history() {
+        let mut controller = StateFeedbackController::new(StateFeedbackParameters {
+            k_position: 1.,
+            k_speed: 0.,
+            k_integral: 1.,
+            rate_filter_time: 0.,
+            update_freq: 100.,
+            tolerance: 0.01,
+        });
+        controller.set_target(1.);
+        for _ in 0..100 {
+            controller.update(0., 0.01);
+        }
+        controller.reset();
+        assert!(controller.target() == 1.);
+        // only the proportional part is left
+        assert!((controller.update(0., 0.01) - 1.01).abs() < 1e-4);
+    }
+}
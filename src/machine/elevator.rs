@@ -1,22 +1,27 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2024 Tuna Gül
 
-use super::pid_controller::PIDController;
+use super::controllers::Controller;
+use super::controllers::mpc::{MpcController, MpcParameters};
+use super::elevator_parameters::ElevatorParameters;
 use super::motor::ElevatorMotor;
-use std::time::Instant;
+use super::motion_profile::{MotionLimits, MotionProfile, MotionState};
 
 pub struct Elevator {
     pub floors: Vec<f32>, // floor heights, taken from elevator controller
     pub is_idle: bool,
     // pid-related
     pub current_height: f32,
-    // pub current_speed: f32,
+    pub current_speed: f32,
     pub current_accel: f32,
-    pub height_pid: PIDController,
-    // pub speed_pid: PIDController,
+    pub motor_force: f32, // force the motor puts on the rope, positive lifts the car
+    pub profile: MotionProfile, // position and speed references of the trip
+    pub height_controller: Box<dyn Controller>, // position loop, the speed and current loops are in the motor
+    pub mpc: Option<MpcController>, // replaces the profile and the position loop when set
     // weigth and forces 
     pub max_speed: f32,
     pub max_accel: f32,
+    pub max_jerk: f32,
     pub elevator_mass: f32,
     pub elevator_counter_mass: f32,
     pub max_load: f32,
@@ -24,8 +29,6 @@ pub struct Elevator {
     pub motor: ElevatorMotor,
     // simulation-related
     pub gravity: f32,
-    pub last_update: Instant,
-    pub time_multiplier: f32,
 }
 
 
@@ -36,71 +39,98 @@ impl Elevator {
         elevator_counter_mass: f32,
         max_speed: f32,
         max_accel: f32,
+        max_jerk: f32,
         max_load: f32,
-        time_multiplier: f32,
     ) -> Self {
-        let height_pid = PIDController::new(
-            1., 
-            0., 
-            0., 
-            0.,
-            10., 
-            1.,
-            false,
-            0.,
-            0.,
-            false,
-            0.,
-            0.,
-            1.,
-        );
-        // let speed_pid = PIDController::new(1., 0., 0., 30., 0.);
+        let parameters = ElevatorParameters::from_file(
+            "param/elevator_parameters.yaml",
+        ).unwrap();
+
+        // the height controller only corrects the car around the reference of
+        // the motion profile, its correction is limited like the car itself
+        let mut height_controller = parameters.height_controller.build();
+        height_controller.set_output_limits(-max_speed, max_speed);
+        height_controller.set_change_limit(max_accel);
+
+        let mpc = parameters.mpc.map(|parameters| MpcController::new(parameters, max_speed, max_jerk));
+
+        let profile = MotionProfile::new(MotionLimits { max_speed, max_accel, max_jerk }, 0.);
         let motor = ElevatorMotor::from_file(
-            "parameters/motor_parameters.yaml", 
+            "param/motor_parameters.yaml", 
         ).unwrap();
 
         Self {
             floors,
             is_idle: true,
             current_height: 0.0,
-            // current_speed: 0.0,
+            current_speed: 0.0,
             current_accel: 0.0,
-            height_pid,
-            // speed_pid,
+            motor_force: 0.0,
+            profile,
+            height_controller,
+            mpc,
             max_speed,
             max_accel,
+            max_jerk,
             elevator_mass,
             elevator_counter_mass,
             max_load,
             current_load: 0.0,
             motor,
             gravity: 9.81,
-            last_update: Instant::now(),
-            time_multiplier,
         }
     }
 
-    fn get_used_energy(&self) -> f32{
+    // the car of ElevatorSystem, the counterweight is the car plus about half
+    // of the rated load
+    pub fn standard(floors: Vec<f32>) -> Self {
+        Self::new(floors, 500., 950., 1., 1., 1., 1000.)
+    }
+
+    // taken from the supply by the motor so far, kW s
+    pub fn get_used_energy(&self) -> f32 {
         self.motor.get_total_energy_used()
     }
 
-    fn get_total_mass(&self) -> f32 {
+    // given back to the supply by the motor so far, kW s
+    pub fn get_recovered_energy(&self) -> f32 {
+        self.motor.get_total_energy_recovered()
+    }
+
+    // everything the rope moves, car, load and the counterweight
+    pub fn get_total_mass(&self) -> f32 {
         self.elevator_mass + self.current_load + self.elevator_counter_mass
     }
 
-    fn get_delta_time(&mut self) -> f32 {
-        // calculate delta time
-        let now = Instant::now();
-        let mut delta_time = now.duration_since(self.last_update).as_secs_f32();
-        self.last_update = now;
-        delta_time *= self.time_multiplier;
-        
-        delta_time
+    // gravitational pull on the car side minus the counterweight side
+    pub fn get_imbalance_force(&self) -> f32 {
+        (self.elevator_mass + self.current_load - self.elevator_counter_mass) * self.gravity
+    }
+
+    // accelerations the motor can give the car with the given part of its
+    // current limit, the heavier side of the rope decides how they are shared
+    pub fn get_accel_limits(&self, current_fraction: f32) -> (f32, f32) {
+        let moving_mass = self.get_total_mass() + self.motor.get_reflected_mass();
+        let force_limit = self.motor.get_force_limit() * current_fraction;
+        let imbalance = self.get_imbalance_force();
+        let min_accel = ((-force_limit - imbalance) / moving_mass).max(-self.max_accel);
+        let max_accel = ((force_limit - imbalance) / moving_mass).min(self.max_accel);
+        (min_accel, max_accel)
+    }
+
+    // the car is controlled by the mpc from now on
+    pub fn set_mpc(&mut self, parameters: MpcParameters) {
+        let mut mpc = MpcController::new(parameters, self.max_speed, self.max_jerk);
+        mpc.set_target(self.profile.target());
+        self.mpc = Some(mpc);
     }
 
     fn calculate_target_speed(&mut self, delta_time: f32) -> f32 {
-        // calculate target speed
-        let target_speed = self.height_pid.update(self.current_height, delta_time);
+        // the reference moves on the s-curve, the controller corrects what the
+        // car could not follow
+        let reference = self.profile.reference();
+        self.height_controller.set_target(reference.position);
+        let target_speed = reference.speed + self.height_controller.update(self.current_height, delta_time);
 
         // limits are applied in the motor
         // if target_speed > self.max_speed {
@@ -117,8 +147,23 @@ impl Elevator {
         let e = self.elevator_mass + self.current_load;
 
         // required force by the elevator motor
-        let req_force = (e-self.elevator_counter_mass)*self.gravity - m*target_accel;
-        req_force         
+        (e-self.elevator_counter_mass)*self.gravity + (m + self.motor.get_reflected_mass())*target_accel
+    }
+
+    // Newton on the whole rope: motor force against the weight difference of
+    // the car side and the counterweight side. A heavy car accelerates slower
+    // and a car heavier than the motor can hold stalls or falls back
+    fn update_dynamics(&mut self, delta_time: f32) {
+        self.motor_force = self.motor.get_shaft_force();
+
+        // a dynamic motor has a rotor that has to be accelerated with the car
+        let moving_mass = self.get_total_mass() + self.motor.get_reflected_mass();
+        self.current_accel = (self.motor_force - self.get_imbalance_force()) / moving_mass;
+        self.current_speed += self.current_accel * delta_time;
+        self.current_height += self.current_speed * delta_time;
+
+        // the rope ties the motor to the car
+        self.motor.sync_linear_speed(self.current_speed);
     }
 
     pub fn load(&mut self, weight: f32) {
@@ -130,52 +175,283 @@ impl Elevator {
     }
 
     pub fn direction(&self) -> bool {
-        self.height_pid.target > self.current_height
+        self.profile.target() > self.current_height
     }
 
     pub fn distance_to_floor(&self, floor_idx: usize) -> f32 {
         self.floors[floor_idx] - self.current_height
     }
 
+    // the closest height the car can stop at, its own height when it stands
+    pub fn stopping_height(&self) -> f32 {
+        self.profile.stopping_position(MotionState {
+            position: self.current_height,
+            speed: self.current_speed,
+            accel: self.current_accel,
+        })
+    }
+
     pub fn is_idle(&self) -> bool {
         self.is_idle
     }
 
     pub fn set_target(&mut self, floor_idx: usize) {
-        self.height_pid.set_target(self.floors[floor_idx]);
+        // a parked car starts the new trip from where it really is
+        if self.profile.is_finished() {
+            self.profile.reset(self.current_height);
+        }
+        self.profile.set_target(self.floors[floor_idx]);
+        if let Some(mpc) = &mut self.mpc {
+            mpc.set_target(self.floors[floor_idx]);
+        }
         self.is_idle = false;
     }
 
     pub fn get_current_speed(&self) -> f32 {
-        self.motor.get_current_speed()
+        self.current_speed
+    }
+
+    // the speed loop is opened, the given current replaces the output of the
+    // motor speed pid. The load is still carried by the feed forward current
+    pub fn update_with_current(&mut self, current: f32, delta_time: f32) {
+        self.motor.update_with_current(current, delta_time);
+        self.update_dynamics(delta_time);
+        self.motor.set_feed_forward_force(self.calculate_motor_force(0.));
+    }
+
+    // the position loop is opened, the given speed replaces the output of the height pid
+    pub fn update_with_speed(&mut self, target_speed: f32, delta_time: f32) {
+        self.motor.update(delta_time);
+        self.update_dynamics(delta_time);
+        self.motor.set_target_linear_speed(target_speed);
+        self.motor.set_feed_forward_force(self.calculate_motor_force(0.));
     }
 
-    pub fn update(&mut self) {
+    pub fn update(&mut self, delta_time: f32) {
         // Delta time ve geçmiş döngüyle hesaplama yapan işler fonksiyonun başında
         // yeni hesaplamalar aşağıda
-        
-        // geçen zamanı al
-        let delta_time = self.get_delta_time();
-
-        // geçen zamana bağlı yüksekliği güncelle
-        self.current_height += self.motor.get_current_speed() * delta_time;
+        // delta time simülasyon saatinden geliyor (ElevatorSystem::clock)
 
         // geçen zamana bağlı motor değerlerini güncelle (harcanılan enerji gibi)
         self.motor.update(delta_time);
 
+        // geçen zamana bağlı hızı ve yüksekliği kuvvetlerden güncelle
+        self.update_dynamics(delta_time);
+
+        // referans yörüngede ilerle
+        self.profile.update(delta_time);
+
+        // mpc varsa hız ve ivme referansı onun planından geliyor
+        if self.mpc.is_some() {
+            self.update_mpc(delta_time);
+            return;
+        }
+
         // yeni hesaplamalar
         // calculate target speed
         let target_speed: f32 = self.calculate_target_speed(delta_time);
 
-        // get required force to reach the target speed
-        // let target_accel = (target_speed - self.current_accel) / delta_time;
-        // let required_force = self.calculate_motor_force(target_accel)
-        // do stuff with required force idk
+        // motora yeni hedefi ver, yükü taşımak için gereken kuvvet doğrudan veriliyor
+        self.motor.set_target_linear_speed(target_speed);
+        let target_accel = self.profile.reference().accel.clamp(-self.max_accel, self.max_accel);
+        self.motor.set_feed_forward_force(self.calculate_motor_force(target_accel));
+        self.is_idle = self.profile.is_finished() && self.height_controller.has_reached_target(self.current_height);
+    }
+
+    fn update_mpc(&mut self, delta_time: f32) {
+        let measured = MotionState {
+            position: self.current_height,
+            speed: self.current_speed,
+            accel: self.current_accel,
+        };
+        let Some(mpc) = &self.mpc else { return };
+        let (min_accel, max_accel) = self.get_accel_limits(mpc.parameters.current_margin);
+
+        let Some(mpc) = &mut self.mpc else { return };
+        let command = mpc.update(measured, min_accel, max_accel, delta_time);
+        let is_idle = mpc.has_reached_target(self.current_height, self.current_speed);
+
+        self.motor.set_target_linear_speed(command.speed);
+        self.motor.set_feed_forward_force(self.calculate_motor_force(command.accel));
+        self.is_idle = is_idle;
+    }
+
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::motor::{MotorModel, MotorParameters};
+    use crate::machine::controllers::ControllerParameters;
+
+    fn loaded_car(load: f32, max_accel: f32) -> Elevator {
+        let mut elevator = Elevator::new(vec![0.0, 3.0, 6.0, 9.0], 500., 950., 1., max_accel, 1., 1000.);
+        elevator.load(load);
+        elevator
+    }
+
+    fn peak_accel(load: f32) -> f32 {
+        // the acceleration limit is high so the motor gives everything it has
+        let mut elevator = loaded_car(load, 100.);
+        elevator.set_target(3);
+
+        let mut peak: f32 = 0.;
+        for _ in 0..200 {
+            elevator.update(0.01);
+            peak = peak.max(elevator.current_accel);
+        }
+        peak
+    }
+
+    #[test]
+    fn counterweight_balance() {
+        let elevator = loaded_car(450., 1.);
+        assert!(elevator.get_imbalance_force().abs() < 1e-3);
+    }
+
+    #[test]
+    fn heavy_car_accelerates_slower() {
+        assert!(peak_accel(1000.) < peak_accel(0.));
+    }
+
+    #[test]
+    fn accel_limits_follow_load() {
+        let (_, up_empty) = loaded_car(0., 100.).get_accel_limits(1.);
+        let (down_heavy, up_heavy) = loaded_car(1000., 100.).get_accel_limits(1.);
+        // a heavy car goes up slower and comes down faster
+        assert!(up_heavy < up_empty);
+        assert!(-down_heavy > up_heavy);
+        // never more than the car is allowed
+        let (down, up) = loaded_car(450., 1.).get_accel_limits(1.);
+        assert!(down == -1. && up == 1.);
+    }
+
+    #[test]
+    fn too_heavy_car_falls_back() {
+        let mut elevator = loaded_car(5000., 1.);
+        elevator.set_target(2);
+        for _ in 0..300 {
+            elevator.update(0.01);
+        }
+        assert!(elevator.current_height < 0.);
+        assert!(elevator.motor_force <= elevator.motor.max_force);
+    }
 
-        // motora yeni hedefi ver
-        self.motor.set_target_speed(target_speed);
-        self.is_idle = self.height_pid.has_reached_target(self.current_height);
+    #[test]
+    fn follows_motion_profile() {
+        let mut elevator = loaded_car(800., 1.);
+        // let the motor take the load first, there is no brake holding the car
+        for _ in 0..100 {
+            elevator.update(0.01);
+        }
+        elevator.set_target(3);
+        let mut peak_accel: f32 = 0.;
+        for _ in 0..1500 {
+            elevator.update(0.01);
+            let reference = elevator.profile.reference();
+            assert!((elevator.current_height - reference.position).abs() < 0.05);
+            peak_accel = peak_accel.max(elevator.current_accel.abs());
+        }
+        // no step in the commanded position, the car stays in its limits
+        assert!(peak_accel < elevator.max_accel * 1.2);
+        assert!(elevator.is_idle());
     }
 
+    fn check_reaches_floor(model: MotorModel) {
+        let mut elevator = loaded_car(800., 1.);
+        let mut parameters = MotorParameters::from_file("param/motor_parameters.yaml").unwrap();
+        parameters.model = model;
+        elevator.motor = ElevatorMotor::new(parameters).unwrap();
 
+        elevator.set_target(2);
+        for _ in 0..2000 {
+            elevator.update(0.01);
+        }
+        assert!(elevator.is_idle());
+        assert!((elevator.current_height - 6.).abs() < 0.01);
+    }
+
+    #[test]
+    fn reaches_floor() {
+        check_reaches_floor(MotorModel::Static);
+    }
+
+    #[test]
+    fn reaches_floor_dynamic_motor() {
+        check_reaches_floor(MotorModel::Dynamic);
+    }
+
+    #[test]
+    fn other_height_controllers_reach_floor() {
+        let controllers = [
+            "
+            type: lqr
+            time_constant: 0.1
+            q_position: 10.
+            q_speed: 0.1
+            r: 1.
+            update_freq: 10.
+            tolerance: 0.01
+            ",
+            "
+            type: fuzzy
+            error_scale: 0.5
+            rate_scale: 1.
+            output_scale: 0.5
+            update_freq: 10.
+            tolerance: 0.01
+            ",
+        ];
+        for yaml in controllers {
+            let mut elevator = loaded_car(800., 1.);
+            let parameters: ControllerParameters = serde_yaml::from_str(yaml).unwrap();
+            elevator.height_controller = parameters.build();
+            elevator.height_controller.set_output_limits(-1., 1.);
+
+            elevator.set_target(2);
+            for _ in 0..2000 {
+                elevator.update(0.01);
+            }
+            assert!(elevator.is_idle(), "{}", yaml);
+            assert!((elevator.current_height - 6.).abs() < 0.01, "{}", yaml);
+        }
+    }
+
+    // full car from one floor to the other, gives back (recovered, used) energy
+    fn heavy_car_travel(model: MotorModel, from: usize, to: usize) -> (f32, f32) {
+        let mut elevator = loaded_car(1000., 1.);
+        let mut parameters = MotorParameters::from_file("param/motor_parameters.yaml").unwrap();
+        parameters.model = model;
+        elevator.motor = ElevatorMotor::new(parameters).unwrap();
+        elevator.current_height = elevator.floors[from];
+
+        elevator.set_target(to);
+        for _ in 0..2000 {
+            elevator.update(0.01);
+        }
+        assert!((elevator.current_height - elevator.floors[to]).abs() < 0.01);
+        (elevator.motor.get_total_energy_recovered(), elevator.motor.get_total_energy_used())
+    }
+
+    fn check_heavy_car_regenerates(model: MotorModel) {
+        // the full car is heavier than the counterweight, going down the motor
+        // only holds it back and works as a generator
+        let (recovered_down, used_down) = heavy_car_travel(model, 3, 0);
+        let (recovered_up, used_up) = heavy_car_travel(model, 0, 3);
+        assert!(recovered_down > 0.);
+        assert!(recovered_down > recovered_up * 10.);
+        assert!(used_down - recovered_down < used_up - recovered_up);
+    }
+
+    #[test]
+    fn heavy_car_regenerates() {
+        check_heavy_car_regenerates(MotorModel::Static);
+    }
+
+    #[test]
+    fn heavy_car_regenerates_dynamic_motor() {
+        check_heavy_car_regenerates(MotorModel::Dynamic);
+    }
 }
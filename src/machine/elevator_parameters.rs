@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// File for parsing the control parameters of the elevator car from a yaml file.
+// The car is controlled by a cascade, every loop runs at the update_freq of
+// its own parameters:
+//   position loop (here)                  height -> speed correction
+//   speed loop (motor speed_controller or pid_parameters) rpm -> current
+//   current loop (motor current_pid_parameters, dynamic model) current -> voltage
+// The motion profile feeds the speed and acceleration references forward.
+// With the mpc the position loop and the motion profile are replaced by the
+// plan of the mpc, the speed and current loops stay the same.
+
+use serde::Deserialize;
+use std::error::Error;
+use crate::machine::controllers::ControllerParameters;
+use crate::machine::controllers::mpc::MpcParameters;
+
+#[derive(Debug, Deserialize)]
+pub struct ElevatorParameters {
+    pub height_controller: ControllerParameters,
+    // the mpc replaces the motion profile and the height controller when given
+    #[serde(default)]
+    pub mpc: Option<MpcParameters>,
+}
+
+impl ElevatorParameters {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read() {
+        let parameters = ElevatorParameters::from_file("param/elevator_parameters.yaml").unwrap();
+        assert!(matches!(parameters.height_controller, ControllerParameters::Pid(_)));
+        assert!(parameters.mpc.is_none());
+
+        // kp 1, no integral and no derivative, tolerance 1 cm
+        let mut controller = parameters.height_controller.build();
+        controller.set_target(1.);
+        assert!((controller.update(0., 0.1) - 1.).abs() < 1e-6);
+        assert!(controller.has_reached_target(0.995));
+        assert!(!controller.has_reached_target(0.98));
+    }
+}
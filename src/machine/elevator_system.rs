@@ -2,41 +2,688 @@
 // Copyright (C) 2024 Tuna Gül
 
 use super::elevator::Elevator;
+use super::energy::{EnergyIndicators, EnergyLedger, EnergyParameters, EnergyReport};
+use super::sim_clock::SimClock;
+use crate::control_algorithms::{CarCall, CarState, DestinationCall, Direction, DispatchState, Dispatcher, HallCall};
+use crate::control_algorithms::basic_algorithm::CollectiveControl;
+use crate::population::population::{Passenger, PassengerState};
+use crate::population::generator::PassengerGenerator;
+use crate::population::trace::TraceReplay;
+use std::error::Error;
 
 extern crate rand;
 // use rand::Rng;
 
+// what a car does for the passengers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CarStatus {
+    Idle,
+    Moving { floor: usize },
+    DoorsOpen { floor: usize, until: f64 },
+}
+
+// what the dispatcher sees, a macro so that the borrow of the fields does not
+// keep the dispatcher from being borrowed
+macro_rules! dispatch_state {
+    ($system:expr) => {
+        DispatchState {
+            now: $system.clock.now(),
+            floors: &$system.floors,
+            dwell_time: $system.dwell_time,
+            cars: (0..$system.elevators.len())
+                .map(|car| CarState {
+                    elevator: &$system.elevators[car],
+                    status: $system.car_status[car],
+                    direction: $system.directions[car],
+                    passengers: $system.riding[car].len(),
+                    riding: &$system.riding[car],
+                })
+                .collect(),
+            hall_calls: &$system.hall_calls,
+            destination_calls: &$system.destination_calls,
+            car_calls: &$system.car_calls,
+            passengers: &$system.passengers,
+            waiting: &$system.waiting,
+        }
+    };
+}
+
 pub struct ElevatorSystem {
     floors: Vec<f32>,
     pub elevators: Vec<Elevator>,
-    pub total_energy_consumed: f32,
+    pub clock: SimClock,
+    pub total_energy_consumed: f32, // net of every car, kW s
+    pub energy_parameters: EnergyParameters,
+    pub energy: Vec<EnergyLedger>, // of every car
+    // passengers
+    pub passengers: Vec<Passenger>, // everyone spawned so far, in spawn order
+    pub generator: Option<PassengerGenerator>,
+    pub replay: Option<TraceReplay>, // recorded passengers, next to the generated ones
+    pub dwell_time: f32,   // doors open and close, the time the people take to get in and out comes on top
+    pub settle_speed: f32, // a car slower than this at its target has arrived
+    pub car_status: Vec<CarStatus>,
+    // group control
+    pub dispatcher: Box<dyn Dispatcher>,
+    pub hall_calls: Vec<HallCall>,
+    pub destination_calls: Vec<DestinationCall>,
+    pub car_calls: Vec<CarCall>,
+    pub directions: Vec<Option<Direction>>, // the way every car sweeps
+    waiting: Vec<usize>,     // indices of the waiting passengers
+    riding: Vec<Vec<usize>>, // indices of the passengers in every car
 }
 
 impl ElevatorSystem {
     pub fn new(num_elevators: usize, floors: Vec<f32>) -> Self {
+        Self::with_clock(num_elevators, floors, SimClock::default())
+    }
+
+    pub fn with_clock(num_elevators: usize, floors: Vec<f32>, clock: SimClock) -> Self {
         let mut elevators = Vec::new();
         for _ in 0..num_elevators {
-            elevators.push(Elevator::new(
-                floors.clone(), 
-                500.0, 
-                300.0, 
-                10.0, 
-                5.0, 
-                1000.0, 
-                1.0,
-            ));
+            elevators.push(Elevator::standard(floors.clone()));
         }
 
         Self {
             floors,
             elevators,
+            clock,
             total_energy_consumed: 0.0,
+            energy_parameters: EnergyParameters::default(),
+            energy: vec![EnergyLedger::default(); num_elevators],
+            passengers: Vec::new(),
+            generator: None,
+            replay: None,
+            dwell_time: 3.0,
+            settle_speed: 0.05,
+            car_status: vec![CarStatus::Idle; num_elevators],
+            dispatcher: Box::new(CollectiveControl::default()),
+            hall_calls: Vec::new(),
+            destination_calls: Vec::new(),
+            car_calls: Vec::new(),
+            directions: vec![None; num_elevators],
+            waiting: Vec::new(),
+            riding: vec![Vec::new(); num_elevators],
         }
     }
 
+    // passengers from the generator call the cars from now on
+    pub fn set_generator(&mut self, generator: PassengerGenerator) {
+        self.generator = Some(generator);
+    }
+
+    // the recorded passengers appear at their recorded times, the trace has
+    // to fit the building
+    pub fn set_replay(&mut self, replay: TraceReplay) -> Result<(), Box<dyn Error>> {
+        replay.trace.validate(self.floors.len())?;
+        if replay.default_mass <= 0. {
+            return Err(format!("the default mass {} is not positive", replay.default_mass).into());
+        }
+        self.replay = Some(replay);
+        Ok(())
+    }
+
+    // the next passenger of the generator or the replay
+    pub fn next_spawn_time(&self) -> Option<f64> {
+        let generated = self.generator.as_ref().map(PassengerGenerator::next_spawn_time);
+        let replayed = self.replay.as_ref().and_then(TraceReplay::next_spawn_time);
+        generated.into_iter().chain(replayed).reduce(f64::min)
+    }
+
+    pub fn floors(&self) -> &[f32] {
+        &self.floors
+    }
+
+    // simulated seconds since the start
+    pub fn now(&self) -> f64 {
+        self.clock.now()
+    }
+
+    // advance the whole system by the fixed delta time of the clock
     pub fn update(&mut self) {
-        for elevator in &mut self.elevators {
-            elevator.update();
+        let delta_time = self.clock.tick();
+        self.step(delta_time);
+    }
+
+    // advance the whole system by a caller supplied delta time
+    pub fn update_with(&mut self, delta_time: f32) {
+        let delta_time = self.clock.advance(delta_time);
+        self.step(delta_time);
+    }
+
+    // run with the fixed delta time until the given simulated duration passes
+    pub fn run_for(&mut self, duration: f64) {
+        let end = self.clock.now() + duration;
+        while self.clock.now() < end {
+            self.update();
+        }
+    }
+
+    // advance the physics of every elevator up to the given simulated time
+    pub fn advance_to(&mut self, time: f64) {
+        let delta_time = self.clock.advance_to(time);
+        if delta_time > 0. {
+            self.step(delta_time);
+        }
+    }
+
+    // move the clock without touching the physics, only meaningful while
+    // every car is parked (the event engine uses this to jump over idle time),
+    // the standing time is still billed
+    pub fn skip_to(&mut self, time: f64) {
+        let delta_time = self.clock.advance_to(time);
+        if delta_time > 0. {
+            self.add_time(delta_time);
+        }
+    }
+
+    fn step(&mut self, delta_time: f32) {
+        // every elevator gets the same delta time
+        for (car, (elevator, ledger)) in self.elevators.iter_mut().zip(&mut self.energy).enumerate() {
+            let (used, recovered, height) = (elevator.get_used_energy(), elevator.get_recovered_energy(), elevator.current_height);
+            elevator.update(delta_time);
+            // the model has no brake, the motor holds a standing car, in a
+            // real one the brake does and it is in the standby. The car
+            // stands with the doors open too
+            if matches!(self.car_status[car], CarStatus::Idle | CarStatus::DoorsOpen { .. }) {
+                continue;
+            }
+            ledger.add_drive(
+                elevator.get_used_energy() - used,
+                elevator.get_recovered_energy() - recovered,
+                (elevator.current_height - height).abs(),
+                &self.energy_parameters,
+            );
+        }
+        self.update_passengers();
+        self.add_time(delta_time);
+    }
+
+    // standby and lighting, a car with the doors open stands too
+    fn add_time(&mut self, delta_time: f32) {
+        for (status, ledger) in self.car_status.iter().zip(&mut self.energy) {
+            let standing = matches!(status, CarStatus::Idle | CarStatus::DoorsOpen { .. });
+            ledger.add_time(delta_time, standing, &self.energy_parameters);
+        }
+        self.total_energy_consumed = self.energy.iter().map(EnergyLedger::net).sum();
+    }
+
+    // the cars together
+    pub fn total_energy(&self) -> EnergyLedger {
+        EnergyLedger::sum(&self.energy)
+    }
+
+    // the ledgers and the indicators so far
+    pub fn energy_report(&self) -> EnergyReport {
+        let total = self.total_energy();
+        let rated_distance = self.elevators.iter().zip(&self.energy)
+            .map(|(elevator, ledger)| elevator.max_load * ledger.distance)
+            .sum();
+        let floor_height = match (self.floors.first(), self.floors.last()) {
+            (Some(first), Some(last)) if self.floors.len() > 1 => (last - first) / (self.floors.len() - 1) as f32,
+            _ => 0.,
+        };
+        EnergyReport {
+            per_car: self.energy.clone(),
+            indicators: EnergyIndicators::new(&total, self.arrived_passengers().count(), rated_distance, floor_height),
+            total,
+        }
+    }
+
+    // a passenger appears at the origin floor and presses the button of
+    // the direction, or types the destination on the keypad
+    pub fn add_passenger(&mut self, passenger: Passenger) {
+        let floor = passenger.origin;
+        let direction = passenger.direction();
+        let call = DestinationCall {
+            passenger: self.passengers.len(),
+            floor,
+            destination: passenger.destination,
+            time: self.clock.now(),
+            car: None,
+        };
+        self.waiting.push(self.passengers.len());
+        self.passengers.push(passenger);
+
+        if self.dispatcher.uses_destinations() {
+            self.destination_calls.push(call);
+            return;
+        }
+
+        // a car with open doors there going that way takes the passenger anyway
+        let is_served = self.car_status.iter().zip(&self.directions).any(|(status, car_direction)| {
+            matches!(status, CarStatus::DoorsOpen { floor: door_floor, .. } if *door_floor == floor)
+                && car_direction.is_none_or(|car_direction| car_direction == direction)
+        });
+        if !is_served {
+            self.press_hall_button(floor, direction);
+        }
+    }
+
+    pub fn waiting_passengers(&self) -> impl Iterator<Item = &Passenger> {
+        self.waiting.iter().map(|idx| &self.passengers[*idx])
+    }
+
+    pub fn riding_passengers(&self, car: usize) -> impl Iterator<Item = &Passenger> {
+        self.riding[car].iter().map(|idx| &self.passengers[*idx])
+    }
+
+    pub fn arrived_passengers(&self) -> impl Iterator<Item = &Passenger> {
+        self.passengers.iter().filter(|passenger| passenger.state == PassengerState::Arrived)
+    }
+
+    // the dispatcher decides which car answers the hall calls and where the
+    // cars stop
+    pub fn set_dispatcher(&mut self, dispatcher: Box<dyn Dispatcher>) {
+        self.dispatcher = dispatcher;
+    }
+
+    fn press_hall_button(&mut self, floor: usize, direction: Direction) {
+        if !self.hall_calls.iter().any(|call| call.floor == floor && call.direction == direction) {
+            self.hall_calls.push(HallCall { floor, direction, time: self.clock.now(), car: None });
+        }
+    }
+
+    fn press_car_button(&mut self, car: usize, floor: usize) {
+        let call = CarCall { car, floor };
+        if !self.car_calls.contains(&call) {
+            self.car_calls.push(call);
+        }
+    }
+
+    fn assign_hall_calls(&mut self) {
+        if !self.hall_calls.is_empty() {
+            let state = dispatch_state!(self);
+            let assignments = self.dispatcher.assign(&state);
+            for assignment in assignments {
+                if let Some(call) = self.hall_calls.get_mut(assignment.call) {
+                    call.car = Some(assignment.car);
+                }
+            }
+        }
+
+        if self.destination_calls.iter().any(|call| call.car.is_none()) {
+            let state = dispatch_state!(self);
+            let assignments = self.dispatcher.assign_destinations(&state);
+            for assignment in assignments {
+                // the letter on the keypad does not change, call_again clears it
+                if let Some(call) = self.destination_calls.get_mut(assignment.call).filter(|call| call.car.is_none()) {
+                    call.car = Some(assignment.car);
+                    self.passengers[call.passenger].assign_car(Some(assignment.car));
+                }
+            }
+        }
+    }
+
+    // what the dispatcher sees, for the ones that decide outside of it
+    pub fn dispatch_state(&self) -> DispatchState<'_> {
+        dispatch_state!(self)
+    }
+
+    fn next_stop(&mut self, car: usize) -> Option<usize> {
+        let state = dispatch_state!(self);
+        self.dispatcher.next_stop(car, &state)
+    }
+
+    fn update_passengers(&mut self) {
+        let now = self.clock.now();
+        if let Some(generator) = &mut self.generator {
+            for passenger in generator.poll(now) {
+                self.add_passenger(passenger);
+            }
+        }
+        if let Some(replay) = &mut self.replay {
+            for passenger in replay.poll(now) {
+                self.add_passenger(passenger);
+            }
+        }
+        self.assign_hall_calls();
+
+        for car in 0..self.elevators.len() {
+            match self.car_status[car] {
+                CarStatus::Idle => {
+                    let Some(floor) = self.next_stop(car) else {
+                        self.directions[car] = None;
+                        continue;
+                    };
+                    let elevator = &mut self.elevators[car];
+                    if elevator.distance_to_floor(floor).abs() < 0.01 {
+                        // already standing there
+                        self.open_doors(car, floor, now);
+                    } else {
+                        self.directions[car] = Some(if elevator.distance_to_floor(floor) > 0. { Direction::Up } else { Direction::Down });
+                        elevator.set_target(floor);
+                        self.car_status[car] = CarStatus::Moving { floor };
+                        self.energy[car].trips += 1;
+                    }
+                },
+                CarStatus::Moving { floor } => {
+                    let elevator = &self.elevators[car];
+                    if elevator.is_idle() && elevator.get_current_speed().abs() < self.settle_speed {
+                        self.open_doors(car, floor, now);
+                        continue;
+                    }
+                    // a closer floor on the way that the car can still stop at
+                    if let Some(stop) = self.next_stop(car) {
+                        if self.dispatch_state().is_on_the_way(car, stop, floor) {
+                            self.elevators[car].set_target(stop);
+                            self.car_status[car] = CarStatus::Moving { floor: stop };
+                        }
+                    }
+                },
+                CarStatus::DoorsOpen { floor, until } => {
+                    // late comers get in while the doors are open and keep them open
+                    if self.directions[car].is_none() {
+                        self.directions[car] = self.dispatch_state().departure_direction(car, floor);
+                    }
+                    let boarding = self.board(car, floor, now);
+                    let until = until + boarding as f64;
+                    self.car_status[car] = CarStatus::DoorsOpen { floor, until };
+                    if now >= until {
+                        self.car_status[car] = CarStatus::Idle;
+                        self.call_again(car, floor);
+                    }
+                },
+            }
+        }
+    }
+
+    // the ones that did not fit or go the other way call again
+    fn call_again(&mut self, car: usize, floor: usize) {
+        if self.dispatcher.uses_destinations() {
+            // the ones this car left behind get a new car
+            let direction = self.directions[car];
+            for call in &mut self.destination_calls {
+                if call.floor == floor && call.car == Some(car) && Some(call.direction()) == direction {
+                    call.car = None;
+                    self.passengers[call.passenger].assign_car(None);
+                }
+            }
+            return;
+        }
+
+        let waiting: Vec<Direction> = self.waiting_passengers()
+            .filter(|passenger| passenger.origin == floor)
+            .map(|passenger| passenger.direction())
+            .collect();
+        for direction in waiting {
+            self.press_hall_button(floor, direction);
+        }
+    }
+
+    // the doors stay open until everyone got out and in, one after the other
+    fn open_doors(&mut self, car: usize, floor: usize, now: f64) {
+        self.energy[car].add_door_cycle(&self.energy_parameters);
+        self.car_calls.retain(|call| !(call.car == car && call.floor == floor));
+
+        // people get out first
+        let mut alighting = 0.;
+        let passengers = &mut self.passengers;
+        let elevator = &mut self.elevators[car];
+        self.riding[car].retain(|idx| {
+            let passenger = &mut passengers[*idx];
+            if passenger.destination != floor {
+                return true;
+            }
+            passenger.alight(now);
+            elevator.unload(passenger.mass);
+            alighting += passenger.alighting_duration;
+            false
+        });
+
+        self.directions[car] = self.dispatch_state().departure_direction(car, floor);
+        let boarding = self.board(car, floor, now);
+        let until = now + (self.dwell_time + alighting + boarding) as f64;
+        self.car_status[car] = CarStatus::DoorsOpen { floor, until };
+    }
+
+    // the passengers going the way of the car get in, returns the time they take
+    fn board(&mut self, car: usize, floor: usize, now: f64) -> f32 {
+        let Some(direction) = self.directions[car] else {
+            return 0.;
+        };
+        // the button of the direction goes off, the car is here
+        self.hall_calls.retain(|call| !(call.floor == floor && call.direction == direction));
+
+        let mut boarding = 0.;
+        let mut destinations = Vec::new();
+        let passengers = &mut self.passengers;
+        let elevator = &mut self.elevators[car];
+        let riding = &mut self.riding[car];
+        self.waiting.retain(|idx| {
+            let passenger = &mut passengers[*idx];
+            if passenger.origin != floor
+                || passenger.direction() != direction
+                || !passenger.can_board(car)
+                || elevator.current_load + passenger.mass > elevator.max_load {
+                return true;
+            }
+            passenger.board(car, now);
+            elevator.load(passenger.mass);
+            riding.push(*idx);
+            destinations.push(passenger.destination);
+            boarding += passenger.boarding_duration;
+            false
+        });
+
+        let passengers = &self.passengers;
+        self.destination_calls.retain(|call| passengers[call.passenger].state == PassengerState::Waiting);
+        for destination in destinations {
+            self.press_car_button(car, destination);
+        }
+        boarding
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::population::demographics::Demographics;
+    use crate::test_fixtures::population;
+    use crate::population::generator::{ArrivalProcess, PopulationParameters};
+    use crate::population::trace::{Trace, REPLAY_FIRST_ID};
+
+    fn run_scenario() -> Vec<(f32, f32)> {
+        let mut system = ElevatorSystem::new(2, vec![0.0, 3.0, 6.0, 9.0]);
+        let mut trajectory = Vec::new();
+
+        system.run_for(1.);
+        system.elevators[0].set_target(3);
+        system.elevators[1].set_target(1);
+        for _ in 0..500 {
+            system.update();
+            trajectory.push((system.elevators[0].current_height, system.elevators[1].current_height));
+        }
+
+        trajectory
+    }
+
+    #[test]
+    fn deterministic_replay() {
+        let first = run_scenario();
+        let second = run_scenario();
+        // bit identical, not just close
+        assert!(first == second);
+    }
+
+    #[test]
+    fn elevators_move() {
+        let trajectory = run_scenario();
+        let (first, second) = trajectory[trajectory.len()-1];
+        assert!(first > 0.);
+        assert!(second > 0.);
+    }
+
+    #[test]
+    fn passenger_rides() {
+        let mut system = ElevatorSystem::new(1, vec![0.0, 3.0, 6.0, 9.0]);
+        system.add_passenger(Passenger::new(0, 2, 0, 80., 0.));
+        system.run_for(40.);
+
+        let passenger = &system.passengers[0];
+        assert!(passenger.state == PassengerState::Arrived, "{:?}", passenger);
+        assert!(passenger.board_time.unwrap() < passenger.arrival_time.unwrap());
+        assert!((system.elevators[0].current_height - 0.).abs() < 0.01);
+        assert!(system.elevators[0].current_load.abs() < 1e-3);
+    }
+
+    #[test]
+    fn generated_passengers_are_carried() {
+        let parameters = population(ArrivalProcess::Poisson { rate: 360. }, 3, 0.);
+        let mut system = ElevatorSystem::new(2, vec![0.0, 3.0, 6.0, 9.0]);
+        system.set_generator(PassengerGenerator::new(parameters, 4).unwrap());
+        system.run_for(300.);
+
+        assert!(system.passengers.len() > 10);
+        assert!(system.arrived_passengers().count() > system.passengers.len() / 2);
+        for passenger in system.arrived_passengers() {
+            assert!(passenger.spawn_time <= passenger.board_time.unwrap());
+        }
+        // the cars carry exactly the people in them
+        for car in 0..2 {
+            let mass: f32 = system.riding_passengers(car).map(|passenger| passenger.mass).sum();
+            assert!((system.elevators[car].current_load - mass).abs() < 0.1);
         }
     }
+
+    #[test]
+    fn slow_passengers_keep_the_doors_open() {
+        let door_time = |boarding_duration: f32| {
+            let mut system = ElevatorSystem::new(1, vec![0.0, 3.0, 6.0]);
+            let mut passenger = Passenger::new(0, 0, 2, 80., 0.);
+            passenger.boarding_duration = boarding_duration;
+            system.add_passenger(passenger);
+            system.update();
+            let CarStatus::DoorsOpen { until, .. } = system.car_status[0] else {
+                panic!("{:?}", system.car_status[0]);
+            };
+            until - system.now()
+        };
+        assert!((door_time(8.) - door_time(1.) - 7.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn trolleys_are_carried() {
+        // a trolley is carried and takes its time at the door
+        let mut parameters = Demographics::default();
+        parameters.groups.iter_mut().for_each(|group| group.trolley_probability = 1.);
+        let mut rng = rand::SeedableRng::seed_from_u64(0);
+        let traits = parameters.sample(&mut rng);
+        let mut system = ElevatorSystem::new(1, vec![0.0, 3.0, 6.0]);
+        system.add_passenger(Passenger::from_traits(0, 0, 2, &traits, 0.));
+        system.update();
+        assert!((system.elevators[0].current_load - traits.mass).abs() < 1e-3);
+        assert!(traits.mass > 25. + 10.);
+        assert!(traits.boarding_duration > parameters.trolley_time);
+    }
+
+    #[test]
+    fn recorded_passengers_are_replayed() {
+        let run = || {
+            let trace = Trace::from_file("data/lobby_trace.csv").unwrap();
+            trace.validate(4).unwrap();
+            let mut system = ElevatorSystem::new(2, vec![0.0, 3.0, 6.0, 9.0]);
+            system.set_replay(TraceReplay::new(trace, 75.)).unwrap();
+            system.run_for(120.);
+            system.passengers
+        };
+        let passengers = run();
+        let recorded = [2.0, 4.5, 4.5, 11.0, 30.25];
+        assert!(passengers.len() == recorded.len());
+        for (passenger, time) in passengers.iter().zip(recorded) {
+            assert!(passenger.spawn_time == time);
+            assert!(passenger.state == PassengerState::Arrived, "{:?}", passenger);
+        }
+        assert!(passengers[1].mass == 75. && passengers[2].mass == 64.);
+        // the same demand gives the same run
+        assert!(run() == passengers);
+    }
+
+    #[test]
+    fn replay_next_to_generator() {
+        // the trace goes up to floor 3
+        let trace = Trace::from_file("data/lobby_trace.csv").unwrap();
+        let mut small = ElevatorSystem::new(1, vec![0.0, 3.0, 6.0]);
+        assert!(small.set_replay(TraceReplay::new(trace.clone(), 75.)).is_err());
+        assert!(small.replay.is_none());
+
+        let mut system = ElevatorSystem::new(2, vec![0.0, 3.0, 6.0, 9.0]);
+        let parameters = PopulationParameters::from_file("param/population_parameters.yaml").unwrap();
+        system.set_generator(PassengerGenerator::new(parameters, 4).unwrap());
+        system.set_replay(TraceReplay::new(trace, 75.)).unwrap();
+        system.run_for(120.);
+
+        // recorded and generated people do not share an id
+        let mut ids: Vec<u64> = system.passengers.iter().map(|passenger| passenger.id).collect();
+        let replayed = ids.iter().filter(|id| **id >= REPLAY_FIRST_ID).count();
+        assert!(replayed == 5 && ids.len() > replayed);
+        ids.sort();
+        ids.dedup();
+        assert!(ids.len() == system.passengers.len());
+    }
+
+    #[test]
+    fn caller_supplied_delta_time() {
+        let mut system = ElevatorSystem::with_clock(1, vec![0.0, 100.0], SimClock::new(0.01, 2.0));
+        system.update_with(0.05);
+        system.update();
+        assert!((system.now() - 0.12).abs() < 1e-6);
+    }
+
+    #[test]
+    fn energy_of_a_ride() {
+        let mut system = ElevatorSystem::new(2, vec![0.0, 3.0, 6.0, 9.0]);
+        system.add_passenger(Passenger::new(0, 2, 0, 80., 0.));
+        system.run_for(40.);
+
+        let total = system.total_energy();
+        assert!((system.total_energy_consumed - total.net()).abs() < 1e-3);
+        assert!(total.motoring > 0. && total.door_cycles >= 1 && total.trips >= 1);
+        assert!(total.distance >= 6. && total.standby > 0.);
+        // the other car only stood
+        assert!(system.energy[1].motoring == 0. && system.energy[1].running_time == 0.);
+
+        // the lights go off after a long standing, the standby goes on
+        let (lighting, standby) = (total.lighting, total.standby);
+        system.run_for(2. * system.energy_parameters.lighting_off_after as f64);
+        let total = system.total_energy();
+        assert!(total.lighting - lighting < 2. * system.energy_parameters.lighting_power * system.energy_parameters.lighting_off_after);
+        assert!(total.standby > standby);
+
+        let report = system.energy_report();
+        assert!(report.per_car.len() == 2 && report.total == total);
+        assert!(report.indicators.per_passenger > 0. && report.indicators.per_floor > 0.);
+        assert!((report.indicators.standby_power - 100.).abs() < 0.5 && report.indicators.standby_class == 'B');
+    }
+
+    #[test]
+    fn no_drive_with_open_doors() {
+        let mut system = ElevatorSystem::new(1, vec![0.0, 3.0, 6.0, 9.0]);
+        system.add_passenger(Passenger::new(0, 0, 3, 80., 0.));
+        while !matches!(system.car_status[0], CarStatus::DoorsOpen { .. }) {
+            system.update();
+        }
+        let (motoring, standby) = (system.energy[0].motoring, system.energy[0].standby);
+        let running_time = system.energy[0].running_time;
+        let mut steps = 0;
+        while matches!(system.car_status[0], CarStatus::DoorsOpen { .. }) {
+            system.update();
+            steps += 1;
+        }
+        // the doors were open for a while, the motor did not hold the car and
+        // the car stood in the standby
+        assert!(steps > 10 && system.energy[0].motoring == motoring);
+        assert!(system.energy[0].standby > standby);
+        assert!(system.energy[0].running_time == running_time);
+    }
+
+    #[test]
+    fn skipped_time_is_standby() {
+        let mut system = ElevatorSystem::new(1, vec![0.0, 3.0, 6.0, 9.0]);
+        system.skip_to(100.);
+        let ledger = system.energy[0];
+        assert!((ledger.idle_time - 100.).abs() < 1e-3);
+        assert!((ledger.standby - system.energy_parameters.standby_power * 100.).abs() < 1e-3);
+        assert!(system.total_energy_consumed > 0.);
+    }
 }
@@ -0,0 +1,226 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Energy of the elevators, a ledger for every car (kW s):
+//   motoring     taken from the supply by the drive (motor.rs) while the car
+//                is not idle, a standing car is held by the brake
+//   regenerated  given back to the supply while the motor generates, only
+//                the part the drive can feed back (regeneration_efficiency)
+//   standby      controller, drive and car equipment while the car stands
+//   door         the door operator, a fixed energy for every opening and closing
+//   lighting     the lights of the car, off after the car stood lighting_off_after
+// The net is what the building pays for, ElevatorSystem::total_energy_consumed
+// is the net of every car. The indicators are in the style of ISO 25745-2:
+// the running energy (drive and doors) over the rated load and the distance
+// travelled (mWh/(kg m)) and the standby power (W) get a class from A to G.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+// kW s to Wh
+pub const WH_PER_KWS: f32 = 1. / 3.6;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct EnergyParameters {
+    pub standby_power: f32,           // kW
+    pub door_cycle_energy: f32,       // kW s for an opening and a closing
+    pub lighting_power: f32,          // kW
+    pub lighting_off_after: f32,      // seconds of standing
+    pub regeneration_efficiency: f32, // 0 with a braking resistor
+}
+
+impl Default for EnergyParameters {
+    fn default() -> Self {
+        Self {
+            standby_power: 0.1,
+            door_cycle_energy: 0.5,
+            lighting_power: 0.05,
+            lighting_off_after: 300.,
+            regeneration_efficiency: 0.8,
+        }
+    }
+}
+
+impl EnergyParameters {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct EnergyLedger {
+    pub motoring: f32,
+    pub regenerated: f32,
+    pub standby: f32,
+    pub door: f32,
+    pub lighting: f32,
+    pub trips: usize,
+    pub door_cycles: usize,
+    pub distance: f32,     // meters
+    pub running_time: f32, // seconds the car did not stand
+    pub idle_time: f32,
+    #[serde(skip)]
+    standing_for: f32, // seconds since the car stopped
+}
+
+impl EnergyLedger {
+    // the drive in a step
+    pub fn add_drive(&mut self, used: f32, recovered: f32, distance: f32, parameters: &EnergyParameters) {
+        self.motoring += used.max(0.);
+        self.regenerated += recovered.max(0.) * parameters.regeneration_efficiency;
+        self.distance += distance;
+    }
+
+    // standby and lighting of a step, standing is true for an idle car
+    pub fn add_time(&mut self, delta_time: f32, standing: bool, parameters: &EnergyParameters) {
+        let lit = !standing || self.standing_for < parameters.lighting_off_after;
+        if standing {
+            self.idle_time += delta_time;
+            self.standing_for += delta_time;
+            self.standby += parameters.standby_power * delta_time;
+        } else {
+            self.running_time += delta_time;
+            self.standing_for = 0.;
+        }
+        if lit {
+            self.lighting += parameters.lighting_power * delta_time;
+        }
+    }
+
+    pub fn add_door_cycle(&mut self, parameters: &EnergyParameters) {
+        self.door += parameters.door_cycle_energy;
+        self.door_cycles += 1;
+    }
+
+    // the drive and the doors, what a trip costs
+    pub fn running(&self) -> f32 {
+        self.motoring - self.regenerated + self.door
+    }
+
+    pub fn consumed(&self) -> f32 {
+        self.motoring + self.standby + self.door + self.lighting
+    }
+
+    pub fn net(&self) -> f32 {
+        self.consumed() - self.regenerated
+    }
+
+    // the ledgers of the cars together
+    pub fn sum<'a>(ledgers: impl IntoIterator<Item = &'a EnergyLedger>) -> Self {
+        ledgers.into_iter().fold(Self::default(), |mut total, ledger| {
+            total.motoring += ledger.motoring;
+            total.regenerated += ledger.regenerated;
+            total.standby += ledger.standby;
+            total.door += ledger.door;
+            total.lighting += ledger.lighting;
+            total.trips += ledger.trips;
+            total.door_cycles += ledger.door_cycles;
+            total.distance += ledger.distance;
+            total.running_time += ledger.running_time;
+            total.idle_time += ledger.idle_time;
+            total
+        })
+    }
+}
+
+// limits of the classes A to F, above the last one is G
+pub const RUNNING_CLASS_LIMITS: [f32; 6] = [0.56, 0.84, 1.26, 1.89, 2.80, 4.20]; // mWh/(kg m)
+pub const STANDBY_CLASS_LIMITS: [f32; 6] = [50., 100., 200., 400., 800., 1600.]; // W
+
+pub fn energy_class(value: f32, limits: &[f32; 6]) -> char {
+    let class = limits.iter().position(|limit| value <= *limit).unwrap_or(limits.len());
+    (b'A' + class as u8) as char
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct EnergyIndicators {
+    pub net: f32,              // Wh
+    pub per_passenger: f32,    // Wh for every passenger who arrived
+    pub per_trip: f32,         // Wh
+    pub per_floor: f32,        // Wh for every floor travelled
+    pub specific_running: f32, // mWh/(kg m)
+    pub standby_power: f32,    // W
+    pub running_class: char,
+    pub standby_class: char,
+}
+
+impl EnergyIndicators {
+    // rated_distance is the rated load of every car times the distance it
+    // travelled (kg m), floor_height the mean height of a floor
+    pub fn new(total: &EnergyLedger, passengers: usize, rated_distance: f32, floor_height: f32) -> Self {
+        let per = |count: f32| if count > 0. { total.net() * WH_PER_KWS / count } else { 0. };
+        let specific_running = if rated_distance > 0. {
+            total.running() * WH_PER_KWS * 1000. / rated_distance
+        } else {
+            0.
+        };
+        let standby_power = if total.idle_time > 0. { total.standby / total.idle_time * 1000. } else { 0. };
+        Self {
+            net: total.net() * WH_PER_KWS,
+            per_passenger: per(passengers as f32),
+            per_trip: per(total.trips as f32),
+            per_floor: per(if floor_height > 0. { total.distance / floor_height } else { 0. }),
+            specific_running,
+            standby_power,
+            running_class: energy_class(specific_running, &RUNNING_CLASS_LIMITS),
+            standby_class: energy_class(standby_power, &STANDBY_CLASS_LIMITS),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EnergyReport {
+    pub per_car: Vec<EnergyLedger>,
+    pub total: EnergyLedger,
+    pub indicators: EnergyIndicators,
+}
+
+impl EnergyReport {
+    pub fn to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read() {
+        let parameters = EnergyParameters::from_file("param/energy_parameters.yaml").unwrap();
+        assert!(parameters == EnergyParameters::default());
+    }
+
+    #[test]
+    fn ledger_and_classes() {
+        let parameters = EnergyParameters { lighting_off_after: 10., ..Default::default() };
+        let mut ledger = EnergyLedger::default();
+        ledger.add_drive(36., 10., 30., &parameters);
+        ledger.add_door_cycle(&parameters);
+        ledger.trips += 1;
+        ledger.add_time(20., false, &parameters);
+        // the lights go off after 10 s of standing
+        for _ in 0..40 {
+            ledger.add_time(1., true, &parameters);
+        }
+        assert!((ledger.lighting - 0.05 * 30.).abs() < 1e-4);
+        assert!((ledger.standby - 4.).abs() < 1e-4 && ledger.idle_time == 40.);
+        assert!((ledger.running() - (36. - 8. + 0.5)).abs() < 1e-4);
+        assert!((ledger.net() - (ledger.consumed() - 8.)).abs() < 1e-4);
+        assert!(EnergyLedger::sum([&ledger, &ledger]).trips == 2);
+
+        let indicators = EnergyIndicators::new(&ledger, 2, 1000. * 30., 3.);
+        assert!((indicators.per_passenger * 2. - ledger.net() / 3.6).abs() < 1e-4);
+        assert!((indicators.per_floor * 10. - ledger.net() / 3.6).abs() < 1e-4);
+        // 28.5 kW s is 7.92 Wh over 30000 kg m
+        assert!((indicators.specific_running - 0.2639).abs() < 1e-3);
+        assert!(indicators.running_class == 'A' && (indicators.standby_power - 100.).abs() < 1e-2 && indicators.standby_class == 'B');
+        assert!(energy_class(5., &RUNNING_CLASS_LIMITS) == 'G' && energy_class(1.5, &RUNNING_CLASS_LIMITS) == 'D');
+    }
+}
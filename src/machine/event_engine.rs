@@ -0,0 +1,411 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Discrete event simulation engine, sits next to the fixed tick
+// ElevatorSystem::update loop. Events are kept in a time ordered queue, the
+// clock jumps straight to the next event while no car moves and nothing waits
+// for a car, the elevator physics is advanced with small sub steps only while
+// something happens. Running the same scenario in EngineMode::FixedTick is
+// the ElevatorSystem::update loop itself, so the two engines can be compared.
+// The passengers, the calls, the dispatcher and the doors are the ones of
+// ElevatorSystem, the engine only decides when the system is stepped and
+// logs what happened: the floors the cars pass (interpolated inside the
+// step), the arrivals, and the doors opening and closing. Passengers come
+// from spawn_passenger or from the generator and the replay of the system.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use super::elevator_system::{CarStatus, ElevatorSystem};
+use crate::control_algorithms::Dispatcher;
+use crate::population::population::Passenger;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    PassengerSpawn { passenger: usize, origin: usize, destination: usize }, // index in the spawns
+    FloorReached { elevator: usize, floor: usize },
+    Arrival { elevator: usize, floor: usize },
+    DoorOpen { elevator: usize, floor: usize },
+    DoorClose { elevator: usize, floor: usize },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub time: f64,
+    pub kind: EventKind,
+    seq: u64, // keeps the scheduling order of events with the same time
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Event {}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Event {
+    // BinaryHeap is a max heap, the order is reversed to pop the earliest event first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.time.total_cmp(&self.time)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineMode {
+    // ElevatorSystem::update on every tick of the system clock
+    FixedTick,
+    // jump over idle time, sub steps only while something happens
+    DiscreteEvent,
+}
+
+pub struct EventEngine {
+    pub system: ElevatorSystem,
+    pub mode: EngineMode,
+    pub sub_step: f32,   // physics step while something happens (only in discrete event mode)
+    pub log: Vec<Event>, // every handled event in the order of handling
+    pub physics_steps: u64,
+    pub spawns: Vec<Passenger>, // given to spawn_passenger
+    queue: BinaryHeap<Event>,
+    next_seq: u64,
+    last_floors: Vec<Option<usize>>,
+    owes_step: bool, // an event changed the system, it has to look at it
+}
+
+impl EventEngine {
+    pub fn new(system: ElevatorSystem, mode: EngineMode) -> Self {
+        let num_elevators = system.elevators.len();
+        let sub_step = system.clock.fixed_delta_time();
+
+        Self {
+            system,
+            mode,
+            sub_step,
+            log: Vec::new(),
+            physics_steps: 0,
+            spawns: Vec::new(),
+            queue: BinaryHeap::new(),
+            next_seq: 0,
+            last_floors: vec![None; num_elevators],
+            owes_step: false,
+        }
+    }
+
+    pub fn set_dispatcher(&mut self, dispatcher: Box<dyn Dispatcher>) {
+        self.system.set_dispatcher(dispatcher);
+    }
+
+    pub fn now(&self) -> f64 {
+        self.system.now()
+    }
+
+    pub fn schedule(&mut self, time: f64, kind: EventKind) {
+        self.queue.push(Event { time, kind, seq: self.next_seq });
+        self.next_seq += 1;
+    }
+
+    // the passenger calls a car at the spawn time
+    pub fn spawn_passenger(&mut self, passenger: Passenger) {
+        let kind = EventKind::PassengerSpawn {
+            passenger: self.spawns.len(),
+            origin: passenger.origin,
+            destination: passenger.destination,
+        };
+        self.schedule(passenger.spawn_time, kind);
+        self.spawns.push(passenger);
+    }
+
+    pub fn pending_events(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_anything_moving(&self) -> bool {
+        self.system.car_status.iter().any(|status| matches!(status, CarStatus::Moving { .. }))
+    }
+
+    // a step of the system changes something: a car moves, a call waits for
+    // a car, doors are due to close or an event was not looked at yet
+    fn is_busy(&self) -> bool {
+        let system = &self.system;
+        self.owes_step
+            || system.next_spawn_time().is_some_and(|time| time <= self.now())
+            || !system.hall_calls.is_empty()
+            || !system.destination_calls.is_empty()
+            || !system.car_calls.is_empty()
+            || system.car_status.iter().any(|status| match status {
+                CarStatus::Idle => false,
+                CarStatus::Moving { .. } => true,
+                CarStatus::DoorsOpen { until, .. } => *until <= self.now(),
+            })
+    }
+
+    // the next time something happens without a step: an event, a passenger
+    // of the generator or the replay, or doors that close
+    fn next_wake(&self, end: f64) -> f64 {
+        let doors = self.system.car_status.iter().filter_map(|status| match status {
+            CarStatus::DoorsOpen { until, .. } => Some(*until),
+            _ => None,
+        });
+        self.queue.peek().map(|event| event.time).into_iter()
+            .chain(self.system.next_spawn_time())
+            .chain(doors)
+            .fold(end, f64::min)
+    }
+
+    // arrival times of the given car, handy for comparing the engines
+    pub fn arrivals(&self, elevator_idx: usize) -> Vec<(f64, usize)> {
+        self.log.iter().filter_map(|event| match event.kind {
+            EventKind::Arrival { elevator, floor } if elevator == elevator_idx => Some((event.time, floor)),
+            _ => None,
+        }).collect()
+    }
+
+    pub fn run_until(&mut self, end: f64) {
+        loop {
+            self.handle_due_events();
+            if self.now() >= end {
+                break;
+            }
+
+            match self.mode {
+                EngineMode::FixedTick => self.step(|system| system.update()),
+                EngineMode::DiscreteEvent => {
+                    if self.is_busy() {
+                        let next_event = self.queue.peek().map_or(end, |event| event.time).min(end);
+                        let step_end = (self.now() + self.sub_step as f64).min(next_event);
+                        self.step(|system| system.advance_to(step_end));
+                    } else {
+                        // nothing happens, nothing to simulate until the next wake
+                        let wake = self.next_wake(end);
+                        self.system.skip_to(wake);
+                    }
+                },
+            }
+        }
+        self.handle_due_events();
+    }
+
+    fn handle_due_events(&mut self) {
+        while let Some(event) = self.queue.peek() {
+            if event.time > self.now() {
+                break;
+            }
+            let event = self.queue.pop().unwrap();
+            self.handle(event);
+        }
+    }
+
+    // the events of the cars have happened in the system already, they are
+    // only logged
+    fn handle(&mut self, event: Event) {
+        self.log.push(event);
+        if let EventKind::PassengerSpawn { passenger, .. } = event.kind {
+            self.system.add_passenger(self.spawns[passenger].clone());
+            self.owes_step = true;
+        }
+    }
+
+    // a step of the system, what it did becomes events
+    fn step(&mut self, step: impl FnOnce(&mut ElevatorSystem)) {
+        let start = self.now();
+        let start_heights: Vec<f32> = self.system.elevators.iter().map(|car| car.current_height).collect();
+        let start_status = self.system.car_status.clone();
+        step(&mut self.system);
+        self.physics_steps += 1;
+        self.owes_step = false;
+        let end = self.now();
+
+        for (idx, (start_height, before)) in start_heights.iter().zip(start_status).enumerate() {
+            let after = self.system.car_status[idx];
+            if let CarStatus::Moving { .. } = before {
+                let car = &self.system.elevators[idx];
+                let (start_height, end_height) = (*start_height, car.current_height);
+                // the exact crossing time is interpolated inside the step
+                let mut crossings = Vec::new();
+                for (floor, height) in car.floors.iter().enumerate() {
+                    let crossed = (start_height < *height && *height <= end_height)
+                        || (end_height <= *height && *height < start_height);
+                    if crossed {
+                        let ratio = ((*height - start_height) / (end_height - start_height)) as f64;
+                        crossings.push((start + (end - start) * ratio, floor));
+                    }
+                }
+                for (time, floor) in crossings {
+                    self.schedule(time, EventKind::FloorReached { elevator: idx, floor });
+                    self.last_floors[idx] = Some(floor);
+                }
+            }
+
+            match (before, after) {
+                (CarStatus::DoorsOpen { .. }, CarStatus::DoorsOpen { .. }) => (),
+                (before, CarStatus::DoorsOpen { floor, .. }) => {
+                    // the car may level into the floor from one side without crossing it
+                    if matches!(before, CarStatus::Moving { .. }) && self.last_floors[idx] != Some(floor) {
+                        self.schedule(end, EventKind::FloorReached { elevator: idx, floor });
+                        self.last_floors[idx] = Some(floor);
+                    }
+                    self.schedule(end, EventKind::Arrival { elevator: idx, floor });
+                    self.schedule(end, EventKind::DoorOpen { elevator: idx, floor });
+                },
+                (CarStatus::DoorsOpen { floor, .. }, _) => {
+                    self.schedule(end, EventKind::DoorClose { elevator: idx, floor });
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_algorithms::basic_algorithm::CollectiveControl;
+    use crate::control_algorithms::{Assignment, DispatchState};
+    use crate::test_fixtures::population;
+    use crate::population::generator::{ArrivalProcess, PassengerGenerator};
+
+    fn run(mode: EngineMode) -> EventEngine {
+        let system = ElevatorSystem::new(1, vec![0.0, 3.0, 6.0, 9.0]);
+        let mut engine = EventEngine::new(system, mode);
+        engine.spawn_passenger(Passenger::new(0, 0, 2, 75., 100.));
+        engine.run_until(300.);
+        engine
+    }
+
+    #[test]
+    fn event_order() {
+        let engine = run(EngineMode::DiscreteEvent);
+        let kinds: Vec<EventKind> = engine.log.iter().map(|event| event.kind).collect();
+
+        let spawn = kinds.iter().position(|kind| matches!(kind, EventKind::PassengerSpawn { .. })).unwrap();
+        let first_floor = kinds.iter().position(|kind| *kind == EventKind::FloorReached { elevator: 0, floor: 1 }).unwrap();
+        let second_floor = kinds.iter().position(|kind| *kind == EventKind::FloorReached { elevator: 0, floor: 2 }).unwrap();
+        let arrival = kinds.iter().position(|kind| *kind == EventKind::Arrival { elevator: 0, floor: 2 }).unwrap();
+        let door_open = kinds.iter().position(|kind| *kind == EventKind::DoorOpen { elevator: 0, floor: 2 }).unwrap();
+        let door_close = kinds.iter().position(|kind| *kind == EventKind::DoorClose { elevator: 0, floor: 2 }).unwrap();
+        assert!(spawn < first_floor);
+        assert!(first_floor < second_floor);
+        assert!(second_floor < arrival);
+        assert!(arrival < door_open);
+        assert!(door_open < door_close);
+
+        // the log is in time order
+        for pair in engine.log.windows(2) {
+            assert!(pair[0].time <= pair[1].time);
+        }
+    }
+
+    #[test]
+    fn skips_idle_time() {
+        let fixed = run(EngineMode::FixedTick);
+        let discrete = run(EngineMode::DiscreteEvent);
+        assert!(discrete.physics_steps * 2 < fixed.physics_steps);
+    }
+
+    #[test]
+    fn engines_agree() {
+        let fixed = run(EngineMode::FixedTick);
+        let discrete = run(EngineMode::DiscreteEvent);
+
+        let fixed_arrivals = fixed.arrivals(0);
+        let discrete_arrivals = discrete.arrivals(0);
+        assert!(fixed_arrivals.len() == discrete_arrivals.len());
+        for (a, b) in fixed_arrivals.iter().zip(discrete_arrivals.iter()) {
+            assert!(a.1 == b.1);
+            assert!((a.0 - b.0).abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn floor_reached_between_ticks() {
+        let engine = run(EngineMode::DiscreteEvent);
+        let tick = engine.system.clock.fixed_delta_time() as f64;
+        let crossing = engine.log.iter()
+            .find(|event| event.kind == EventKind::FloorReached { elevator: 0, floor: 1 })
+            .unwrap();
+        // interpolated, so it is not stuck on the tick grid
+        let offset = (crossing.time / tick).fract();
+        assert!(offset > 1e-6 && offset < 1. - 1e-6);
+    }
+
+    // gives every call to the last car, the stops come from collective control
+    struct LastCar(CollectiveControl);
+
+    impl Dispatcher for LastCar {
+        fn assign(&mut self, state: &DispatchState) -> Vec<Assignment> {
+            (0..state.hall_calls.len())
+                .map(|call| Assignment { call, car: state.cars.len() - 1 })
+                .collect()
+        }
+
+        fn next_stop(&mut self, car: usize, state: &DispatchState) -> Option<usize> {
+            self.0.next_stop(car, state)
+        }
+    }
+
+    #[test]
+    fn uses_the_dispatcher() {
+        let system = ElevatorSystem::new(2, vec![0.0, 3.0, 6.0, 9.0]);
+        let mut engine = EventEngine::new(system, EngineMode::DiscreteEvent);
+        engine.set_dispatcher(Box::new(LastCar(CollectiveControl::default())));
+        engine.spawn_passenger(Passenger::new(0, 1, 3, 75., 10.));
+        engine.run_until(200.);
+
+        assert!(engine.arrivals(0).is_empty());
+        let floors: Vec<usize> = engine.arrivals(1).iter().map(|(_, floor)| *floor).collect();
+        assert!(floors == vec![1, 3]);
+        assert!(engine.system.hall_calls.is_empty() && engine.system.car_calls.is_empty());
+    }
+
+    // the skipped time is standby, the ride is booked like in the fixed tick loop
+    #[test]
+    fn energy_in_both_engines() {
+        let fixed = run(EngineMode::FixedTick).system.total_energy();
+        let discrete = run(EngineMode::DiscreteEvent).system.total_energy();
+        assert!(discrete.trips == fixed.trips && discrete.trips > 0);
+        assert!((discrete.distance - fixed.distance).abs() < 0.01);
+        assert!((discrete.idle_time - fixed.idle_time).abs() < 1.);
+        assert!((discrete.running_time - fixed.running_time).abs() < 1.);
+        assert!((discrete.net() - fixed.net()).abs() < 0.01 * fixed.net().abs());
+    }
+
+    // the generated people ride the same way in both engines and in run_for
+    #[test]
+    fn generated_passengers_in_both_engines() {
+        let parameters = population(ArrivalProcess::Poisson { rate: 360. }, 3, 0.);
+        let system = || {
+            let mut system = ElevatorSystem::new(2, vec![0.0, 3.0, 6.0, 9.0]);
+            system.set_generator(PassengerGenerator::new(parameters.clone(), 4).unwrap());
+            system
+        };
+        let mut plain = system();
+        plain.run_for(300.);
+        let board_times = |system: &ElevatorSystem| -> Vec<f64> {
+            system.passengers.iter().filter_map(|passenger| passenger.board_time).collect()
+        };
+
+        for mode in [EngineMode::FixedTick, EngineMode::DiscreteEvent] {
+            let mut engine = EventEngine::new(system(), mode);
+            engine.run_until(300.);
+            let system = &engine.system;
+
+            assert!(system.passengers.len() == plain.passengers.len());
+            assert!(system.arrived_passengers().count() > system.passengers.len() / 2);
+            for (time, plain_time) in board_times(system).iter().zip(board_times(&plain)) {
+                assert!((time - plain_time).abs() < 0.5, "{} {}", time, plain_time);
+            }
+            for car in 0..2 {
+                let mass: f32 = system.riding_passengers(car).map(|passenger| passenger.mass).sum();
+                assert!((system.elevators[car].current_load - mass).abs() < 0.1);
+            }
+        }
+    }
+}
@@ -2,6 +2,13 @@
 // Copyright (C) 2024 Tuna Gül
 
 pub mod elevator;
+pub mod elevator_parameters;
 pub mod pid_controller;
+pub mod controllers;
 pub mod elevator_system;
-mod motor;
\ No newline at end of file
+pub mod sim_clock;
+pub mod event_engine;
+pub mod motor;
+pub mod motion_profile;
+pub mod tuning;
+pub mod energy;
\ No newline at end of file
@@ -0,0 +1,383 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Jerk limited (7 segment S-curve) trajectory planner for the trips of the car.
+// A trip from rest to rest is
+//   jerk up, constant accel, jerk down, cruise, jerk down, constant decel, jerk up
+// and the segments that are not needed for short trips get zero length. The
+// profile is a list of constant jerk segments, so position, speed and
+// acceleration references are known exactly at any time. When the target
+// changes mid-flight the profile is planned again from the reference state it
+// is in, the acceleration never jumps.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionLimits {
+    pub max_speed: f32,
+    pub max_accel: f32,
+    pub max_jerk: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MotionState {
+    pub position: f32,
+    pub speed: f32,
+    pub accel: f32,
+}
+
+impl MotionState {
+    // state after moving with a constant jerk for the given time
+    pub fn after(&self, jerk: f32, time: f32) -> Self {
+        Self {
+            position: self.position + self.speed * time + self.accel * time * time / 2. + jerk * time * time * time / 6.,
+            speed: self.speed + self.accel * time + jerk * time * time / 2.,
+            accel: self.accel + jerk * time,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    start: MotionState,
+    duration: f32,
+    jerk: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct MotionProfile {
+    limits: MotionLimits,
+    segments: Vec<Segment>,
+    elapsed: f32, // time since the profile was planned
+    target: f32,
+    reference: MotionState,
+}
+
+impl MotionProfile {
+    // the profile starts at rest on the given position
+    pub fn new(limits: MotionLimits, position: f32) -> Self {
+        Self {
+            limits,
+            segments: Vec::new(),
+            elapsed: 0.,
+            target: position,
+            reference: MotionState { position, ..Default::default() },
+        }
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn limits(&self) -> MotionLimits {
+        self.limits
+    }
+
+    // the new limits count from now on. A trip in progress is planned again
+    // right away, the old plan would keep accelerating to the old max speed
+    // and the ramp down to the new one would start too late
+    pub fn set_limits(&mut self, limits: MotionLimits) {
+        self.limits = limits;
+        if !self.is_finished() {
+            self.set_target(self.target);
+        }
+    }
+
+    // the reference state of the current time
+    pub fn reference(&self) -> MotionState {
+        self.reference
+    }
+
+    // total time of the planned profile
+    pub fn duration(&self) -> f32 {
+        self.segments.iter().map(|segment| segment.duration).sum()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.duration()
+    }
+
+    // the car did not start from where the profile thinks it is (it was moved
+    // by hand or fell back), start again at rest from the given position
+    pub fn reset(&mut self, position: f32) {
+        *self = Self::new(self.limits, position);
+    }
+
+    // plan again from the current reference state
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+        self.segments.clear();
+        self.elapsed = 0.;
+
+        let mut state = self.reference;
+
+        // bring the acceleration to zero first, the rest of the planner works
+        // with velocity changes that start and end without acceleration. A
+        // profile planned with the same limits starts this ramp early enough
+        // to stay under max_speed, only a lower limit from set_limits can be
+        // passed here and the slowdown below follows with the same jerk
+        if state.accel != 0. {
+            let jerk = -state.accel.signum() * self.limits.max_jerk;
+            state = self.push(state, jerk, state.accel.abs() / self.limits.max_jerk);
+        }
+
+        // moving away from the target or too fast to stop before it, stop first
+        // and plan the rest from rest
+        let direction = (target - state.position).signum();
+        let speed = state.speed * direction;
+        let distance = (target - state.position).abs();
+        if speed < 0. || self.velocity_change_distance(speed, 0.) > distance {
+            state = self.push_velocity_change(state, 0.);
+        }
+
+        // faster than allowed, after a change of the limits
+        if state.speed.abs() > self.limits.max_speed {
+            state = self.push_velocity_change(state, self.limits.max_speed * state.speed.signum());
+        }
+
+        let direction = (target - state.position).signum();
+        let speed = state.speed * direction;
+        let distance = (target - state.position).abs();
+        if distance <= 0. {
+            return;
+        }
+
+        // the peak speed of the trip, the highest speed that still leaves
+        // enough room to stop on the target
+        let peak = if self.trip_distance(speed, self.limits.max_speed) <= distance {
+            self.limits.max_speed
+        } else {
+            let mut low = speed;
+            let mut high = self.limits.max_speed;
+            for _ in 0..60 {
+                let middle = (low + high) / 2.;
+                if self.trip_distance(speed, middle) <= distance {
+                    low = middle;
+                } else {
+                    high = middle;
+                }
+            }
+            low
+        };
+
+        state = self.push_velocity_change(state, peak * direction);
+        let cruise_distance = distance - self.trip_distance(speed, peak);
+        if peak > 0. && cruise_distance > 0. {
+            state = self.push(state, 0., cruise_distance / peak);
+        }
+        self.push_velocity_change(state, 0.);
+    }
+
+    // where a car in the given state stops at the earliest, the acceleration
+    // goes to zero first like in set_target
+    pub fn stopping_position(&self, state: MotionState) -> f32 {
+        let mut state = state;
+        if state.accel != 0. {
+            let jerk = -state.accel.signum() * self.limits.max_jerk;
+            state = state.after(jerk, state.accel.abs() / self.limits.max_jerk);
+        }
+        state.position + state.speed.signum() * self.velocity_change_distance(state.speed.abs(), 0.)
+    }
+
+    // move the reference forward in time
+    pub fn update(&mut self, delta_time: f32) -> MotionState {
+        self.elapsed += delta_time;
+        self.reference = self.sample(self.elapsed);
+        self.reference
+    }
+
+    pub fn sample(&self, time: f32) -> MotionState {
+        let mut start_time = 0.;
+        for segment in &self.segments {
+            if time < start_time + segment.duration {
+                return segment.start.after(segment.jerk, time - start_time);
+            }
+            start_time += segment.duration;
+        }
+
+        // the profile is over, the reference stays on the target at rest
+        MotionState { position: self.target, ..Default::default() }
+    }
+
+    fn push(&mut self, start: MotionState, jerk: f32, duration: f32) -> MotionState {
+        if duration > 0. {
+            self.segments.push(Segment { start, duration, jerk });
+        }
+        start.after(jerk, duration)
+    }
+
+    // times of the jerk ramps and of the constant acceleration part of a
+    // velocity change that starts and ends with zero acceleration
+    fn velocity_change_times(&self, from: f32, to: f32) -> (f32, f32) {
+        let change = (to - from).abs();
+        let accel = self.limits.max_accel;
+        let jerk = self.limits.max_jerk;
+
+        if change >= accel * accel / jerk {
+            (accel / jerk, change / accel - accel / jerk)
+        } else {
+            // the max acceleration is never reached
+            ((change / jerk).sqrt(), 0.)
+        }
+    }
+
+    // the acceleration pulse is symmetric so the mean speed is the average
+    fn velocity_change_distance(&self, from: f32, to: f32) -> f32 {
+        let (ramp, constant) = self.velocity_change_times(from, to);
+        (from + to) / 2. * (2. * ramp + constant)
+    }
+
+    // from the given speed up to the peak and down to rest
+    fn trip_distance(&self, speed: f32, peak: f32) -> f32 {
+        self.velocity_change_distance(speed, peak) + self.velocity_change_distance(peak, 0.)
+    }
+
+    fn push_velocity_change(&mut self, state: MotionState, to: f32) -> MotionState {
+        let (ramp, constant) = self.velocity_change_times(state.speed, to);
+        let jerk = self.limits.max_jerk * (to - state.speed).signum();
+
+        let state = self.push(state, jerk, ramp);
+        let state = self.push(state, 0., constant);
+        let mut state = self.push(state, -jerk, ramp);
+        // no rounding error left behind
+        state.speed = to;
+        state.accel = 0.;
+        state
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> MotionLimits {
+        MotionLimits { max_speed: 1., max_accel: 1., max_jerk: 1. }
+    }
+
+    // runs the profile to the end, checks the limits on the way
+    fn run(profile: &mut MotionProfile) -> Vec<MotionState> {
+        let mut states = vec![profile.reference()];
+        while !profile.is_finished() {
+            let state = profile.update(0.001);
+            let previous = states[states.len()-1];
+            assert!(state.speed.abs() <= profile.limits().max_speed + 1e-3);
+            assert!(state.accel.abs() <= profile.limits().max_accel + 1e-3);
+            assert!((state.accel - previous.accel).abs() <= profile.limits().max_jerk * 0.001 + 1e-3);
+            states.push(state);
+        }
+        states
+    }
+
+    #[test]
+    fn rest_to_rest() {
+        let mut profile = MotionProfile::new(limits(), 0.);
+        profile.set_target(3.);
+        // 2 s to reach the max speed, 1 m of cruise, 2 s to stop
+        assert!((profile.duration() - 5.).abs() < 1e-3);
+
+        let states = run(&mut profile);
+        let peak = states.iter().map(|state| state.speed).fold(0., f32::max);
+        assert!((peak - 1.).abs() < 1e-3);
+        let last = profile.reference();
+        assert!((last.position - 3.).abs() < 1e-3);
+        assert!(last.speed == 0.);
+    }
+
+    #[test]
+    fn stopping_position() {
+        let mut profile = MotionProfile::new(limits(), 0.);
+        profile.set_target(10.);
+        for _ in 0..4000 {
+            profile.update(0.001);
+        }
+        // cruising at 1 m/s, the stop takes 2 s and 1 m
+        let reference = profile.reference();
+        assert!((profile.stopping_position(reference) - reference.position - 1.).abs() < 1e-3);
+
+        // a new target there is reached without overshoot
+        let stop = profile.stopping_position(reference);
+        profile.set_target(stop);
+        let states = run(&mut profile);
+        assert!(states.iter().all(|state| state.position <= stop + 1e-3 && state.speed >= -1e-3));
+    }
+
+    #[test]
+    fn short_trip() {
+        let mut profile = MotionProfile::new(limits(), 6.);
+        profile.set_target(5.8);
+        let states = run(&mut profile);
+        // too short to reach the max speed
+        let peak = states.iter().map(|state| state.speed.abs()).fold(0., f32::max);
+        assert!(peak < 0.5);
+        assert!((profile.reference().position - 5.8).abs() < 1e-3);
+    }
+
+    #[test]
+    fn replan_mid_flight() {
+        let mut profile = MotionProfile::new(limits(), 0.);
+        profile.set_target(9.);
+        for _ in 0..1500 {
+            profile.update(0.001);
+        }
+        let before = profile.reference();
+        assert!(before.accel > 0.);
+
+        // the target moves closer while the car is still accelerating
+        profile.set_target(3.);
+        let states = run(&mut profile);
+        assert!((states[1].speed - before.speed).abs() < 1e-2);
+        assert!((profile.reference().position - 3.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn replan_behind() {
+        let mut profile = MotionProfile::new(limits(), 0.);
+        profile.set_target(9.);
+        for _ in 0..4000 {
+            profile.update(0.001);
+        }
+        // the new target is already passed, stop and come back
+        profile.set_target(1.);
+        let states = run(&mut profile);
+        assert!(states.iter().any(|state| state.speed < 0.));
+        assert!((profile.reference().position - 1.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn replan_near_max_speed() {
+        // the target is moved on every 10 ms while the car speeds up, the
+        // acceleration is cut in every state of the jerk ramp
+        for steps in (1000..2000).step_by(10) {
+            let mut profile = MotionProfile::new(limits(), 0.);
+            profile.set_target(9.);
+            for _ in 0..steps {
+                profile.update(0.001);
+            }
+            profile.set_target(9.5);
+            run(&mut profile);
+            assert!((profile.reference().position - 9.5).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn lower_speed_limit() {
+        let mut profile = MotionProfile::new(limits(), 0.);
+        profile.set_target(9.);
+        for _ in 0..1000 {
+            profile.update(0.001);
+        }
+        // 0.5 m/s at 1 m/s^2, the acceleration can not be cut before 1 m/s
+        profile.set_limits(MotionLimits { max_speed: 0.6, ..limits() });
+        let mut states = Vec::new();
+        while !profile.is_finished() {
+            states.push(profile.update(0.001));
+        }
+        let peak = states.iter().map(|state| state.speed).fold(0., f32::max);
+        assert!(peak <= 1. + 1e-3);
+
+        // then it slows down and cruises under the new limit
+        let slowed = states.iter().position(|state| state.speed < 0.6 && state.accel < 0.).unwrap();
+        assert!(states[slowed..].iter().all(|state| state.speed <= 0.6 + 1e-3));
+        assert!(states.iter().filter(|state| (state.speed - 0.6).abs() < 1e-3 && state.accel == 0.).count() > 1000);
+        assert!((profile.reference().position - 9.).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Dynamic DC motor model, the alternative of the static MotorSamples lookup.
+// Armature:   L di/dt = V - R i - ke w
+// Rotor:      J dw/dt = kt i - b w - load torque
+// The drive in front of the motor is an ideal current regulator, it puts the
+// voltage for the commanded current on the armature but can not go over the
+// supply voltage, so the back emf limits the speed.
+
+use std::error::Error;
+use std::f32::consts::PI;
+
+use super::motor_samples::MotorSamples;
+use super::motor_parameters::DcMotorOverrides;
+
+// time constants that can not be seen on a steady state curve, used when the
+// yaml does not give the inductance or the rotor inertia
+const ELECTRICAL_TIME_CONSTANT: f32 = 0.01;
+const MECHANICAL_TIME_CONSTANT: f32 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DcMotorParameters {
+    pub supply_voltage: f32,
+    pub resistance: f32,      // ohm
+    pub inductance: f32,      // henry
+    pub torque_constant: f32, // Nm/A, same as the back emf constant in V s/rad
+    pub rotor_inertia: f32,   // kg m^2
+    pub viscous_friction: f32, // Nm s/rad
+}
+
+impl DcMotorParameters {
+    // least squares fit on the motor samples, every sample is a steady state
+    // where the rated torque (tnm) is given at the given current and rpm
+    pub fn fit(samples: &[MotorSamples]) -> Result<Self, Box<dyn Error>> {
+        if samples.is_empty() {
+            return Err("no motor samples to fit the dc motor on".into());
+        }
+        let supply_voltage = samples.iter().map(|sample| sample.voltage).sum::<f32>() / samples.len() as f32;
+        // same torque constant as the static model so both give the same force
+        let torque_constant = MotorSamples::get_max_tnm(samples) / MotorSamples::get_max_current(samples);
+
+        // V - ke w = R i
+        let mut num = 0.;
+        let mut den = 0.;
+        for sample in samples {
+            let w = sample.rpm * 2. * PI / 60.;
+            num += sample.current * (sample.voltage - torque_constant * w);
+            den += sample.current * sample.current;
+        }
+        let resistance = num / den;
+
+        // kt i - T = b w, friction can not be negative, when the curve can not
+        // explain any friction it stays zero
+        let mut num = 0.;
+        let mut den = 0.;
+        for sample in samples {
+            let w = sample.rpm * 2. * PI / 60.;
+            num += w * (torque_constant * sample.current - sample.tnm);
+            den += w * w;
+        }
+        let viscous_friction = if den > 0. { (num / den).max(0.) } else { 0. };
+
+        let inductance = resistance * ELECTRICAL_TIME_CONSTANT;
+        let rotor_inertia = MECHANICAL_TIME_CONSTANT * torque_constant * torque_constant / resistance;
+
+        Self {
+            supply_voltage,
+            resistance,
+            inductance,
+            torque_constant,
+            rotor_inertia,
+            viscous_friction,
+        }.check()
+    }
+
+    pub fn with_overrides(mut self, overrides: &DcMotorOverrides) -> Result<Self, Box<dyn Error>> {
+        if let Some(resistance) = overrides.resistance { self.resistance = resistance; }
+        if let Some(inductance) = overrides.inductance { self.inductance = inductance; }
+        if let Some(torque_constant) = overrides.torque_constant { self.torque_constant = torque_constant; }
+        if let Some(rotor_inertia) = overrides.rotor_inertia { self.rotor_inertia = rotor_inertia; }
+        if let Some(viscous_friction) = overrides.viscous_friction { self.viscous_friction = viscous_friction; }
+        if let Some(supply_voltage) = overrides.supply_voltage { self.supply_voltage = supply_voltage; }
+        self.check()
+    }
+
+    // the model divides by these, they have to be positive
+    fn check(self) -> Result<Self, Box<dyn Error>> {
+        let values = [
+            ("resistance", self.resistance),
+            ("inductance", self.inductance),
+            ("rotor inertia", self.rotor_inertia),
+        ];
+        for (name, value) in values {
+            if value <= 0. || value.is_nan() {
+                return Err(format!("the dc motor {} {} is not positive", name, value).into());
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DcMotor {
+    pub parameters: DcMotorParameters,
+    pub current: f32, // armature current
+    pub voltage: f32, // armature voltage given by the drive
+    pub speed: f32,   // rad/s
+}
+
+impl DcMotor {
+    pub fn new(parameters: DcMotorParameters) -> Self {
+        Self {
+            parameters,
+            current: 0.,
+            voltage: 0.,
+            speed: 0.,
+        }
+    }
+
+    pub fn get_rpm(&self) -> f32 {
+        self.speed * 60. / (2. * PI)
+    }
+
+    pub fn set_rpm(&mut self, rpm: f32) {
+        self.speed = rpm * 2. * PI / 60.;
+    }
+
+    pub fn get_torque(&self) -> f32 {
+        self.parameters.torque_constant * self.current - self.parameters.viscous_friction * self.speed
+    }
+
+    // electrical power taken from the supply, in kW like kwp_in of the samples
+    pub fn get_input_power(&self) -> f32 {
+        self.voltage * self.current / 1000.
+    }
+
+    pub fn get_back_emf(&self) -> f32 {
+        self.parameters.torque_constant * self.speed
+    }
+
+    // ideal current regulating drive
+    pub fn step(&mut self, current_reference: f32, load_torque: f32, delta_time: f32) {
+        // the drive asks for the voltage of the reference current
+        let voltage = self.parameters.resistance * current_reference + self.get_back_emf();
+        self.step_voltage(voltage, load_torque, delta_time);
+    }
+
+    // the armature voltage is given by a current controller, limited by the supply
+    pub fn step_voltage(&mut self, voltage: f32, load_torque: f32, delta_time: f32) {
+        let p = self.parameters;
+        let back_emf = self.get_back_emf();
+        self.voltage = voltage.clamp(-p.supply_voltage, p.supply_voltage);
+
+        // exact solution of the armature equation with constant voltage and speed,
+        // stays stable even when the step is longer than L/R
+        let steady_current = (self.voltage - back_emf) / p.resistance;
+        let decay = (-delta_time * p.resistance / p.inductance).exp();
+        self.current = steady_current + (self.current - steady_current) * decay;
+
+        let accel = (self.get_torque() - load_torque) / p.rotor_inertia;
+        self.speed += accel * delta_time;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fitted() -> DcMotorParameters {
+        let samples = MotorSamples::from_file("data/motor_samples.csv").unwrap();
+        DcMotorParameters::fit(&samples).unwrap()
+    }
+
+    #[test]
+    fn fit_is_physical() {
+        let parameters = fitted();
+        assert!(parameters.supply_voltage == 450.);
+        assert!(parameters.resistance > 0.);
+        assert!(parameters.inductance > 0.);
+        assert!(parameters.rotor_inertia > 0.);
+        assert!(parameters.viscous_friction >= 0.);
+        assert!((parameters.torque_constant * 61.51879 - 785.).abs() < 0.1);
+    }
+
+    #[test]
+    fn overrides() {
+        let overrides = DcMotorOverrides { rotor_inertia: Some(3.), ..Default::default() };
+        let parameters = fitted().with_overrides(&overrides).unwrap();
+        assert!(parameters.rotor_inertia == 3.);
+        assert!(parameters.resistance == fitted().resistance);
+    }
+
+    #[test]
+    fn rejects_unphysical_parameters() {
+        assert!(DcMotorParameters::fit(&[]).is_err());
+        for overrides in [
+            DcMotorOverrides { resistance: Some(0.), ..Default::default() },
+            DcMotorOverrides { inductance: Some(-1.), ..Default::default() },
+            DcMotorOverrides { rotor_inertia: Some(0.), ..Default::default() },
+        ] {
+            assert!(fitted().with_overrides(&overrides).is_err());
+        }
+    }
+
+    #[test]
+    fn has_inertia() {
+        let mut motor = DcMotor::new(fitted());
+        motor.step(20., 0., 0.01);
+        let first_step = motor.get_rpm();
+        for _ in 0..100 {
+            motor.step(20., 0., 0.01);
+        }
+        // does not jump to a steady speed, keeps accelerating
+        assert!(first_step > 0.);
+        assert!(motor.get_rpm() > first_step * 10.);
+    }
+
+    #[test]
+    fn back_emf_limits_speed() {
+        let parameters = fitted();
+        let mut motor = DcMotor::new(parameters);
+        for _ in 0..10000 {
+            motor.step(50., 0., 0.01);
+        }
+        let no_load_speed = parameters.supply_voltage / parameters.torque_constant;
+        assert!(motor.speed <= no_load_speed * 1.001);
+        assert!(motor.voltage == parameters.supply_voltage);
+    }
+}
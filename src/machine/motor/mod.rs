@@ -1,9 +1,13 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2024 Tuna Gül
 
+#[allow(clippy::module_inception)]
 pub mod motor;
 mod motor_samples;
-mod motor_parameters;
+pub mod motor_parameters;
+pub mod dc_motor;
 
 pub use motor::ElevatorMotor;
+pub use motor_parameters::{MotorModel, MotorParameters};
+pub use motor_samples::Quadrant;
 // pub use motor_samples::MotorProperties;
\ No newline at end of file
@@ -2,18 +2,29 @@
 // Copyright (C) 2024 Tuna Gül
 
 use std::error::Error;
+use std::f32::consts::PI;
 
 use crate::machine::pid_controller::PIDController;
-use super::motor_samples::MotorSamples;
-use super::motor_parameters::MotorParameters;
+use crate::machine::controllers::{Controller, ControllerParameters};
+use super::motor_samples::{MotorSamples, Quadrant};
+use super::motor_parameters::{MotorModel, MotorParameters};
+use super::dc_motor::{DcMotor, DcMotorParameters};
 
 pub struct ElevatorMotor {
     motor_samples: Vec<MotorSamples>,
     current_properties: MotorSamples,
     gearbox_ratio: f32,
+    output_shaft_radius: f32, // radius of the pulley on the gearbox output shaft, in meters
     current_speed: f32,
-    speed_pid: PIDController,
-    pub total_energy_used: f32,
+    speed_controller: Box<dyn Controller>, // speed loop, gives the current reference
+    current_pid: Option<PIDController>, // current loop, gives the armature voltage
+    current_limit: f32,
+    torque_constant: f32, // Nm per ampere
+    feed_forward_current: f32,
+    dc_motor: Option<DcMotor>, // only with the dynamic model
+    quadrant: Quadrant,
+    pub total_energy_used: f32,      // taken from the supply, kW s
+    pub total_energy_recovered: f32, // given back to the supply while generating, kW s
     pub max_force: f32, // max force of the gearbox output shaft
 }
 
@@ -29,7 +40,9 @@ impl ElevatorMotor {
         parameters: MotorParameters,
     ) -> Result<Self, Box<dyn Error>> {
 
-        let mut speed_pid: PIDController = PIDController::from_parameters(parameters.pid_parameters);        
+        let mut speed_controller = parameters.speed_controller
+            .unwrap_or(ControllerParameters::Pid(parameters.pid_parameters))
+            .build();
         let motor_samples = MotorSamples::from_file(parameters.sample_path.as_str())?;
 
         // hız limitleri
@@ -44,17 +57,43 @@ impl ElevatorMotor {
         let current_limit = max_current.min(max_soft_current);
 
         // hız ve akım sınırlarını belirle
-        speed_pid.set_output_limits(
+        speed_controller.set_output_limits(
             -current_limit, 
             current_limit, 
         );
-        speed_pid.set_target_limits(
-            rpm_limit, 
-            -rpm_limit,
+        speed_controller.set_target_limits(
+            -rpm_limit, 
+            rpm_limit,
         );
 
+        // the samples give the rated torque at the max current, torque grows
+        // linearly with the current
+        let torque_constant = MotorSamples::get_max_tnm(&motor_samples) / max_current;
+
         let max_torque = MotorSamples::get_max_tnm(&motor_samples) * parameters.gearbox_ratio;
-        let max_force =  max_torque * parameters.output_shaft_radius;
+        // force on the rope = torque on the output shaft / pulley radius
+        let max_force =  max_torque / parameters.output_shaft_radius;
+
+        let dc_motor = match parameters.model {
+            MotorModel::Static => None,
+            MotorModel::Dynamic => {
+                let dc_parameters = DcMotorParameters::fit(&motor_samples)?
+                    .with_overrides(&parameters.dc_motor)?;
+                Some(DcMotor::new(dc_parameters))
+            },
+        };
+
+        // the static model reaches the current at once, a current loop only
+        // makes sense for the dynamic one
+        let current_pid = match (&dc_motor, parameters.current_pid_parameters) {
+            (Some(dc_motor), Some(current_pid_parameters)) => {
+                let mut current_pid = PIDController::from_parameters(current_pid_parameters);
+                let supply_voltage = dc_motor.parameters.supply_voltage;
+                current_pid.set_output_limits(-supply_voltage, supply_voltage);
+                Some(current_pid)
+            },
+            _ => None,
+        };
 
         // I am unwrapping here because i know it will not panic
         let current_properties = MotorSamples::simulate_properties_from_current(&motor_samples, 0.)
@@ -64,48 +103,220 @@ impl ElevatorMotor {
             Self {
                 motor_samples,
                 gearbox_ratio: parameters.gearbox_ratio,
+                output_shaft_radius: parameters.output_shaft_radius,
                 current_properties,
-                speed_pid,
+                speed_controller,
+                current_pid,
                 current_speed: 0.0,
+                current_limit,
+                torque_constant,
+                feed_forward_current: 0.0,
+                dc_motor,
+                quadrant: Quadrant::ForwardMotoring,
                 total_energy_used: 0.0,
+                total_energy_recovered: 0.0,
                 max_force,
             }
         )
     }
 
+    pub fn model(&self) -> MotorModel {
+        match self.dc_motor {
+            Some(_) => MotorModel::Dynamic,
+            None => MotorModel::Static,
+        }
+    }
+
+    // speed of the motor shaft, the speed pid works with this one
+    pub fn get_motor_rpm(&self) -> f32 {
+        self.current_speed
+    }
+
     pub fn get_current_speed(&self) -> f32 {
         // this function gives the speed of the output shaft of the gear box
         self.current_speed / self.gearbox_ratio
     }
 
+    // kasnak çevre hızı, the speed of the rope on the pulley in m/s
+    pub fn get_linear_speed(&self) -> f32 {
+        self.rpm_to_linear(self.get_current_speed())
+    }
+
+    pub fn rpm_to_linear(&self, rpm: f32) -> f32 {
+        rpm * 2. * PI * self.output_shaft_radius / 60.
+    }
+
+    pub fn linear_to_rpm(&self, speed: f32) -> f32 {
+        speed * 60. / (2. * PI * self.output_shaft_radius)
+    }
+
+    // the motor is tied to the car with the rope, when the car can not follow
+    // the motor (not enough force) the car drags the motor with it
+    pub fn sync_linear_speed(&mut self, speed: f32) {
+        self.current_speed = self.linear_to_rpm(speed) * self.gearbox_ratio;
+        if let Some(dc_motor) = &mut self.dc_motor {
+            dc_motor.set_rpm(self.current_speed);
+        }
+        self.update_operating_point();
+    }
+
+    // the car may turn the motor against its torque, find the quadrant with
+    // the speed the motor really has
+    fn update_operating_point(&mut self) {
+        match &self.dc_motor {
+            Some(dc_motor) => {
+                self.quadrant = Quadrant::from_current_and_rpm(dc_motor.current, dc_motor.get_rpm());
+            },
+            None => {
+                let (properties, quadrant) = MotorSamples::simulate_properties(
+                    &self.motor_samples,
+                    self.current_properties.current,
+                    self.current_speed,
+                ).expect("Motor current limit exceeded");
+                self.current_properties = properties;
+                self.quadrant = quadrant;
+            },
+        }
+    }
+
+    pub fn get_quadrant(&self) -> Quadrant {
+        self.quadrant
+    }
+
+    // armature current of the motor right now
+    pub fn get_current(&self) -> f32 {
+        match &self.dc_motor {
+            Some(dc_motor) => dc_motor.current,
+            None => self.current_properties.current,
+        }
+    }
+
+    // force the motor puts on the rope with the current it is given right now
+    pub fn get_shaft_force(&self) -> f32 {
+        let torque = match &self.dc_motor {
+            Some(dc_motor) => dc_motor.get_torque(),
+            None => self.current_properties.current * self.torque_constant,
+        };
+        torque * self.gearbox_ratio / self.output_shaft_radius
+    }
+
+    // current limit of the drive, the smaller of the largest sample
+    // (MotorSamples::get_max_current) and the soft limit
+    pub fn get_current_limit(&self) -> f32 {
+        self.current_limit
+    }
+
+    // force on the rope with the current limit
+    pub fn get_force_limit(&self) -> f32 {
+        self.current_limit * self.torque_constant * self.gearbox_ratio / self.output_shaft_radius
+    }
+
+    // the rotor inertia seen from the rope as if it was a mass moving with the car
+    pub fn get_reflected_mass(&self) -> f32 {
+        match &self.dc_motor {
+            Some(dc_motor) => {
+                let ratio = self.gearbox_ratio / self.output_shaft_radius;
+                dc_motor.parameters.rotor_inertia * ratio * ratio
+            },
+            None => 0.,
+        }
+    }
+
+    // power taken from the supply in kW, negative while generating
+    pub fn get_input_power(&self) -> f32 {
+        match &self.dc_motor {
+            Some(dc_motor) => dc_motor.get_input_power(),
+            None => self.current_properties.kwp_in,
+        }
+    }
+
+    // the force the load needs is known from the load weighing device, the
+    // current for it is given directly and the pid only corrects the rest
+    pub fn set_feed_forward_force(&mut self, force: f32) {
+        let torque = force * self.output_shaft_radius / self.gearbox_ratio;
+        self.feed_forward_current = torque / self.torque_constant;
+    }
+
+    pub fn set_target_linear_speed(&mut self, target: f32) -> bool {
+        self.set_target_speed(self.linear_to_rpm(target))
+    }
+
     pub fn set_target_speed(&mut self, target: f32) -> bool {
         // this function sets the speed of the output shaft of the gear box
         let motor_target = target*self.gearbox_ratio;
 
-        // rpm limit is applied in pid controller
-        self.speed_pid.set_target(motor_target)
+        // rpm limit is applied in the speed controller
+        self.speed_controller.set_target(motor_target)
     }
 
     pub fn get_total_energy_used(&self) -> f32 {
         self.total_energy_used
     }
 
+    pub fn get_total_energy_recovered(&self) -> f32 {
+        self.total_energy_recovered
+    }
+
+    // consumed minus recovered
+    pub fn get_net_energy(&self) -> f32 {
+        self.total_energy_used - self.total_energy_recovered
+    }
+
+    // the tuners change the gains of the speed loop in place
+    pub fn speed_controller_mut(&mut self) -> &mut dyn Controller {
+        self.speed_controller.as_mut()
+    }
+
     pub fn has_reached_target(&self) -> bool {
-        self.speed_pid.has_reached_target(self.current_speed)
+        self.speed_controller.has_reached_target(self.current_speed)
     }
 
-    fn give_current(&mut self, current: f32) {
+    fn give_current(&mut self, current: f32, delta_time: f32) {
         self.current_properties = MotorSamples::simulate_properties_from_current(&self.motor_samples, current)
             .expect("Motor current limit exceeded");
 
-        self.current_speed = self.current_properties.rpm;
+        match (&mut self.dc_motor, &mut self.current_pid) {
+            // the current loop runs at its own rate, usually faster than the
+            // simulation step, so the step is divided
+            (Some(dc_motor), Some(current_pid)) => {
+                let sub_steps = (delta_time * current_pid.update_freq()).round().max(1.);
+                let sub_step = delta_time / sub_steps;
+                current_pid.set_target(current);
+                for _ in 0..sub_steps as usize {
+                    // back emf is known from the speed, the pid only corrects the rest
+                    let voltage = current_pid.update(dc_motor.current, sub_step) + dc_motor.get_back_emf();
+                    dc_motor.step_voltage(voltage, 0., sub_step);
+                }
+                self.current_speed = dc_motor.get_rpm();
+            },
+            // the current is the reference of an ideal drive, the motor gets there in time
+            (Some(dc_motor), None) => {
+                dc_motor.step(current, 0., delta_time);
+                self.current_speed = dc_motor.get_rpm();
+            },
+            // steady state is reached immediately
+            (None, _) => self.current_speed = self.current_properties.rpm,
+        }
+        self.update_operating_point();
     }
 
     pub fn update(&mut self, delta_time: f32) {
-        self.total_energy_used += self.current_properties.kwp_in * delta_time;
+        let speed_output = self.speed_controller.update(self.current_speed, delta_time);
+        self.update_with_current(speed_output, delta_time);
+    }
+
+    // the speed loop is opened, the given current replaces the output of the
+    // speed controller (used by the tuners), the feed forward current is still added
+    pub fn update_with_current(&mut self, current: f32, delta_time: f32) {
+        let power = self.get_input_power();
+        if power >= 0. {
+            self.total_energy_used += power * delta_time;
+        } else {
+            self.total_energy_recovered -= power * delta_time;
+        }
 
-        let new_current = self.speed_pid.update(self.current_speed, delta_time);
-        self.give_current(new_current);
+        let new_current = current + self.feed_forward_current;
+        self.give_current(new_current.clamp(-self.current_limit, self.current_limit), delta_time);
     }
 }
 
@@ -116,43 +327,43 @@ mod tests {
     use super::*;
     use std::time::Instant;
 
-    #[test]
-    fn give_current() {
-        let mut motor = ElevatorMotor::from_file("param/motor_parameters.yaml").unwrap();
+    // every test runs against both motor models
+    fn new_motor(model: MotorModel) -> ElevatorMotor {
+        let mut parameters = MotorParameters::from_file("param/motor_parameters.yaml").unwrap();
+        parameters.model = model;
+        ElevatorMotor::new(parameters).unwrap()
+    }
+
+    fn check_give_current(model: MotorModel) {
+        let mut motor = new_motor(model);
         let max_current = MotorSamples::get_max_current(&motor.motor_samples);
-        motor.give_current(max_current-5.);
+        motor.give_current(max_current-5., 0.01);
         assert!(motor.current_speed > 0.);
     }
 
-    #[test]
-    fn give_negative_current() {
-        let mut motor = ElevatorMotor::from_file("param/motor_parameters.yaml").unwrap();
+    fn check_give_negative_current(model: MotorModel) {
+        let mut motor = new_motor(model);
         let max_current = MotorSamples::get_max_current(&motor.motor_samples);
-        motor.give_current(-max_current+5.);
+        motor.give_current(-max_current+5., 0.01);
         assert!(motor.current_speed < 0.);
     }
 
-    #[test]
-    #[should_panic]
-    fn overcurrent() {
-        let mut motor = ElevatorMotor::from_file("param/motor_parameters.yaml").unwrap();
+    fn check_overcurrent(model: MotorModel) {
+        let mut motor = new_motor(model);
         let max_current = MotorSamples::get_max_current(&motor.motor_samples);
-        motor.give_current(max_current+5.);
+        motor.give_current(max_current+5., 0.01);
     }
 
-    #[test]
-    #[should_panic]
-    fn negative_overcurrent() {
-        let mut motor = ElevatorMotor::from_file("param/motor_parameters.yaml").unwrap();
+    fn check_negative_overcurrent(model: MotorModel) {
+        let mut motor = new_motor(model);
         let max_current = MotorSamples::get_max_current(&motor.motor_samples);
-        motor.give_current(-max_current-5.);
+        motor.give_current(-max_current-5., 0.01);
     }
 
-    #[test]
-    fn set_speed() {
-        let mut motor = ElevatorMotor::from_file("param/motor_parameters.yaml").unwrap();
+    fn check_set_speed(model: MotorModel, direction: f32) {
+        let mut motor = new_motor(model);
         let max_rpm = MotorSamples::get_max_rpm(&motor.motor_samples);
-        let target_speed = max_rpm/2.;
+        let target_speed = direction*max_rpm/2.;
 
         motor.set_target_speed(target_speed);
         
@@ -160,7 +371,8 @@ mod tests {
         loop {
             motor.update(0.01);
             if motor.has_reached_target() {
-                assert!(motor.current_speed == target_speed);
+                assert!((motor.current_speed - target_speed).abs() < 1.);
+                break;
             }
 
             // time limit
@@ -169,31 +381,123 @@ mod tests {
                 panic!("Timeout");
             }
         }
-        
+    }
+
+    #[test]
+    fn give_current() {
+        check_give_current(MotorModel::Static);
+    }
+
+    #[test]
+    fn give_current_dynamic() {
+        check_give_current(MotorModel::Dynamic);
+    }
+
+    #[test]
+    fn give_negative_current() {
+        check_give_negative_current(MotorModel::Static);
+    }
+
+    #[test]
+    fn give_negative_current_dynamic() {
+        check_give_negative_current(MotorModel::Dynamic);
+    }
+
+    #[test]
+    #[should_panic]
+    fn overcurrent() {
+        check_overcurrent(MotorModel::Static);
+    }
+
+    #[test]
+    #[should_panic]
+    fn overcurrent_dynamic() {
+        check_overcurrent(MotorModel::Dynamic);
+    }
+
+    #[test]
+    #[should_panic]
+    fn negative_overcurrent() {
+        check_negative_overcurrent(MotorModel::Static);
+    }
+
+    #[test]
+    #[should_panic]
+    fn negative_overcurrent_dynamic() {
+        check_negative_overcurrent(MotorModel::Dynamic);
+    }
+
+    #[test]
+    fn set_speed() {
+        check_set_speed(MotorModel::Static, 1.);
+    }
+
+    #[test]
+    fn set_speed_dynamic() {
+        check_set_speed(MotorModel::Dynamic, 1.);
     }
 
     #[test]
     fn set_negative_speed() {
-        let mut motor = ElevatorMotor::from_file("param/motor_parameters.yaml").unwrap();
-        let max_rpm = MotorSamples::get_max_rpm(&motor.motor_samples);
-        let target_speed = -max_rpm/2.;
+        check_set_speed(MotorModel::Static, -1.);
+    }
 
-        motor.set_target_speed(target_speed);
-        
-        let start = Instant::now();
-        loop {
+    #[test]
+    fn set_negative_speed_dynamic() {
+        check_set_speed(MotorModel::Dynamic, -1.);
+    }
+
+    fn check_overhauled_motor_generates(model: MotorModel) {
+        let mut motor = new_motor(model);
+        // the motor pulls upwards with about a third of its torque while the
+        // load drags it down
+        let force = motor.max_force / 3.;
+        for _ in 0..10 {
+            motor.sync_linear_speed(-1.);
+            motor.set_target_linear_speed(-1.);
+            motor.set_feed_forward_force(force);
             motor.update(0.01);
-            if motor.has_reached_target() {
-                assert!(motor.current_speed == target_speed);
-            }
+        }
+        motor.sync_linear_speed(-1.);
+        assert!(motor.get_quadrant() == Quadrant::ReverseGenerating);
+        assert!(motor.get_input_power() < 0.);
+        assert!(motor.get_total_energy_recovered() > 0.);
+        assert!(motor.get_net_energy() < motor.get_total_energy_used());
+    }
 
-            // time limit
-            let elapsed = start.elapsed().as_secs_f32();
-            if elapsed >= 3. {
-                panic!("Timeout");
-            }
+    #[test]
+    fn overhauled_motor_generates() {
+        check_overhauled_motor_generates(MotorModel::Static);
+    }
+
+    #[test]
+    fn overhauled_motor_generates_dynamic() {
+        check_overhauled_motor_generates(MotorModel::Dynamic);
+    }
+
+    #[test]
+    fn current_loop_follows_reference() {
+        let mut motor = new_motor(MotorModel::Dynamic);
+        assert!(motor.current_pid.is_some());
+        for _ in 0..5 {
+            motor.give_current(20., 0.01);
         }
-        
+        assert!((motor.get_current() - 20.).abs() < 1.);
+
+        // the static model reaches the current at once, it has no current loop
+        let motor = new_motor(MotorModel::Static);
+        assert!(motor.current_pid.is_none());
     }
 
+    #[test]
+    fn dynamic_motor_has_inertia() {
+        let mut motor = new_motor(MotorModel::Dynamic);
+        motor.give_current(30., 0.01);
+        let dynamic_speed = motor.current_speed;
+
+        let mut motor = new_motor(MotorModel::Static);
+        motor.give_current(30., 0.01);
+        // the static model jumps to the steady state speed at once
+        assert!(dynamic_speed < motor.current_speed);
+    }
 }
\ No newline at end of file
@@ -3,18 +3,52 @@
 
 // File for parsing the motor parameters like voltage or randomness or pid coefficients from a yaml file
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use crate::machine::pid_controller::PIDParameters;
+use crate::machine::controllers::ControllerParameters;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MotorModel {
+    // steady state values straight from the motor samples
+    #[default]
+    Static,
+    // DC motor with inductance, back emf and rotor inertia (dc_motor.rs)
+    Dynamic,
+}
+
+// parameters of the dynamic model, the ones that are not given are fitted
+// from the motor samples
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DcMotorOverrides {
+    pub supply_voltage: Option<f32>,
+    pub resistance: Option<f32>,
+    pub inductance: Option<f32>,
+    pub torque_constant: Option<f32>,
+    pub rotor_inertia: Option<f32>,
+    pub viscous_friction: Option<f32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MotorParameters {
-    pub pid_parameters: PIDParameters,
+    pub pid_parameters: PIDParameters, // speed loop, rpm -> current
+    // another control law for the speed loop, pid_parameters is used without it
+    #[serde(default)]
+    pub speed_controller: Option<ControllerParameters>,
+    // current loop of the drive, current -> armature voltage. Only the dynamic
+    // model has a current that takes time, without it the drive is ideal
+    #[serde(default)]
+    pub current_pid_parameters: Option<PIDParameters>,
     pub gearbox_ratio: f32,
     pub output_shaft_radius: f32,
     pub sample_path: String,
     pub soft_rpm_limit: f32,
     pub soft_current_limit: f32,
+    #[serde(default)]
+    pub model: MotorModel,
+    #[serde(default)]
+    pub dc_motor: DcMotorOverrides,
 }
 
 impl MotorParameters {
@@ -23,16 +57,74 @@ impl MotorParameters {
         let result = serde_yaml::from_reader(file)?;
         Ok(result)
     }
+
+    // to write the gains found by the tuners back, the comments of the
+    // original file are not kept
+    pub fn to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_fixtures::temp_path;
 
     #[test]
     fn read() {
-        let parameters = MotorParameters::from_file("parameters/motor_parameters.yaml").unwrap();
+        let parameters = MotorParameters::from_file("param/motor_parameters.yaml").unwrap();
         println!("{:?}", parameters);
     }
+
+    #[test]
+    fn write_back_tuned_gains() {
+        let mut parameters = MotorParameters::from_file("param/motor_parameters.yaml").unwrap();
+        parameters.pid_parameters = parameters.pid_parameters.with_gains(0.5, 2., 0.01);
+        parameters.model = MotorModel::Dynamic;
+        parameters.speed_controller = Some(serde_yaml::from_str("
+            type: fuzzy
+            error_scale: 50.
+            rate_scale: 500.
+            output_scale: 50.
+            update_freq: 100.
+            tolerance: 1.
+        ").unwrap());
+
+        let path = temp_path("elevator_motor_parameters.yaml");
+        parameters.to_file(&path).unwrap();
+        let read = MotorParameters::from_file(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(read.pid_parameters.gains() == (0.5, 2., 0.01));
+        assert!(read.model == MotorModel::Dynamic);
+        assert!(matches!(read.speed_controller, Some(ControllerParameters::Fuzzy(_))));
+        let current_gains = read.current_pid_parameters.unwrap().gains();
+        assert!(current_gains == parameters.current_pid_parameters.unwrap().gains());
+        assert!(read.sample_path == parameters.sample_path);
+        assert!(read.output_shaft_radius == parameters.output_shaft_radius);
+    }
+
+    #[test]
+    fn model_defaults_to_static() {
+        let parameters: MotorParameters = serde_yaml::from_str("
+            pid_parameters:
+                kp: 1.
+                ki: 0.
+                kd: 0.
+                integral_limit: 0.
+                update_freq: 100.
+                tolerance: 1.
+            gearbox_ratio: 1.
+            output_shaft_radius: 0.07
+            sample_path: data/motor_samples.csv
+            soft_rpm_limit: 200.
+            soft_current_limit: 50.
+        ").unwrap();
+        assert!(parameters.model == MotorModel::Static);
+        assert!(parameters.dc_motor.resistance.is_none());
+        assert!(parameters.current_pid_parameters.is_none());
+    }
 }
\ No newline at end of file
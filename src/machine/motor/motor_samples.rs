@@ -6,7 +6,36 @@
 use serde::Deserialize;
 use std::error::Error;
 use std::fs::File;
+use std::f32::consts::PI;
+
+
+// the four quadrants of the torque-speed plane. In the generating quadrants
+// the load overhauls the motor (a heavy car going down, an empty car going up
+// with the counterweight pulling) and the motor gives energy back
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Quadrant {
+    ForwardMotoring,   // speed > 0, torque > 0
+    ForwardGenerating, // speed > 0, torque < 0
+    ReverseMotoring,   // speed < 0, torque < 0
+    ReverseGenerating, // speed < 0, torque > 0
+}
+
+impl Quadrant {
+    // the sign of the torque is the sign of the current
+    pub fn from_current_and_rpm(current: f32, rpm: f32) -> Self {
+        if rpm >= 0. {
+            if current >= 0. { Quadrant::ForwardMotoring } else { Quadrant::ForwardGenerating }
+        } else if current <= 0. {
+            Quadrant::ReverseMotoring
+        } else {
+            Quadrant::ReverseGenerating
+        }
+    }
 
+    pub fn is_generating(&self) -> bool {
+        matches!(self, Quadrant::ForwardGenerating | Quadrant::ReverseGenerating)
+    }
+}
 
 #[derive(Debug, Deserialize, Copy, Clone)]
 pub struct MotorSamples {
@@ -37,7 +66,7 @@ impl MotorSamples {
             motor_samples.push(record);
         }
 
-        if motor_samples.len() == 0 {
+        if motor_samples.is_empty() {
             return Err("No motor samples found".into());
         }
 
@@ -49,7 +78,7 @@ impl MotorSamples {
         Ok(motor_samples)
     }
 
-    fn check_properties(samples: &Vec<Self>) -> Result<(), Box<dyn Error>> {
+    fn check_properties(samples: &[Self]) -> Result<(), Box<dyn Error>> {
         // Check if the samples are sorted by current
         for i in 0..samples.len()-1 {
             if samples[i].current > samples[i+1].current {
@@ -72,7 +101,7 @@ impl MotorSamples {
         Ok(())
     }
 
-    pub fn get_max_rpm(samples: &Vec<Self>) -> f32 {
+    pub fn get_max_rpm(samples: &[Self]) -> f32 {
         let mut max_rpm = 0.0;
         for motor_property in samples {
             if motor_property.rpm > max_rpm {
@@ -82,7 +111,7 @@ impl MotorSamples {
         max_rpm
     }
 
-    pub fn get_max_tnm(samples: &Vec<Self>) -> f32 {
+    pub fn get_max_tnm(samples: &[Self]) -> f32 {
         let mut max_tnm = 0.0;
         for motor_property in samples {
             if motor_property.tnm > max_tnm {
@@ -92,7 +121,7 @@ impl MotorSamples {
         max_tnm
     }
 
-    pub fn get_max_current(samples: &Vec<Self>) -> f32 {
+    pub fn get_max_current(samples: &[Self]) -> f32 {
         let mut max_current = 0.0;
         for motor_property in samples {
             if motor_property.current > max_current {
@@ -102,7 +131,7 @@ impl MotorSamples {
         max_current
     }
 
-    pub fn find_smaller_closest(samples: &Vec<Self>, current: f32) -> usize {
+    pub fn find_smaller_closest(samples: &[Self], current: f32) -> usize {
         // verdiğimiz akımdaki datanın bir küçüğünün indexini verir
 
         let mut closest_current = 0.0;
@@ -112,21 +141,30 @@ impl MotorSamples {
                 break;
             }
 
-            if motor_property.current <= current {
-                if current - motor_property.current < current - closest_current {
-                    closest_current = motor_property.current;
-                    closest_idx = index;
-                }
+            if motor_property.current <= current && current - motor_property.current < current - closest_current {
+                closest_current = motor_property.current;
+                closest_idx = index;
             }
         }
         closest_idx
     }
 
-    pub fn simulate_properties_from_current(samples: &Vec<Self>, current: f32) -> Option<MotorSamples> {
-        if current > Self::get_max_current(samples) {
+    pub fn simulate_properties_from_current(samples: &[Self], current: f32) -> Option<MotorSamples> {
+        let max_current = Self::get_max_current(samples);
+        if current > max_current || current < -max_current {
             return None;
         }
 
+        // the samples only cover one direction, the motor turns the other way
+        // with the same characteristics when the current is reversed
+        if current < 0. {
+            let mut properties = Self::simulate_properties_from_current(samples, -current)?;
+            properties.current = current;
+            properties.rpm = -properties.rpm;
+            properties.tnm = -properties.tnm;
+            return Some(properties);
+        }
+
         let matching_idx = MotorSamples::find_smaller_closest(samples, current);
         let mc1 = samples[matching_idx].current;
         let mc2 = samples[matching_idx+1].current;
@@ -163,6 +201,25 @@ impl MotorSamples {
             }
         )
     }
+
+    // four quadrant version, the motor turns with the given rpm (the load may
+    // force it) while it is given the current. kwp_in is negative when the
+    // motor works as a generator and gives power back to the supply
+    pub fn simulate_properties(samples: &[Self], current: f32, rpm: f32) -> Option<(MotorSamples, Quadrant)> {
+        let mut properties = Self::simulate_properties_from_current(samples, current)?;
+        let quadrant = Quadrant::from_current_and_rpm(current, rpm);
+        properties.rpm = rpm;
+
+        if quadrant.is_generating() {
+            // torque grows linearly with the current up to the rated torque
+            let torque = Self::get_max_tnm(samples) * current.abs() / Self::get_max_current(samples);
+            let mechanical_power = torque * (rpm * 2. * PI / 60.).abs() / 1000.;
+            properties.tnm = torque * current.signum();
+            properties.kwp_in = -mechanical_power * properties.efficiency / 100.;
+        }
+
+        Some((properties, quadrant))
+    }
 }
 
 
@@ -249,6 +306,37 @@ mod tests {
         assert!(88.94 < new_property.efficiency && new_property.efficiency < 88.96);
     }
 
+    #[test]
+    fn negative_current_is_mirrored() {
+        let motor_samples = MotorSamples::from_file("data/motor_samples.csv").unwrap();
+        let forward = MotorSamples::simulate_properties_from_current(&motor_samples, 30.).unwrap();
+        let reverse = MotorSamples::simulate_properties_from_current(&motor_samples, -30.).unwrap();
+        assert!(reverse.rpm == -forward.rpm);
+        assert!(reverse.kwp_in == forward.kwp_in);
+        assert!(reverse.efficiency == forward.efficiency);
+    }
+
+    #[test]
+    fn quadrants() {
+        assert!(Quadrant::from_current_and_rpm(10., 100.) == Quadrant::ForwardMotoring);
+        assert!(Quadrant::from_current_and_rpm(-10., 100.) == Quadrant::ForwardGenerating);
+        assert!(Quadrant::from_current_and_rpm(-10., -100.) == Quadrant::ReverseMotoring);
+        assert!(Quadrant::from_current_and_rpm(10., -100.) == Quadrant::ReverseGenerating);
+    }
+
+    #[test]
+    fn generating_gives_power_back() {
+        let motor_samples = MotorSamples::from_file("data/motor_samples.csv").unwrap();
+        // the load turns the motor backwards while it pulls forwards
+        let (properties, quadrant) = MotorSamples::simulate_properties(&motor_samples, 30., -100.).unwrap();
+        assert!(quadrant.is_generating());
+        assert!(properties.kwp_in < 0.);
+
+        let (properties, quadrant) = MotorSamples::simulate_properties(&motor_samples, -30., -100.).unwrap();
+        assert!(!quadrant.is_generating());
+        assert!(properties.kwp_in > 0.);
+    }
+
     #[test]
     fn simulate_properties_efficiency() {
         let motor_samples = MotorSamples::from_file("data/motor_samples.csv").unwrap();
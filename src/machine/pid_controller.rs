@@ -1,11 +1,14 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2024 Tuna Gül
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use super::controllers::Controller;
 
 
 // this struct is for parsing the pid parameters from a yaml file
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PIDParameters {
     kp: f32,
     ki: f32,
@@ -28,6 +31,18 @@ pub struct PIDParameters {
     min_output: f32,
     #[serde(default = "default_change_limit")]
     change_limit: f32,
+
+    #[serde(default)]
+    anti_windup: AntiWindup,
+    // back-calculation tracking time constant, zero means the integral time kp/ki
+    #[serde(default)]
+    tracking_time: f32,
+    // the derivative of the measurement has no kick when the target jumps
+    #[serde(default)]
+    derivative_on_measurement: bool,
+    // time constant of the low-pass filter on the derivative term, zero is no filter
+    #[serde(default)]
+    derivative_filter_time: f32,
 }
 
 fn default_enable_target_limits() -> bool { false }
@@ -39,6 +54,55 @@ fn default_max_output() -> f32 { 0. }
 fn default_change_limit() -> f32 { 0. }
 
 
+impl PIDParameters {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+
+    pub fn to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
+
+    pub fn gains(&self) -> (f32, f32, f32) {
+        (self.kp, self.ki, self.kd)
+    }
+
+    pub fn update_freq(&self) -> f32 {
+        self.update_freq
+    }
+
+    // same parameters with other gains, used by the tuners
+    pub fn with_gains(&self, kp: f32, ki: f32, kd: f32) -> Self {
+        Self { kp, ki, kd, ..self.clone() }
+    }
+
+    pub fn with_limits(&self, change_limit: f32, integral_limit: f32) -> Self {
+        Self { change_limit, integral_limit, ..self.clone() }
+    }
+}
+
+
+// what the integral does while the output is saturated by the output limits
+// or the change limit. integral_limit is applied in every mode
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AntiWindup {
+    // the integral keeps integrating
+    #[default]
+    None,
+    // the integral is held so the output without limits stays in the limits
+    Clamping,
+    // the difference of the limited and unlimited output is fed back to the integral
+    BackCalculation,
+    // no integration while saturated and the error pushes further into the limit
+    ConditionalIntegration,
+}
+
+
 // this is the real thing
 pub struct PIDController {
     pub target: f32,
@@ -53,7 +117,13 @@ pub struct PIDController {
     max_output: f32,
     min_output: f32,
     change_limit: f32,
+    anti_windup: AntiWindup,
+    tracking_time: f32,
+    derivative_on_measurement: bool,
+    derivative_filter_time: f32,
     prev_error: f32,
+    prev_measurement: Option<f32>,
+    filtered_derivative: f32,
     integral: f32,
     prev_output: f32,
     accumulated_time: f32, // to keep at constant frequency
@@ -65,7 +135,7 @@ impl PIDController {
     pub fn from_parameters(
         parameters: PIDParameters,
     ) -> Self {
-        Self::new(
+        let mut controller = Self::new(
             parameters.kp, 
             parameters.ki, 
             parameters.kd, 
@@ -79,9 +149,14 @@ impl PIDController {
             parameters.max_output,
             parameters.min_output,
             parameters.change_limit,
-        )
+        );
+        controller.set_anti_windup(parameters.anti_windup, parameters.tracking_time);
+        controller.set_derivative_on_measurement(parameters.derivative_on_measurement);
+        controller.set_derivative_filter(parameters.derivative_filter_time);
+        controller
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         kp: f32, 
         ki: f32, 
@@ -110,7 +185,13 @@ impl PIDController {
             max_output,
             min_output,
             change_limit,
+            anti_windup: AntiWindup::None,
+            tracking_time: 0.0,
+            derivative_on_measurement: false,
+            derivative_filter_time: 0.0,
             prev_error: 0.0,
+            prev_measurement: None,
+            filtered_derivative: 0.0,
             integral: 0.0,
             prev_output: 0.0,
             accumulated_time: 0.0,
@@ -119,6 +200,10 @@ impl PIDController {
         }
     }
 
+    pub fn update_freq(&self) -> f32 {
+        self.update_freq
+    }
+
     pub fn set_output_limits(&mut self, min_output: f32, max_output: f32) {
         self.enable_output_limits = true;
         self.min_output = min_output;
@@ -130,7 +215,7 @@ impl PIDController {
     }
 
     pub fn set_target_limits(&mut self, min_target: f32, max_target: f32) {
-        self.enable_output_limits = true;
+        self.enable_target_limits = true;
         self.min_target = min_target ;
         self.max_target = max_target ;
     }
@@ -153,6 +238,19 @@ impl PIDController {
         self.kd = kd;
     }
 
+    pub fn set_anti_windup(&mut self, anti_windup: AntiWindup, tracking_time: f32) {
+        self.anti_windup = anti_windup;
+        self.tracking_time = tracking_time;
+    }
+
+    pub fn set_derivative_on_measurement(&mut self, derivative_on_measurement: bool) {
+        self.derivative_on_measurement = derivative_on_measurement;
+    }
+
+    pub fn set_derivative_filter(&mut self, derivative_filter_time: f32) {
+        self.derivative_filter_time = derivative_filter_time;
+    }
+
     pub fn set_integral_limit(&mut self, integral_limit: f32) {
         self.integral_limit = integral_limit;
     }
@@ -173,23 +271,38 @@ impl PIDController {
         }
 
         self.target = target;
-        return true;
+        true
     }
 
     pub fn has_reached_target(&self, current_value: f32) -> bool {
         (self.target - current_value).abs() < self.tolerance
     }
 
+    // forget the history, the target and the parameters stay
+    pub fn reset(&mut self) {
+        self.prev_error = 0.0;
+        self.prev_measurement = None;
+        self.filtered_derivative = 0.0;
+        self.integral = 0.0;
+        self.prev_output = 0.0;
+        self.accumulated_time = 0.0;
+    }
+
     pub fn update(&mut self, current_value: f32, delta_time: f32) -> f32 {
         // Accumulate the time
         self.accumulated_time += delta_time;
 
-        // Check if enough time has passed for an update (based on frequency)
-        if self.accumulated_time < (1.0 / self.update_freq) {
+        // Check if enough time has passed for an update (based on frequency),
+        // a little slack so the rounding of the summed steps does not skip a cycle
+        if self.accumulated_time < (1.0 / self.update_freq) * 0.999 {
             // If not enough time has passed, return the last output
             return self.prev_output;
         }
 
+        // the controller runs at its own frequency, so the time step of this
+        // update is the time accumulated since the last one
+        let delta_time = self.accumulated_time;
+
         // Reset accumulated time after enough time has passed
         self.accumulated_time = 0.0;
 
@@ -198,35 +311,98 @@ impl PIDController {
         // Proportional term
         let proportional = self.kp * error;
 
-        // Integral term with clamping
+        // Integral term, conditional integration decides after the output is known
+        let previous_integral = self.integral;
         self.integral += error * delta_time;
+        self.apply_integral_limit();
 
-        // Apply the integral limit
-        if self.integral_limit != 0. {
-            if self.integral > self.integral_limit {
-                self.integral = self.integral_limit;
-            } else if self.integral < -self.integral_limit {
-                self.integral = -self.integral_limit;
-            }
-        }
-
-        // Calculate the integral contribution to the output
-        let integral = self.ki * self.integral;
-
-
-        // Derivative term
+        // Derivative term, of the error or of the measurement
         let derivative = if delta_time > 0.0 {
-            (error - self.prev_error) / delta_time
+            match (self.derivative_on_measurement, self.prev_measurement) {
+                (true, Some(prev_measurement)) => -(current_value - prev_measurement) / delta_time,
+                (true, None) => 0.0,
+                (false, _) => (error - self.prev_error) / delta_time,
+            }
         } else {
             0.0
         };
-        let derivative = self.kd * derivative;
 
-        // Update previous error for the next cycle
+        // first order low-pass filter on the derivative
+        if self.derivative_filter_time > 0. {
+            let alpha = delta_time / (self.derivative_filter_time + delta_time);
+            self.filtered_derivative += (derivative - self.filtered_derivative) * alpha;
+        } else {
+            self.filtered_derivative = derivative;
+        }
+        let derivative = self.kd * self.filtered_derivative;
+
+        // Update previous error and measurement for the next cycle
         self.prev_error = error;
+        self.prev_measurement = Some(current_value);
 
         // Calculate the output
-        let mut output = proportional + integral + derivative;
+        let unlimited = proportional + self.ki * self.integral + derivative;
+        let mut output = self.limit_output(unlimited, delta_time);
+
+        // Anti-windup
+        if output != unlimited && self.ki != 0. {
+            match self.anti_windup {
+                AntiWindup::None => {},
+                AntiWindup::Clamping => {
+                    // the integral term that puts the unlimited output right on the
+                    // limit, an integral that was already over it is only held
+                    let term = self.ki * self.integral;
+                    let previous_term = self.ki * previous_integral;
+                    let limit_term = output - proportional - derivative;
+                    let term = if unlimited > output {
+                        term.min(limit_term.max(previous_term))
+                    } else {
+                        term.max(limit_term.min(previous_term))
+                    };
+                    self.integral = term / self.ki;
+                },
+                AntiWindup::BackCalculation => {
+                    let tracking_time = if self.tracking_time > 0. {
+                        self.tracking_time
+                    } else {
+                        self.kp.abs() / self.ki.abs()
+                    };
+                    if tracking_time > 0. {
+                        self.integral += (output - unlimited) / self.ki * delta_time / tracking_time;
+                    }
+                },
+                AntiWindup::ConditionalIntegration => {
+                    // the error pushes the output further into the limit
+                    if (unlimited > output) == (error * self.ki > 0.) {
+                        self.integral = previous_integral;
+                    }
+                },
+            }
+            self.apply_integral_limit();
+            output = self.limit_output(proportional + self.ki * self.integral + derivative, delta_time);
+        }
+
+        // Return the output
+        self.prev_output = output;
+        output
+    }
+
+    fn apply_integral_limit(&mut self) {
+        if self.integral_limit != 0. {
+            self.integral = self.integral.clamp(-self.integral_limit, self.integral_limit);
+        }
+    }
+
+    // change limit and output limits
+    fn limit_output(&self, mut output: f32, delta_time: f32) -> f32 {
+        // the change limit is about the output, compare it with the previous output
+        if self.change_limit != 0. && (output - self.prev_output).abs()/delta_time > self.change_limit {
+            if output > self.prev_output {
+                output = self.prev_output + self.change_limit * delta_time;
+            } else {
+                output = self.prev_output - self.change_limit * delta_time;
+            }
+        }
 
         // Check if the output is within limits
         if self.enable_output_limits {
@@ -235,21 +411,158 @@ impl PIDController {
             } else if output > self.max_output {
                 output = self.max_output;
             }
-        } 
-
-        if self.change_limit != 0. {
-            if (output - current_value).abs()/delta_time > self.change_limit {
-                if output > current_value {
-                    output = current_value + self.change_limit * delta_time;
-                } else {
-                    output = current_value - self.change_limit * delta_time;
-                }
-            }
         }
 
-        // Return the output
-        self.prev_output = output;
         output
     }
 
-}
\ No newline at end of file
+}
+
+
+impl Controller for PIDController {
+    fn set_target(&mut self, target: f32) -> bool {
+        PIDController::set_target(self, target)
+    }
+
+    fn target(&self) -> f32 {
+        self.target
+    }
+
+    fn update(&mut self, measurement: f32, delta_time: f32) -> f32 {
+        PIDController::update(self, measurement, delta_time)
+    }
+
+    fn has_reached_target(&self, measurement: f32) -> bool {
+        PIDController::has_reached_target(self, measurement)
+    }
+
+    fn reset(&mut self) {
+        PIDController::reset(self)
+    }
+
+    fn set_output_limits(&mut self, min_output: f32, max_output: f32) {
+        PIDController::set_output_limits(self, min_output, max_output)
+    }
+
+    fn set_target_limits(&mut self, min_target: f32, max_target: f32) {
+        PIDController::set_target_limits(self, min_target, max_target)
+    }
+
+    fn set_change_limit(&mut self, change_limit: f32) {
+        PIDController::set_change_limit(self, change_limit)
+    }
+
+    fn as_pid_mut(&mut self) -> Option<&mut PIDController> {
+        Some(self)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(kp: f32, ki: f32, kd: f32) -> PIDController {
+        PIDController::new(kp, ki, kd, 0., 100., 0.01, false, 0., 0., false, 0., 0., 0.)
+    }
+
+    // first order plant that saturates the controller for a while, then the
+    // target is dropped, returns how long the output stays at the top limit
+    fn windup_recovery(anti_windup: AntiWindup) -> usize {
+        let mut pid = controller(1., 5., 0.);
+        pid.set_output_limits(-1., 1.);
+        pid.set_anti_windup(anti_windup, 0.);
+        pid.set_target(10.);
+
+        let mut value = 0.;
+        for _ in 0..500 {
+            let output = pid.update(value, 0.01);
+            value += (output - value * 0.1) * 0.01;
+        }
+
+        pid.set_target(0.);
+        let mut saturated = 0;
+        for _ in 0..2000 {
+            let output = pid.update(value, 0.01);
+            value += (output - value * 0.1) * 0.01;
+            if output >= 1. {
+                saturated += 1;
+            }
+        }
+        saturated
+    }
+
+    #[test]
+    fn anti_windup() {
+        let wound_up = windup_recovery(AntiWindup::None);
+        assert!(windup_recovery(AntiWindup::Clamping) < wound_up / 2);
+        assert!(windup_recovery(AntiWindup::BackCalculation) < wound_up / 2);
+        assert!(windup_recovery(AntiWindup::ConditionalIntegration) < wound_up / 2);
+    }
+
+    #[test]
+    fn derivative_on_measurement() {
+        let mut on_error = controller(0., 0., 1.);
+        let mut on_measurement = controller(0., 0., 1.);
+        on_measurement.set_derivative_on_measurement(true);
+        on_error.update(0., 0.01);
+        on_measurement.update(0., 0.01);
+
+        // the target jumps while the measurement does not move
+        on_error.set_target(1.);
+        on_measurement.set_target(1.);
+        assert!(on_error.update(0., 0.01) > 50.);
+        assert!(on_measurement.update(0., 0.01) == 0.);
+    }
+
+    #[test]
+    fn derivative_without_time_step() {
+        // an infinite update frequency runs the controller on a zero step too
+        for on_measurement in [false, true] {
+            let mut pid = PIDController::new(1., 0., 1., 0., f32::INFINITY, 0.01, false, 0., 0., false, 0., 0., 0.);
+            pid.set_derivative_on_measurement(on_measurement);
+            pid.set_target(1.);
+            pid.update(0., 0.);
+            assert!(pid.update(0.5, 0.) == 0.5);
+        }
+    }
+
+    #[test]
+    fn derivative_filter() {
+        let mut raw = controller(0., 0., 1.);
+        let mut filtered = controller(0., 0., 1.);
+        filtered.set_derivative_filter(0.1);
+
+        // noisy measurement around zero
+        let mut raw_peak: f32 = 0.;
+        let mut filtered_peak: f32 = 0.;
+        for i in 0..100 {
+            let noise = if i % 2 == 0 { 0.01 } else { -0.01 };
+            raw_peak = raw_peak.max(raw.update(noise, 0.01).abs());
+            filtered_peak = filtered_peak.max(filtered.update(noise, 0.01).abs());
+        }
+        assert!(filtered_peak < raw_peak / 5.);
+    }
+
+    #[test]
+    fn parameters_from_yaml() {
+        let parameters: PIDParameters = serde_yaml::from_str("
+            kp: 1.
+            ki: 1.
+            kd: 0.1
+            integral_limit: 0.
+            update_freq: 100.
+            tolerance: 0.01
+            anti_windup: back_calculation
+            tracking_time: 0.5
+            derivative_on_measurement: true
+            derivative_filter_time: 0.02
+        ").unwrap();
+        assert!(parameters.anti_windup == AntiWindup::BackCalculation);
+
+        let pid = PIDController::from_parameters(parameters);
+        assert!(pid.tracking_time == 0.5);
+        assert!(pid.derivative_on_measurement);
+        assert!(pid.derivative_filter_time == 0.02);
+    }
+}
@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Simulated time source of the elevator system. Every elevator, motor and pid
+// controller is advanced with the delta time handed out by this clock instead
+// of reading the wall clock, so the same scenario always replays the same way
+// and the simulation runs as fast as the cpu allows.
+
+pub const DEFAULT_DELTA_TIME: f32 = 0.01;
+
+#[derive(Debug, Clone)]
+pub struct SimClock {
+    elapsed: f64, // f64 because a simulated day does not fit well into f32
+    fixed_delta_time: f32,
+    time_multiplier: f32,
+    ticks: u64,
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        Self::new(DEFAULT_DELTA_TIME, 1.0)
+    }
+}
+
+impl SimClock {
+    pub fn new(fixed_delta_time: f32, time_multiplier: f32) -> Self {
+        Self {
+            elapsed: 0.0,
+            fixed_delta_time,
+            time_multiplier,
+            ticks: 0,
+        }
+    }
+
+    pub fn fixed_delta_time(&self) -> f32 {
+        self.fixed_delta_time
+    }
+
+    pub fn time_multiplier(&self) -> f32 {
+        self.time_multiplier
+    }
+
+    pub fn set_time_multiplier(&mut self, time_multiplier: f32) {
+        self.time_multiplier = time_multiplier;
+    }
+
+    // simulated seconds since the start of the simulation
+    pub fn now(&self) -> f64 {
+        self.elapsed
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // advance the clock by the fixed delta time, returns the simulated delta time
+    pub fn tick(&mut self) -> f32 {
+        self.advance(self.fixed_delta_time)
+    }
+
+    // advance the clock by a caller supplied delta time, the time multiplier
+    // is applied the same way it used to be applied to the wall clock time
+    pub fn advance(&mut self, delta_time: f32) -> f32 {
+        let delta_time = delta_time * self.time_multiplier;
+        self.elapsed += delta_time as f64;
+        self.ticks += 1;
+
+        delta_time
+    }
+
+    // jump to the given simulated time, used by the event engine. The time is
+    // already simulated time so the time multiplier is not applied here
+    pub fn advance_to(&mut self, time: f64) -> f32 {
+        let delta_time = (time - self.elapsed).max(0.0) as f32;
+        self.elapsed = self.elapsed.max(time);
+        self.ticks += 1;
+
+        delta_time
+    }
+
+    pub fn reset(&mut self) {
+        self.elapsed = 0.0;
+        self.ticks = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tick() {
+        let mut clock = SimClock::new(0.5, 1.0);
+        assert!(clock.tick() == 0.5);
+        assert!(clock.tick() == 0.5);
+        assert!(clock.now() == 1.0);
+        assert!(clock.ticks() == 2);
+    }
+
+    #[test]
+    fn time_multiplier() {
+        let mut clock = SimClock::new(0.1, 10.0);
+        let delta_time = clock.tick();
+        assert!((delta_time - 1.0).abs() < 1e-6);
+
+        let delta_time = clock.advance(0.5);
+        assert!((delta_time - 5.0).abs() < 1e-6);
+        assert!((clock.now() - 6.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn advance_to() {
+        let mut clock = SimClock::new(0.1, 10.0);
+        let delta_time = clock.advance_to(2.5);
+        assert!(delta_time == 2.5);
+        // time never goes backwards
+        assert!(clock.advance_to(1.0) == 0.0);
+        assert!(clock.now() == 2.5);
+    }
+
+    #[test]
+    fn reset() {
+        let mut clock = SimClock::default();
+        clock.tick();
+        clock.reset();
+        assert!(clock.now() == 0.0);
+        assert!(clock.ticks() == 0);
+    }
+}
@@ -0,0 +1,467 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Automatic pid tuning in the simulator.
+// Relay test: the input switches between bias + amplitude and bias - amplitude
+// every time the measurement crosses the setpoint. The loop starts to oscillate
+// at its ultimate period, and the ultimate gain comes from the describing
+// function of the relay, Ku = 4 d / (pi a).
+// Step test: the input jumps from the bias and a first order plus dead time
+// model is fitted on the response (28.3% and 63.2% points).
+// The ultimate point is tuned with the Ziegler-Nichols family of rules, the
+// model with Cohen-Coon and the open loop Ziegler-Nichols rules.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::f32::consts::PI;
+
+use crate::machine::pid_controller::PIDParameters;
+use super::plant::Plant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TuningRule {
+    ZieglerNicholsP,
+    ZieglerNicholsPI,
+    ZieglerNicholsPID,
+    TyreusLuybenPI,
+    TyreusLuybenPID,
+    SomeOvershoot,
+    NoOvershoot,
+    CohenCoonP,
+    CohenCoonPI,
+    CohenCoonPID,
+}
+
+impl TuningRule {
+    pub const ALL: [TuningRule; 10] = [
+        TuningRule::ZieglerNicholsP,
+        TuningRule::ZieglerNicholsPI,
+        TuningRule::ZieglerNicholsPID,
+        TuningRule::TyreusLuybenPI,
+        TuningRule::TyreusLuybenPID,
+        TuningRule::SomeOvershoot,
+        TuningRule::NoOvershoot,
+        TuningRule::CohenCoonP,
+        TuningRule::CohenCoonPI,
+        TuningRule::CohenCoonPID,
+    ];
+
+    // key of the rule in the yaml report
+    pub fn name(&self) -> &'static str {
+        match self {
+            TuningRule::ZieglerNicholsP => "ziegler_nichols_p",
+            TuningRule::ZieglerNicholsPI => "ziegler_nichols_pi",
+            TuningRule::ZieglerNicholsPID => "ziegler_nichols_pid",
+            TuningRule::TyreusLuybenPI => "tyreus_luyben_pi",
+            TuningRule::TyreusLuybenPID => "tyreus_luyben_pid",
+            TuningRule::SomeOvershoot => "some_overshoot",
+            TuningRule::NoOvershoot => "no_overshoot",
+            TuningRule::CohenCoonP => "cohen_coon_p",
+            TuningRule::CohenCoonPI => "cohen_coon_pi",
+            TuningRule::CohenCoonPID => "cohen_coon_pid",
+        }
+    }
+}
+
+// kp, integral time and derivative time, turned into kp ki kd
+fn gains(kp: f32, integral_time: f32, derivative_time: f32) -> (f32, f32, f32) {
+    let ki = if integral_time > 0. { kp / integral_time } else { 0. };
+    (kp, ki, kp * derivative_time)
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UltimatePoint {
+    pub gain: f32,
+    pub period: f32,
+}
+
+impl UltimatePoint {
+    // None for the rules that need a plant model
+    pub fn gains(&self, rule: TuningRule) -> Option<(f32, f32, f32)> {
+        let ku = self.gain;
+        let pu = self.period;
+        match rule {
+            TuningRule::ZieglerNicholsP => Some(gains(0.5 * ku, 0., 0.)),
+            TuningRule::ZieglerNicholsPI => Some(gains(0.45 * ku, pu / 1.2, 0.)),
+            TuningRule::ZieglerNicholsPID => Some(gains(0.6 * ku, pu / 2., pu / 8.)),
+            TuningRule::TyreusLuybenPI => Some(gains(ku / 3.2, 2.2 * pu, 0.)),
+            TuningRule::TyreusLuybenPID => Some(gains(ku / 2.2, 2.2 * pu, pu / 6.3)),
+            TuningRule::SomeOvershoot => Some(gains(ku / 3., pu / 2., pu / 3.)),
+            TuningRule::NoOvershoot => Some(gains(ku / 5., pu / 2., pu / 3.)),
+            _ => None,
+        }
+    }
+}
+
+// first order plus dead time model, gain * e^(-dead_time s) / (time_constant s + 1)
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FirstOrderModel {
+    pub gain: f32,
+    pub time_constant: f32,
+    pub dead_time: f32,
+}
+
+impl FirstOrderModel {
+    // Cohen-Coon and the open loop (reaction curve) Ziegler-Nichols rules
+    pub fn gains(&self, rule: TuningRule) -> Option<(f32, f32, f32)> {
+        let k = self.gain;
+        let t = self.time_constant;
+        let l = self.dead_time;
+        let r = l / t;
+        match rule {
+            TuningRule::ZieglerNicholsP => Some(gains(t / (k * l), 0., 0.)),
+            TuningRule::ZieglerNicholsPI => Some(gains(0.9 * t / (k * l), l / 0.3, 0.)),
+            TuningRule::ZieglerNicholsPID => Some(gains(1.2 * t / (k * l), 2. * l, 0.5 * l)),
+            TuningRule::CohenCoonP => Some(gains(t / (k * l) * (1. + r / 3.), 0., 0.)),
+            TuningRule::CohenCoonPI => Some(gains(
+                t / (k * l) * (0.9 + r / 12.),
+                l * (30. + 3. * r) / (9. + 20. * r),
+                0.,
+            )),
+            TuningRule::CohenCoonPID => Some(gains(
+                t / (k * l) * (4. / 3. + r / 4.),
+                l * (32. + 6. * r) / (13. + 8. * r),
+                4. * l / (11. + 2. * r),
+            )),
+            _ => None,
+        }
+    }
+}
+
+pub struct RelayTest {
+    pub setpoint: f32,
+    pub bias: f32,      // input that holds the plant, the relay switches around it
+    pub amplitude: f32, // relay amplitude d
+    pub hysteresis: f32,
+    pub delta_time: f32,
+    pub duration: f32,
+    pub average_cycles: usize, // the last cycles are averaged, the first ones are the transient
+}
+
+impl RelayTest {
+    pub fn run(&self, plant: &mut impl Plant) -> Result<UltimatePoint, Box<dyn Error>> {
+        let mut high = plant.measure() < self.setpoint;
+        let mut last_switch: Option<f32> = None;
+        let mut max = f32::MIN;
+        let mut min = f32::MAX;
+        let mut cycles: Vec<(f32, f32)> = Vec::new(); // period, peak to peak
+
+        let steps = (self.duration / self.delta_time) as usize;
+        for step in 0..steps {
+            let time = step as f32 * self.delta_time;
+            let error = self.setpoint - plant.measure();
+
+            if high && error < -self.hysteresis {
+                high = false;
+            } else if !high && error > self.hysteresis {
+                high = true;
+                // a cycle is from one switch up to the next one
+                if let Some(last_switch) = last_switch {
+                    cycles.push((time - last_switch, max - min));
+                }
+                last_switch = Some(time);
+                max = f32::MIN;
+                min = f32::MAX;
+            }
+
+            let input = if high { self.bias + self.amplitude } else { self.bias - self.amplitude };
+            let measurement = plant.step(input, self.delta_time);
+            max = max.max(measurement);
+            min = min.min(measurement);
+        }
+
+        // the oscillation grows from the rest, at least one cycle has to be left out
+        if cycles.len() <= self.average_cycles || self.average_cycles == 0 {
+            return Err("relay test did not oscillate long enough, try a longer duration or a larger amplitude".into());
+        }
+        let cycles = &cycles[cycles.len() - self.average_cycles..];
+
+        let period = cycles.iter().map(|cycle| cycle.0).sum::<f32>() / cycles.len() as f32;
+        let amplitude = cycles.iter().map(|cycle| cycle.1).sum::<f32>() / cycles.len() as f32 / 2.;
+        if amplitude <= self.hysteresis {
+            return Err("relay test oscillation is inside the hysteresis".into());
+        }
+
+        // the hysteresis delays the switch, the describing function takes it into account
+        let gain = 4. * self.amplitude / (PI * (amplitude * amplitude - self.hysteresis * self.hysteresis).sqrt());
+        Ok(UltimatePoint { gain, period })
+    }
+}
+
+pub struct StepTest {
+    pub bias: f32,
+    pub step: f32,
+    pub delta_time: f32,
+    pub settle_time: f32, // time at the bias before the step
+    pub duration: f32,    // time after the step
+}
+
+impl StepTest {
+    pub fn run(&self, plant: &mut impl Plant) -> Result<FirstOrderModel, Box<dyn Error>> {
+        let settle_steps = (self.settle_time / self.delta_time) as usize;
+        for _ in 0..settle_steps {
+            plant.step(self.bias, self.delta_time);
+        }
+        let start = plant.measure();
+
+        let steps = (self.duration / self.delta_time) as usize;
+        let mut response = Vec::with_capacity(steps);
+        for _ in 0..steps {
+            response.push(plant.step(self.bias + self.step, self.delta_time) - start);
+        }
+        if response.len() < 20 {
+            return Err("step test is too short".into());
+        }
+
+        // the last 5% of the response is the final value
+        let tail = &response[response.len() * 19 / 20..];
+        let change = tail.iter().sum::<f32>() / tail.len() as f32;
+        if change.abs() < f32::EPSILON {
+            return Err("plant did not respond to the step".into());
+        }
+        // an integrating plant (like the height of the car) never settles
+        let drift = response[response.len() - 1] - response[response.len() * 9 / 10];
+        if (drift / change).abs() > 0.05 {
+            return Err("plant did not settle, a step test can not model it, use the relay test".into());
+        }
+
+        let crossing = |fraction: f32| {
+            response.iter()
+                .position(|value| value / change >= fraction)
+                .map(|index| (index + 1) as f32 * self.delta_time)
+        };
+        let t28 = crossing(0.283).ok_or("response never reached 28.3%")?;
+        let t63 = crossing(0.632).ok_or("response never reached 63.2%")?;
+
+        // a plant that answers within one step (the static motor) would give a
+        // time constant or a dead time of zero and infinite gains
+        let time_constant = (1.5 * (t63 - t28)).max(self.delta_time);
+        let dead_time = (t63 - time_constant).max(self.delta_time);
+
+        Ok(FirstOrderModel {
+            gain: change / self.step,
+            time_constant,
+            dead_time,
+        })
+    }
+}
+
+// tuned pid parameters of every rule that fits the experiment, the rest of
+// the parameters (frequency, limits, anti-windup) are kept from the base
+#[derive(Debug, Serialize)]
+pub struct TuningReport {
+    pub ultimate: Option<UltimatePoint>,
+    pub model: Option<FirstOrderModel>,
+    pub parameters: BTreeMap<String, PIDParameters>,
+}
+
+impl TuningReport {
+    pub fn from_ultimate(base: &PIDParameters, ultimate: UltimatePoint) -> Self {
+        Self {
+            ultimate: Some(ultimate),
+            model: None,
+            parameters: Self::tune(base, |rule| ultimate.gains(rule)),
+        }
+    }
+
+    pub fn from_model(base: &PIDParameters, model: FirstOrderModel) -> Self {
+        Self {
+            ultimate: None,
+            model: Some(model),
+            parameters: Self::tune(base, |rule| model.gains(rule)),
+        }
+    }
+
+    fn tune(
+        base: &PIDParameters,
+        rule_gains: impl Fn(TuningRule) -> Option<(f32, f32, f32)>,
+    ) -> BTreeMap<String, PIDParameters> {
+        TuningRule::ALL.iter()
+            .filter_map(|rule| {
+                let (kp, ki, kd) = rule_gains(*rule)?;
+                Some((rule.name().to_string(), base.with_gains(kp, ki, kd)))
+            })
+            .collect()
+    }
+
+    pub fn get(&self, rule: TuningRule) -> Option<&PIDParameters> {
+        self.parameters.get(rule.name())
+    }
+
+    pub fn to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::temp_path;
+    use std::collections::VecDeque;
+    use crate::machine::elevator::Elevator;
+    use crate::machine::tuning::CarHeightPlant;
+    use crate::machine::tuning::MotorSpeedPlant;
+    use crate::machine::motor::{ElevatorMotor, MotorParameters};
+
+    // 1 / (s + 1)^3, Ku = 8 and Pu = 2 pi / sqrt(3)
+    struct ThirdOrder {
+        states: [f32; 3],
+    }
+
+    impl Plant for ThirdOrder {
+        fn step(&mut self, input: f32, delta_time: f32) -> f32 {
+            let mut previous = input;
+            for state in &mut self.states {
+                *state += (previous - *state) * delta_time;
+                previous = *state;
+            }
+            self.measure()
+        }
+
+        fn measure(&self) -> f32 {
+            self.states[2]
+        }
+    }
+
+    // 2 e^(-0.5 s) / (s + 1)
+    struct DeadTime {
+        state: f32,
+        delay: VecDeque<f32>,
+    }
+
+    impl Plant for DeadTime {
+        fn step(&mut self, input: f32, delta_time: f32) -> f32 {
+            self.delay.push_back(input);
+            let delayed = self.delay.pop_front().unwrap();
+            self.state += (2. * delayed - self.state) * delta_time;
+            self.measure()
+        }
+
+        fn measure(&self) -> f32 {
+            self.state
+        }
+    }
+
+    struct Integrator {
+        state: f32,
+    }
+
+    impl Plant for Integrator {
+        fn step(&mut self, input: f32, delta_time: f32) -> f32 {
+            self.state += input * delta_time;
+            self.measure()
+        }
+
+        fn measure(&self) -> f32 {
+            self.state
+        }
+    }
+
+    fn base() -> PIDParameters {
+        serde_yaml::from_str("
+            kp: 0.
+            ki: 0.
+            kd: 0.
+            integral_limit: 0.
+            update_freq: 100.
+            tolerance: 0.01
+        ").unwrap()
+    }
+
+    fn step_test() -> StepTest {
+        StepTest { bias: 0., step: 1., delta_time: 0.001, settle_time: 0., duration: 10. }
+    }
+
+    #[test]
+    fn relay_finds_ultimate_point() {
+        let mut plant = ThirdOrder { states: [0.; 3] };
+        let relay = RelayTest {
+            setpoint: 0.,
+            bias: 0.,
+            amplitude: 1.,
+            hysteresis: 0.,
+            delta_time: 0.001,
+            duration: 60.,
+            average_cycles: 3,
+        };
+        let ultimate = relay.run(&mut plant).unwrap();
+        assert!((ultimate.gain - 8.).abs() < 8. * 0.15);
+        assert!((ultimate.period - 2. * PI / 3f32.sqrt()).abs() < 0.2);
+
+        let report = TuningReport::from_ultimate(&base(), ultimate);
+        let (kp, ki, kd) = report.get(TuningRule::ZieglerNicholsPID).unwrap().gains();
+        assert!((kp - 0.6 * ultimate.gain).abs() < 1e-4);
+        assert!(ki > 0. && kd > 0.);
+        // cohen-coon needs a model
+        assert!(report.get(TuningRule::CohenCoonPID).is_none());
+    }
+
+    #[test]
+    fn step_finds_model() {
+        let mut plant = DeadTime { state: 0., delay: VecDeque::from(vec![0.; 500]) };
+        let model = step_test().run(&mut plant).unwrap();
+        assert!((model.gain - 2.).abs() < 0.05);
+        assert!((model.time_constant - 1.).abs() < 0.1);
+        assert!((model.dead_time - 0.5).abs() < 0.1);
+
+        let report = TuningReport::from_model(&base(), model);
+        assert!(report.get(TuningRule::CohenCoonPI).is_some());
+        assert!(report.get(TuningRule::TyreusLuybenPI).is_none());
+    }
+
+    #[test]
+    fn step_on_the_static_motor() {
+        let parameters = MotorParameters::from_file("param/motor_parameters.yaml").unwrap();
+        let mut plant = MotorSpeedPlant { motor: ElevatorMotor::new(parameters).unwrap() };
+        let test = StepTest { bias: 0., step: 10., delta_time: 0.01, settle_time: 0., duration: 2. };
+        let model = test.run(&mut plant).unwrap();
+        assert!(model.time_constant > 0. && model.dead_time > 0.);
+
+        let report = TuningReport::from_model(&base(), model);
+        for parameters in report.parameters.values() {
+            let (kp, ki, kd) = parameters.gains();
+            assert!(kp.is_finite() && ki.is_finite() && kd.is_finite());
+        }
+    }
+
+    #[test]
+    fn step_rejects_integrating_plant() {
+        let mut plant = Integrator { state: 0. };
+        assert!(step_test().run(&mut plant).is_err());
+    }
+
+    #[test]
+    fn tune_car_height_loop() {
+        let elevator = Elevator::standard(vec![0.0, 3.0, 6.0, 9.0]);
+        let mut plant = CarHeightPlant { elevator };
+        let relay = RelayTest {
+            setpoint: 0.5,
+            bias: 0.,
+            amplitude: 0.2,
+            hysteresis: 0.001,
+            delta_time: 0.01,
+            duration: 30.,
+            average_cycles: 3,
+        };
+        let ultimate = relay.run(&mut plant).unwrap();
+        assert!(ultimate.gain > 0. && ultimate.gain.is_finite());
+        assert!(ultimate.period > 0.);
+
+        let report = TuningReport::from_ultimate(&base(), ultimate);
+        let path = temp_path("elevator_height_pid_tuning.yaml");
+        report.to_file(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // a single rule can be written back and read as pid parameters
+        let path = temp_path("elevator_height_pid.yaml");
+        let tuned = report.get(TuningRule::ZieglerNicholsPI).unwrap();
+        tuned.to_file(&path).unwrap();
+        let read = PIDParameters::from_file(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(read.gains() == tuned.gains());
+    }
+}
@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// A deterministic simulated trip and the cost the optimizer minimises.
+// Every candidate gets a fresh car, the same load and the same trip so the
+// costs of two candidates can be compared.
+
+use serde::Deserialize;
+use std::error::Error;
+
+use crate::machine::elevator::Elevator;
+use crate::machine::motor::{ElevatorMotor, MotorModel, MotorParameters};
+use crate::machine::pid_controller::PIDController;
+use crate::machine::controllers::mpc::MpcParameters;
+
+// the loop whose pid is searched
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TunedLoop {
+    Height, // position loop of the car
+    Speed,  // speed loop of the motor
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct CostWeights {
+    pub settling_time: f32,  // per second
+    pub overshoot: f32,      // per meter
+    pub leveling_error: f32, // per meter
+    pub energy: f32,         // per kW s taken from the supply
+    pub jerk: f32,           // per m/s^3 rms
+}
+
+impl Default for CostWeights {
+    fn default() -> Self {
+        // a second of settling costs as much as a centimeter of overshoot,
+        // a millimeter of leveling error or 100 kW s
+        Self {
+            settling_time: 1.,
+            overshoot: 100.,
+            leveling_error: 1000.,
+            energy: 0.01,
+            jerk: 1.,
+        }
+    }
+}
+
+impl CostWeights {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+
+    pub fn cost(&self, metrics: &TripMetrics) -> f32 {
+        let cost = self.settling_time * metrics.settling_time
+            + self.overshoot * metrics.overshoot
+            + self.leveling_error * metrics.leveling_error
+            + self.energy * metrics.energy
+            + self.jerk * metrics.rms_jerk;
+
+        // an unstable candidate can end up anywhere
+        if cost.is_finite() { cost } else { f32::MAX }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TripMetrics {
+    pub settling_time: f32,  // last time the car was out of the settle band, the trip time
+    pub overshoot: f32,      // how far the car went past the target
+    pub leveling_error: f32, // distance to the floor at the end
+    pub energy: f32,         // kW s
+    pub rms_jerk: f32,
+}
+
+pub struct TripScenario {
+    pub motor_path: String, // motor parameters of the car
+    pub floors: Vec<f32>,
+    pub load: f32,
+    pub from: usize,
+    pub to: usize,
+    pub model: MotorModel,
+    pub delta_time: f32,
+    pub hold_time: f32, // the motor takes the load before the trip, there is no brake
+    pub duration: f32,
+    pub settle_band: f32,
+}
+
+impl Default for TripScenario {
+    fn default() -> Self {
+        Self {
+            motor_path: "param/motor_parameters.yaml".to_string(),
+            floors: vec![0.0, 3.0, 6.0, 9.0],
+            load: 800.,
+            from: 0,
+            to: 2,
+            model: MotorModel::Static,
+            delta_time: 0.01,
+            hold_time: 1.,
+            duration: 20.,
+            settle_band: 0.01,
+        }
+    }
+}
+
+impl TripScenario {
+    // a fresh car with the load, standing on the first floor of the trip
+    fn new_car(&self) -> Result<Elevator, Box<dyn Error>> {
+        for floor in [self.from, self.to] {
+            if floor >= self.floors.len() {
+                return Err(format!("floor {} of the trip is not one of the {} floors", floor, self.floors.len()).into());
+            }
+        }
+        let mut elevator = Elevator::standard(self.floors.clone());
+        let mut parameters = MotorParameters::from_file(&self.motor_path)?;
+        parameters.model = self.model;
+        elevator.motor = ElevatorMotor::new(parameters)?;
+        elevator.load(self.load);
+        elevator.current_height = self.floors[self.from];
+        elevator.profile.reset(elevator.current_height);
+        Ok(elevator)
+    }
+
+    // runs the trip with the pid of the tuned loop changed by the caller, a
+    // loop that runs another controller has no gains to search
+    pub fn run(&self, tuned_loop: TunedLoop, configure: impl Fn(&mut PIDController)) -> Result<TripMetrics, Box<dyn Error>> {
+        let mut elevator = self.new_car()?;
+
+        let pid = match tuned_loop {
+            TunedLoop::Height => elevator.height_controller.as_pid_mut(),
+            TunedLoop::Speed => elevator.motor.speed_controller_mut().as_pid_mut(),
+        };
+        let Some(pid) = pid else {
+            return Err(format!("the {:?} loop does not run a pid", tuned_loop).into());
+        };
+        configure(pid);
+
+        Ok(self.measure(elevator))
+    }
+
+    // runs the same trip with the car positioned by the mpc
+    pub fn run_with_mpc(&self, parameters: MpcParameters) -> Result<TripMetrics, Box<dyn Error>> {
+        let mut elevator = self.new_car()?;
+        elevator.set_mpc(parameters);
+        Ok(self.measure(elevator))
+    }
+
+    fn measure(&self, mut elevator: Elevator) -> TripMetrics {
+        let hold_steps = (self.hold_time / self.delta_time) as usize;
+        for _ in 0..hold_steps {
+            elevator.update(self.delta_time);
+        }
+
+        let energy_start = elevator.motor.get_total_energy_used();
+        let target = self.floors[self.to];
+        let direction = (target - elevator.current_height).signum();
+        elevator.set_target(self.to);
+
+        let mut metrics = TripMetrics::default();
+        let mut previous_accel = elevator.current_accel;
+        let mut jerk_sum = 0.;
+        let steps = (self.duration / self.delta_time) as usize;
+        for step in 0..steps {
+            elevator.update(self.delta_time);
+
+            let error = elevator.current_height - target;
+            if error.abs() > self.settle_band {
+                metrics.settling_time = (step + 1) as f32 * self.delta_time;
+            }
+            metrics.overshoot = metrics.overshoot.max(error * direction);
+
+            let jerk = (elevator.current_accel - previous_accel) / self.delta_time;
+            jerk_sum += jerk * jerk;
+            previous_accel = elevator.current_accel;
+        }
+
+        metrics.leveling_error = (elevator.current_height - target).abs();
+        metrics.energy = elevator.motor.get_total_energy_used() - energy_start;
+        metrics.rms_jerk = (jerk_sum / steps as f32).sqrt();
+        metrics
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::temp_path;
+
+    #[test]
+    fn default_gains_settle() {
+        let metrics = TripScenario::default().run(TunedLoop::Speed, |_| {}).unwrap();
+        assert!(metrics.settling_time < 15.);
+        assert!(metrics.leveling_error < 0.01);
+        assert!(metrics.energy > 0.);
+        assert!(CostWeights::default().cost(&metrics).is_finite());
+    }
+
+    #[test]
+    fn rejects_loop_without_pid() {
+        let mut parameters = MotorParameters::from_file("param/motor_parameters.yaml").unwrap();
+        parameters.speed_controller = Some(serde_yaml::from_str("
+            type: fuzzy
+            error_scale: 50.
+            rate_scale: 500.
+            output_scale: 50.
+            update_freq: 100.
+            tolerance: 1.
+        ").unwrap());
+        let path = temp_path("elevator_fuzzy_motor.yaml");
+        parameters.to_file(&path).unwrap();
+
+        let scenario = TripScenario { motor_path: path.clone(), ..Default::default() };
+        assert!(scenario.run(TunedLoop::Speed, |_| {}).is_err());
+        // the height loop still runs a pid
+        assert!(scenario.run(TunedLoop::Height, |_| {}).is_ok());
+        std::fs::remove_file(path).unwrap();
+
+        let missing = TripScenario { motor_path: "param/no_such_motor.yaml".to_string(), ..Default::default() };
+        assert!(missing.run(TunedLoop::Height, |_| {}).is_err());
+    }
+
+    #[test]
+    fn rejects_floors_outside_the_building() {
+        let above = TripScenario { to: 4, ..Default::default() };
+        assert!(above.run(TunedLoop::Speed, |_| {}).is_err());
+        let below = TripScenario { from: 9, ..Default::default() };
+        assert!(below.run_with_mpc(MpcParameters::default()).is_err());
+    }
+
+    #[test]
+    fn mpc_against_pid() {
+        let scenario = TripScenario::default();
+        let pid = scenario.run(TunedLoop::Speed, |_| {}).unwrap();
+        let mpc = scenario.run_with_mpc(MpcParameters::default()).unwrap();
+        // both end on the floor before the end of the run
+        assert!(pid.leveling_error < 0.01 && mpc.leveling_error < 0.01);
+        assert!(pid.settling_time < scenario.duration && mpc.settling_time < scenario.duration);
+        assert!(mpc.overshoot < scenario.settle_band);
+        // the plan keeps the jerk limit, the trip takes about as long as the
+        // s-curve of the pid
+        assert!(mpc.rms_jerk <= pid.rms_jerk);
+        assert!(mpc.settling_time < pid.settling_time + 2.);
+        assert!(mpc.energy > 0. && mpc.energy < pid.energy * 1.2);
+    }
+}
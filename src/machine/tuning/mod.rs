@@ -0,0 +1,11 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Tools that find the pid gains in the simulator instead of editing them by hand
+
+pub mod plant;
+pub mod autotune;
+pub mod cost;
+pub mod optimizer;
+
+pub use plant::{Plant, MotorSpeedPlant, CarSpeedPlant, CarHeightPlant};
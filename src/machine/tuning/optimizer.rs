@@ -0,0 +1,352 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Optimization based pid search. The candidate is
+//   [kp, ki, kd, change_limit, integral_limit]
+// and its cost is the weighted cost of a simulated trip (cost.rs). Nelder-Mead
+// and a (1+lambda) evolution strategy work on any bounded objective, both
+// keep a trace of the best cost of every iteration that can be written as csv.
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
+use std::cell::Cell;
+use std::error::Error;
+
+use crate::machine::pid_controller::PIDParameters;
+use crate::random::normal;
+use super::cost::{CostWeights, TripMetrics, TripScenario, TunedLoop};
+
+pub const CANDIDATE_NAMES: [&str; 5] = ["kp", "ki", "kd", "change_limit", "integral_limit"];
+
+// every value is clamped into its bounds
+#[derive(Debug, Clone)]
+pub struct SearchSpace {
+    pub lower: Vec<f32>,
+    pub upper: Vec<f32>,
+}
+
+impl SearchSpace {
+    pub fn clamp(&self, point: &mut [f32]) {
+        for (i, value) in point.iter_mut().enumerate() {
+            *value = value.clamp(self.lower[i], self.upper[i]);
+        }
+    }
+
+    pub fn range(&self, i: usize) -> f32 {
+        self.upper[i] - self.lower[i]
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceEntry {
+    pub iteration: usize,
+    pub evaluations: usize,
+    pub best_cost: f32,
+    pub best: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OptimizationResult {
+    pub best: Vec<f32>,
+    pub best_cost: f32,
+    pub evaluations: usize,
+    pub trace: Vec<TraceEntry>,
+}
+
+impl OptimizationResult {
+    // iteration, evaluations, best cost and the best point of every iteration
+    pub fn write_trace(&self, file_path: &str, names: &[&str]) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_path(file_path)?;
+        let mut header = vec!["iteration", "evaluations", "best_cost"];
+        header.extend_from_slice(names);
+        writer.write_record(&header)?;
+
+        for entry in &self.trace {
+            let mut record = vec![
+                entry.iteration.to_string(),
+                entry.evaluations.to_string(),
+                entry.best_cost.to_string(),
+            ];
+            record.extend(entry.best.iter().map(|value| value.to_string()));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+pub trait Optimizer {
+    fn minimize(&self, objective: &dyn Fn(&[f32]) -> f32, start: &[f32], space: &SearchSpace) -> OptimizationResult;
+}
+
+pub struct NelderMead {
+    pub max_iterations: usize,
+    pub initial_step: f32, // size of the first simplex, as a fraction of the search range
+    pub tolerance: f32,    // stops when the costs of the simplex are this close
+}
+
+impl Default for NelderMead {
+    fn default() -> Self {
+        Self { max_iterations: 100, initial_step: 0.1, tolerance: 1e-4 }
+    }
+}
+
+impl Optimizer for NelderMead {
+    fn minimize(&self, objective: &dyn Fn(&[f32]) -> f32, start: &[f32], space: &SearchSpace) -> OptimizationResult {
+        let n = start.len();
+        let evaluations = Cell::new(0);
+        let evaluate = |point: &mut Vec<f32>| {
+            space.clamp(point);
+            evaluations.set(evaluations.get() + 1);
+            objective(point)
+        };
+
+        // start and one step along every axis
+        let mut simplex: Vec<(Vec<f32>, f32)> = Vec::with_capacity(n + 1);
+        let mut first = start.to_vec();
+        let cost = evaluate(&mut first);
+        simplex.push((first, cost));
+        for i in 0..n {
+            let mut point = start.to_vec();
+            point[i] += self.initial_step * space.range(i);
+            // a start on the upper bound steps down
+            if point[i] > space.upper[i] {
+                point[i] = start[i] - self.initial_step * space.range(i);
+            }
+            let cost = evaluate(&mut point);
+            simplex.push((point, cost));
+        }
+
+        let mut trace = Vec::new();
+        for iteration in 0..self.max_iterations {
+            simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+            trace.push(TraceEntry {
+                iteration,
+                evaluations: evaluations.get(),
+                best_cost: simplex[0].1,
+                best: simplex[0].0.clone(),
+            });
+
+            if (simplex[n].1 - simplex[0].1).abs() <= self.tolerance {
+                break;
+            }
+
+            // centroid of every point but the worst
+            let mut centroid = vec![0.; n];
+            for (point, _) in &simplex[..n] {
+                for i in 0..n {
+                    centroid[i] += point[i] / n as f32;
+                }
+            }
+            let towards = |from: &[f32], coefficient: f32| -> Vec<f32> {
+                (0..n).map(|i| centroid[i] + coefficient * (from[i] - centroid[i])).collect()
+            };
+
+            let worst = simplex[n].clone();
+            let mut reflected = towards(&worst.0, -1.);
+            let reflected_cost = evaluate(&mut reflected);
+
+            if reflected_cost < simplex[0].1 {
+                let mut expanded = towards(&worst.0, -2.);
+                let expanded_cost = evaluate(&mut expanded);
+                simplex[n] = if expanded_cost < reflected_cost {
+                    (expanded, expanded_cost)
+                } else {
+                    (reflected, reflected_cost)
+                };
+            } else if reflected_cost < simplex[n - 1].1 {
+                simplex[n] = (reflected, reflected_cost);
+            } else {
+                let mut contracted = towards(&worst.0, 0.5);
+                let contracted_cost = evaluate(&mut contracted);
+                if contracted_cost < worst.1 {
+                    simplex[n] = (contracted, contracted_cost);
+                } else {
+                    // shrink everything towards the best point
+                    let best = simplex[0].0.clone();
+                    for (point, cost) in simplex.iter_mut().skip(1) {
+                        for i in 0..n {
+                            point[i] = best[i] + 0.5 * (point[i] - best[i]);
+                        }
+                        *cost = evaluate(point);
+                    }
+                }
+            }
+        }
+
+        simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let (best, best_cost) = simplex.swap_remove(0);
+        OptimizationResult { best, best_cost, evaluations: evaluations.get(), trace }
+    }
+}
+
+// (1+lambda) evolution strategy with the one-fifth success rule, the seed
+// makes the search repeatable
+pub struct EvolutionStrategy {
+    pub generations: usize,
+    pub offspring: usize,
+    pub initial_sigma: f32, // mutation step, as a fraction of the search range
+    pub seed: u64,
+}
+
+impl Default for EvolutionStrategy {
+    fn default() -> Self {
+        Self { generations: 50, offspring: 8, initial_sigma: 0.1, seed: 0 }
+    }
+}
+
+impl Optimizer for EvolutionStrategy {
+    fn minimize(&self, objective: &dyn Fn(&[f32]) -> f32, start: &[f32], space: &SearchSpace) -> OptimizationResult {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut parent = start.to_vec();
+        space.clamp(&mut parent);
+        let mut parent_cost = objective(&parent);
+        let mut evaluations = 1;
+        let mut sigma = self.initial_sigma;
+        let mut trace = Vec::new();
+
+        for generation in 0..self.generations {
+            let mut improved = false;
+            for _ in 0..self.offspring {
+                let mut child: Vec<f32> = parent.iter().enumerate()
+                    .map(|(i, value)| value + sigma * space.range(i) * normal(&mut rng, 0., 1.))
+                    .collect();
+                space.clamp(&mut child);
+                let cost = objective(&child);
+                evaluations += 1;
+                if cost < parent_cost {
+                    parent = child;
+                    parent_cost = cost;
+                    improved = true;
+                }
+            }
+
+            // wider steps while it finds better points, narrower when it does not
+            sigma *= if improved { 1.22 } else { 0.82 };
+            trace.push(TraceEntry {
+                iteration: generation,
+                evaluations,
+                best_cost: parent_cost,
+                best: parent.clone(),
+            });
+        }
+
+        OptimizationResult { best: parent, best_cost: parent_cost, evaluations, trace }
+    }
+}
+
+// gains, change limit and integral limit of one loop against a simulated trip
+pub struct PidSearch {
+    pub scenario: TripScenario,
+    pub weights: CostWeights,
+    pub tuned_loop: TunedLoop,
+    pub space: SearchSpace,
+}
+
+impl PidSearch {
+    pub fn new(tuned_loop: TunedLoop) -> Self {
+        // zero limits mean no limit in the pid controller
+        let space = match tuned_loop {
+            TunedLoop::Height => SearchSpace {
+                lower: vec![0., 0., 0., 0., 0.],
+                upper: vec![10., 10., 2., 5., 5.],
+            },
+            TunedLoop::Speed => SearchSpace {
+                lower: vec![0., 0., 0., 0., 0.],
+                upper: vec![2., 10., 0.1, 500., 100.],
+            },
+        };
+        Self {
+            scenario: TripScenario::default(),
+            weights: CostWeights::default(),
+            tuned_loop,
+            space,
+        }
+    }
+
+    pub fn evaluate(&self, candidate: &[f32]) -> Result<TripMetrics, Box<dyn Error>> {
+        self.scenario.run(self.tuned_loop, |pid| {
+            pid.set_parameters(candidate[0], candidate[1], candidate[2]);
+            pid.set_change_limit(candidate[3]);
+            pid.set_integral_limit(candidate[4]);
+        })
+    }
+
+    pub fn cost(&self, candidate: &[f32]) -> f32 {
+        self.evaluate(candidate).map_or(f32::MAX, |metrics| self.weights.cost(&metrics))
+    }
+
+    // the scenario is checked with the start first, a loop without a pid or a
+    // missing motor file is an error instead of a search over f32::MAX costs
+    pub fn run(&self, optimizer: &impl Optimizer, start: &[f32]) -> Result<OptimizationResult, Box<dyn Error>> {
+        self.evaluate(start)?;
+        Ok(optimizer.minimize(&|candidate| self.cost(candidate), start, &self.space))
+    }
+
+    // the best candidate as pid parameters, the rest is kept from the base
+    pub fn to_parameters(base: &PIDParameters, candidate: &[f32]) -> PIDParameters {
+        base.with_gains(candidate[0], candidate[1], candidate[2])
+            .with_limits(candidate[3], candidate[4])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::temp_path;
+
+    fn quadratic(point: &[f32]) -> f32 {
+        (point[0] - 1.).powi(2) + 10. * (point[1] + 2.).powi(2)
+    }
+
+    fn space() -> SearchSpace {
+        SearchSpace { lower: vec![-5., -5.], upper: vec![5., 5.] }
+    }
+
+    #[test]
+    fn nelder_mead_finds_minimum() {
+        let result = NelderMead { max_iterations: 200, ..Default::default() }
+            .minimize(&quadratic, &[4., 4.], &space());
+        assert!((result.best[0] - 1.).abs() < 0.05);
+        assert!((result.best[1] + 2.).abs() < 0.05);
+        // the best cost never gets worse
+        assert!(result.trace.windows(2).all(|pair| pair[1].best_cost <= pair[0].best_cost));
+    }
+
+    #[test]
+    fn evolution_strategy_finds_minimum() {
+        let optimizer = EvolutionStrategy { generations: 100, ..Default::default() };
+        let result = optimizer.minimize(&quadratic, &[4., 4.], &space());
+        assert!(result.best_cost < 0.01);
+
+        // same seed, same search
+        let again = optimizer.minimize(&quadratic, &[4., 4.], &space());
+        assert!(again.best == result.best);
+    }
+
+    #[test]
+    fn bounds() {
+        let bounded = SearchSpace { lower: vec![2., -5.], upper: vec![5., 5.] };
+        let result = NelderMead::default().minimize(&quadratic, &[4., 4.], &bounded);
+        assert!(result.best[0] >= 2.);
+    }
+
+    #[test]
+    fn pid_search_improves_trip() {
+        let search = PidSearch::new(TunedLoop::Speed);
+        // a sluggish speed loop
+        let start = [0.05, 0.2, 0., 100., 0.];
+        assert!(search.cost(&start) == search.cost(&start));
+
+        let result = search.run(&EvolutionStrategy { generations: 5, offspring: 4, ..Default::default() }, &start).unwrap();
+        assert!(result.best_cost < search.cost(&start));
+
+        let path = temp_path("elevator_speed_pid_trace.csv");
+        result.write_trace(&path, &CANDIDATE_NAMES).unwrap();
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        std::fs::remove_file(path).unwrap();
+        assert!(lines == result.trace.len() + 1);
+    }
+}
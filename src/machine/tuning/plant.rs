@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// The loop under test is opened and the tuner drives its input directly.
+// Every plant takes the output of the pid it is tuning as the input and gives
+// back the measurement that pid would see.
+
+use crate::machine::elevator::Elevator;
+use crate::machine::motor::ElevatorMotor;
+
+pub trait Plant {
+    // give the input for delta_time, returns the new measurement
+    fn step(&mut self, input: f32, delta_time: f32) -> f32;
+    fn measure(&self) -> f32;
+}
+
+// motor speed loop without a car, current (A) -> motor rpm
+pub struct MotorSpeedPlant {
+    pub motor: ElevatorMotor,
+}
+
+impl Plant for MotorSpeedPlant {
+    fn step(&mut self, input: f32, delta_time: f32) -> f32 {
+        self.motor.update_with_current(input, delta_time);
+        self.measure()
+    }
+
+    fn measure(&self) -> f32 {
+        self.motor.get_motor_rpm()
+    }
+}
+
+// motor speed loop with the car on the rope, current (A) -> motor rpm
+pub struct CarSpeedPlant {
+    pub elevator: Elevator,
+}
+
+impl Plant for CarSpeedPlant {
+    fn step(&mut self, input: f32, delta_time: f32) -> f32 {
+        self.elevator.update_with_current(input, delta_time);
+        self.measure()
+    }
+
+    fn measure(&self) -> f32 {
+        self.elevator.motor.get_motor_rpm()
+    }
+}
+
+// height loop of the car, speed reference (m/s) -> height (m)
+pub struct CarHeightPlant {
+    pub elevator: Elevator,
+}
+
+impl Plant for CarHeightPlant {
+    fn step(&mut self, input: f32, delta_time: f32) -> f32 {
+        self.elevator.update_with_speed(input, delta_time);
+        self.measure()
+    }
+
+    fn measure(&self) -> f32 {
+        self.elevator.current_height
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::motor::MotorParameters;
+
+    #[test]
+    fn motor_speed_plant() {
+        let parameters = MotorParameters::from_file("param/motor_parameters.yaml").unwrap();
+        let mut plant = MotorSpeedPlant { motor: ElevatorMotor::new(parameters).unwrap() };
+
+        // the static motor turns at the speed of the current right away
+        let slow = plant.step(10., 0.01);
+        assert!(slow > 0. && slow == plant.measure());
+        let fast = plant.step(20., 0.01);
+        assert!(fast > slow);
+        assert!(plant.step(-10., 0.01) == -slow);
+        assert!(plant.step(0., 0.01) == 0.);
+    }
+
+    #[test]
+    fn car_speed_plant() {
+        let elevator = Elevator::standard(vec![0.0, 3.0, 6.0, 9.0]);
+        let mut plant = CarSpeedPlant { elevator };
+        // the feed forward current takes the load from the next step on
+        plant.step(0., 0.01);
+        let holding = plant.measure();
+        for _ in 0..100 {
+            plant.step(0., 0.01);
+        }
+        assert!((plant.measure() - holding).abs() < 0.01);
+
+        // the car is an integrator, more current speeds it up and keeps the speed
+        for _ in 0..100 {
+            plant.step(5., 0.01);
+        }
+        let faster = plant.measure();
+        assert!(faster > holding + 10.);
+        for _ in 0..100 {
+            plant.step(-5., 0.01);
+        }
+        assert!((plant.measure() - holding).abs() < 1.);
+        assert!(plant.measure() == plant.elevator.motor.get_motor_rpm());
+    }
+}
@@ -15,14 +15,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 
-mod machine;
-mod control_algorithms;
-mod population;
-
-use machine::elevator_system::ElevatorSystem;
-
-use std::thread;
-use std::time::{Duration, Instant};
+use elevator_optimization::machine::elevator_system::ElevatorSystem;
 
 fn main() {
     println!("    
@@ -31,26 +24,25 @@ fn main() {
         This is free software, and you are welcome to redistribute it
         under certain conditions;
     ");
-    // i will keep the update frequency low for debugging purposes
-    let update_freq = 1;
-    let mut system = ElevatorSystem::new(1, vec![0.0, 100.0, 200.0, 300.0]);
+    // the system runs on simulated time, nothing sleeps anymore
+    // printing every tick is too much, print every 100th one
+    let print_every = 100;
+    let mut system = ElevatorSystem::new(1, vec![0.0, 3.0, 6.0, 9.0]);
 
-    let start_time = Instant::now();
     let mut has_target_set = false;
-    loop {
+    while system.now() < 60. {
         system.update();
 
-        println!("Energy consumed so far: {}", system.total_energy_consumed);
-        println!("Elevator height: {}", system.elevators[0].current_height);
-        // println!("Elevator speed: {}, target: {}", system.elevators[0].get_current_speed(), system.elevators[0].motor.speed_pid.target);
-
+        if system.clock.ticks().is_multiple_of(print_every) {
+            println!("t = {:.2}s", system.now());
+            println!("Energy consumed so far: {}", system.total_energy_consumed);
+            println!("Elevator height: {}", system.elevators[0].current_height);
+        }
 
-        if start_time.elapsed() >= Duration::from_secs(2) && !has_target_set {
+        if system.now() >= 2. && !has_target_set {
             system.elevators[0].set_target(3);
-            println!("2 seconds passed. Target height set to: {}", system.elevators[0].height_pid.target);
+            println!("2 seconds passed. Target height set to: {}", system.elevators[0].height_controller.target());
             has_target_set = true;
         }
-
-        thread::sleep(Duration::from_secs(1/update_freq));
     }
 }
@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Who the passengers are (yaş/kütle). Every passenger belongs to an age group,
+// the group decides the body mass, how fast the passenger walks to the car
+// and gets in or out, and how likely a bag or a trolley comes along. The
+// luggage is carried by the car too and makes the door stay open longer.
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::Deserialize;
+use std::error::Error;
+
+use crate::random::normal;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AgeGroup {
+    Child,
+    #[default]
+    Adult,
+    Senior,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Luggage {
+    #[default]
+    None,
+    Bag,
+    Trolley, // suitcase trolley, pram, delivery cart
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct AgeGroupParameters {
+    pub group: AgeGroup,
+    pub share: f32, // relative weight of the group in the population
+    pub mass_mean: f32,
+    pub mass_std: f32,
+    pub walking_speed: f32, // m/s
+    pub boarding_time: f32, // seconds to step through the door
+    pub bag_probability: f32,
+    pub trolley_probability: f32,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Demographics {
+    pub groups: Vec<AgeGroupParameters>,
+    pub hall_distance: f32, // walk from the waiting place to the car door, m
+    pub bag_mass: f32,
+    pub bag_time: f32, // extra seconds at the door with a bag
+    pub trolley_mass: f32,
+    pub trolley_time: f32,
+}
+
+// what the demographics give a passenger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PassengerTraits {
+    pub age_group: AgeGroup,
+    pub luggage: Luggage,
+    pub mass: f32,               // body and luggage
+    pub boarding_duration: f32,  // walking to the car and getting in
+    pub alighting_duration: f32, // getting out
+}
+
+impl Default for Demographics {
+    fn default() -> Self {
+        let group = |group, share, mass_mean, mass_std, walking_speed, boarding_time, bag_probability, trolley_probability| {
+            AgeGroupParameters {
+                group,
+                share,
+                mass_mean,
+                mass_std,
+                walking_speed,
+                boarding_time,
+                bag_probability,
+                trolley_probability,
+            }
+        };
+        Self {
+            groups: vec![
+                group(AgeGroup::Child, 0.1, 35., 12., 1.0, 1.5, 0.05, 0.),
+                group(AgeGroup::Adult, 0.7, 78., 13., 1.4, 1.0, 0.15, 0.03),
+                group(AgeGroup::Senior, 0.2, 72., 12., 1.0, 2.0, 0.1, 0.05),
+            ],
+            hall_distance: 3.,
+            bag_mass: 8.,
+            bag_time: 1.,
+            trolley_mass: 25.,
+            trolley_time: 3.,
+        }
+    }
+}
+
+impl Demographics {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result: Self = serde_yaml::from_reader(file)?;
+        result.validate()?;
+        Ok(result)
+    }
+
+    // sample needs a group to pick and a finite time to the car
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.groups.iter().map(|group| group.share).sum::<f32>() <= 0. {
+            return Err("the demographics have no group with a share".into());
+        }
+        for group in &self.groups {
+            if group.share < 0. {
+                return Err(format!("the share of {:?} is negative", group.group).into());
+            }
+            if group.walking_speed <= 0. || !group.walking_speed.is_finite() {
+                return Err(format!("{:?} walk with {} m/s", group.group, group.walking_speed).into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn group(&self, age_group: AgeGroup) -> Option<&AgeGroupParameters> {
+        self.groups.iter().find(|group| group.group == age_group)
+    }
+
+    pub fn sample(&self, rng: &mut StdRng) -> PassengerTraits {
+        let total: f32 = self.groups.iter().map(|group| group.share).sum();
+        let mut pick = rng.gen::<f32>() * total;
+        let mut parameters = &self.groups[self.groups.len() - 1];
+        for group in &self.groups {
+            if pick < group.share {
+                parameters = group;
+                break;
+            }
+            pick -= group.share;
+        }
+
+        let body_mass = normal(rng, parameters.mass_mean, parameters.mass_std)
+            .clamp(parameters.mass_mean / 3., parameters.mass_mean * 3.);
+        let luggage_pick: f32 = rng.gen();
+        let (luggage, luggage_mass, luggage_time) = if luggage_pick < parameters.trolley_probability {
+            (Luggage::Trolley, self.trolley_mass, self.trolley_time)
+        } else if luggage_pick < parameters.trolley_probability + parameters.bag_probability {
+            (Luggage::Bag, self.bag_mass, self.bag_time)
+        } else {
+            (Luggage::None, 0., 0.)
+        };
+
+        let door_time = parameters.boarding_time + luggage_time;
+        PassengerTraits {
+            age_group: parameters.group,
+            luggage,
+            mass: body_mass + luggage_mass,
+            boarding_duration: self.hall_distance / parameters.walking_speed + door_time,
+            alighting_duration: door_time,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn read() {
+        let demographics = Demographics::from_file("param/demographics.yaml").unwrap();
+        assert!(demographics.group(AgeGroup::Senior).is_some());
+    }
+
+    #[test]
+    fn rejects_groups_that_can_not_be_sampled() {
+        assert!(Demographics::default().validate().is_ok());
+
+        let empty = Demographics { groups: Vec::new(), ..Default::default() };
+        assert!(empty.validate().is_err());
+        let mut standing = Demographics::default();
+        standing.groups[1].walking_speed = 0.;
+        assert!(standing.validate().is_err());
+        let mut no_share = Demographics::default();
+        no_share.groups.iter_mut().for_each(|group| group.share = 0.);
+        assert!(no_share.validate().is_err());
+    }
+
+    #[test]
+    fn groups_differ() {
+        let demographics = Demographics::default();
+        let mut rng = StdRng::seed_from_u64(1);
+        let samples: Vec<PassengerTraits> = (0..20000).map(|_| demographics.sample(&mut rng)).collect();
+
+        let of = |age_group: AgeGroup| samples.iter().filter(|s| s.age_group == age_group).collect::<Vec<_>>();
+        let mean = |samples: &[&PassengerTraits], value: fn(&PassengerTraits) -> f32| {
+            samples.iter().map(|s| value(s)).sum::<f32>() / samples.len() as f32
+        };
+        let children = of(AgeGroup::Child);
+        let adults = of(AgeGroup::Adult);
+        let seniors = of(AgeGroup::Senior);
+
+        // shares of the population
+        assert!((children.len() as f32 / 20000. - 0.1).abs() < 0.01);
+        assert!((seniors.len() as f32 / 20000. - 0.2).abs() < 0.01);
+        // children are lighter, seniors are slower at the door
+        assert!(mean(&children, |s| s.mass) < mean(&adults, |s| s.mass) - 30.);
+        assert!(mean(&seniors, |s| s.boarding_duration) > mean(&adults, |s| s.boarding_duration));
+
+        // luggage is carried and takes time
+        let trolleys: Vec<_> = samples.iter().filter(|s| s.luggage == Luggage::Trolley).collect();
+        assert!(!trolleys.is_empty());
+        assert!(trolleys.iter().all(|s| s.alighting_duration >= 3.));
+        assert!(children.iter().all(|s| s.luggage != Luggage::Trolley));
+    }
+}
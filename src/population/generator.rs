@@ -0,0 +1,360 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Spawns passengers over the simulated time. The time between two passengers
+// comes from the arrival process, the origin and the destination are random
+// different floors and the mass is normally distributed, or comes with the age
+// group and the luggage when the demographics are given. The generator has
+// its own seeded rng, the same parameters give the same passengers.
+// With a traffic profile the rate changes over the day, the arrivals are
+// thinned from the highest rate of the day (Lewis-Shedler) and the trips come
+// from the origin/destination matrix of the time band.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::error::Error;
+
+use crate::random::normal;
+use super::demographics::Demographics;
+use super::population::Passenger;
+use super::traffic::{TrafficPreset, TrafficProfile};
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArrivalProcess {
+    // random arrivals, rate is passengers per hour
+    Poisson { rate: f32 },
+    // one passenger every interval seconds
+    Periodic { interval: f32 },
+    // random arrivals with the rate and the trips of the time of day
+    Traffic(TrafficProfile),
+    Preset { preset: TrafficPreset, peak_rate: f32 },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PopulationParameters {
+    pub arrival_process: ArrivalProcess,
+    pub mass_mean: f32,
+    pub mass_std: f32,
+    // age groups and luggage, the mass above is not used with them
+    #[serde(default)]
+    pub demographics: Option<Demographics>,
+    #[serde(default)]
+    pub seed: u64,
+    // hour of the day at the start of the simulation, the traffic profiles
+    // are in the hours of the day
+    #[serde(default)]
+    pub start_hour: f32,
+}
+
+impl PopulationParameters {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+
+    // the generator can not run in the building with these parameters: it
+    // would never stop spawning or could not find two different floors
+    pub fn validate(&self, num_floors: usize) -> Result<(), Box<dyn Error>> {
+        if num_floors < 2 {
+            return Err(format!("{} floors, a trip needs at least 2", num_floors).into());
+        }
+        if let Some(demographics) = &self.demographics {
+            demographics.validate()?;
+        }
+        match self.arrival_process {
+            ArrivalProcess::Poisson { rate } if rate < 0. || rate.is_nan() => {
+                Err(format!("the arrival rate {} is not a rate", rate).into())
+            },
+            ArrivalProcess::Periodic { interval } if interval <= 0. || !interval.is_finite() => {
+                Err(format!("the arrival interval {} is not a positive time", interval).into())
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct PassengerGenerator {
+    pub parameters: PopulationParameters,
+    num_floors: usize,
+    traffic: Option<TrafficProfile>,
+    band_matrices: Vec<Vec<Vec<f32>>>, // od matrix of every band of the traffic
+    max_rate: f32,                     // highest rate of the traffic
+    rng: StdRng,
+    next_spawn_time: f64,
+    next_id: u64,
+}
+
+impl PassengerGenerator {
+    pub fn new(parameters: PopulationParameters, num_floors: usize) -> Result<Self, Box<dyn Error>> {
+        parameters.validate(num_floors)?;
+        let traffic = match &parameters.arrival_process {
+            ArrivalProcess::Traffic(profile) => Some(profile.clone()),
+            ArrivalProcess::Preset { preset, peak_rate } => Some(preset.profile(*peak_rate)),
+            _ => None,
+        };
+        let mut band_matrices = Vec::new();
+        let mut max_rate = 0.;
+        if let Some(traffic) = &traffic {
+            traffic.validate(num_floors)?;
+            band_matrices = traffic.bands.iter().map(|band| band.pattern.matrix(num_floors)).collect();
+            max_rate = traffic.rate.max_rate();
+        }
+
+        let mut generator = Self {
+            rng: StdRng::seed_from_u64(parameters.seed),
+            parameters,
+            num_floors,
+            traffic,
+            band_matrices,
+            max_rate,
+            next_spawn_time: 0.,
+            next_id: 0,
+        };
+        generator.next_spawn_time = generator.next_arrival(0.);
+        Ok(generator)
+    }
+
+    pub fn from_file(file_path: &str, num_floors: usize) -> Result<Self, Box<dyn Error>> {
+        let parameters = PopulationParameters::from_file(file_path)?;
+        Self::new(parameters, num_floors)
+    }
+
+    pub fn next_spawn_time(&self) -> f64 {
+        self.next_spawn_time
+    }
+
+    // hour of the day at the given simulated time
+    pub fn hour(&self, time: f64) -> f32 {
+        ((self.parameters.start_hour as f64 + time / 3600.) % 24.) as f32
+    }
+
+    // exponential time between the arrivals
+    fn exponential(&mut self, rate: f32) -> f64 {
+        let u: f64 = self.rng.gen_range(f64::EPSILON..1.);
+        -u.ln() * 3600. / rate as f64
+    }
+
+    fn next_arrival(&mut self, after: f64) -> f64 {
+        match self.parameters.arrival_process {
+            ArrivalProcess::Poisson { rate } => after + self.exponential(rate),
+            ArrivalProcess::Periodic { interval } => after + interval as f64,
+            ArrivalProcess::Traffic(_) | ArrivalProcess::Preset { .. } => {
+                if self.max_rate <= 0. {
+                    return f64::INFINITY;
+                }
+                // candidates come with the highest rate, the ones at a
+                // quieter time are dropped
+                let mut time = after;
+                loop {
+                    time += self.exponential(self.max_rate);
+                    let rate = self.traffic.as_ref().map_or(0., |traffic| traffic.rate.rate_at(self.hour(time)));
+                    if self.rng.gen::<f32>() * self.max_rate < rate {
+                        return time;
+                    }
+                }
+            },
+        }
+    }
+
+    // origin and destination from the matrix of the band, any two different
+    // floors without one
+    fn trip(&mut self, time: f64) -> (usize, usize) {
+        let hour = self.hour(time);
+        let band = self.traffic.as_ref()
+            .and_then(|traffic| traffic.bands.iter().position(|band| band.start_hour <= hour && hour < band.end_hour));
+        if let Some(band) = band {
+            let matrix = &self.band_matrices[band];
+            let total: f32 = matrix.iter().flatten().sum();
+            if total > 0. {
+                let mut pick = self.rng.gen::<f32>() * total;
+                for (origin, row) in matrix.iter().enumerate() {
+                    for (destination, weight) in row.iter().enumerate() {
+                        if pick < *weight {
+                            return (origin, destination);
+                        }
+                        pick -= weight;
+                    }
+                }
+            }
+        }
+
+        let origin = self.rng.gen_range(0..self.num_floors);
+        // any other floor
+        let mut destination = self.rng.gen_range(0..self.num_floors - 1);
+        if destination >= origin {
+            destination += 1;
+        }
+        (origin, destination)
+    }
+
+    fn spawn(&mut self, time: f64) -> Passenger {
+        let (origin, destination) = self.trip(time);
+        let passenger = if let Some(demographics) = &self.parameters.demographics {
+            let traits = demographics.sample(&mut self.rng);
+            Passenger::from_traits(self.next_id, origin, destination, &traits, time)
+        } else {
+            let mass = normal(&mut self.rng, self.parameters.mass_mean, self.parameters.mass_std)
+                .clamp(self.parameters.mass_mean / 3., self.parameters.mass_mean * 3.);
+            Passenger::new(self.next_id, origin, destination, mass, time)
+        };
+        self.next_id += 1;
+        passenger
+    }
+
+    // every passenger that appeared until the given time, in spawn order
+    pub fn poll(&mut self, now: f64) -> Vec<Passenger> {
+        let mut passengers = Vec::new();
+        while self.next_spawn_time <= now {
+            let passenger = self.spawn(self.next_spawn_time);
+            passengers.push(passenger);
+            self.next_spawn_time = self.next_arrival(self.next_spawn_time);
+        }
+        passengers
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters(arrival_process: ArrivalProcess) -> PopulationParameters {
+        PopulationParameters { arrival_process, mass_mean: 75., mass_std: 12., demographics: None, seed: 7, start_hour: 0. }
+    }
+
+    #[test]
+    fn read() {
+        let parameters = PopulationParameters::from_file("param/population_parameters.yaml").unwrap();
+        assert!(parameters.validate(4).is_ok());
+        assert!(parameters.mass_mean > 0. && parameters.mass_std >= 0.);
+        assert!(PassengerGenerator::from_file("param/population_parameters.yaml", 4).is_ok());
+    }
+
+    #[test]
+    fn rejects_endless_parameters() {
+        // no time between two passengers, poll would never return
+        let periodic = parameters(ArrivalProcess::Periodic { interval: 0. });
+        assert!(PassengerGenerator::new(periodic.clone(), 4).is_err());
+        let periodic = parameters(ArrivalProcess::Periodic { interval: -1. });
+        assert!(PassengerGenerator::new(periodic, 4).is_err());
+        let poisson = parameters(ArrivalProcess::Poisson { rate: -10. });
+        assert!(PassengerGenerator::new(poisson, 4).is_err());
+        // no other floor to go to
+        let poisson = parameters(ArrivalProcess::Poisson { rate: 10. });
+        assert!(PassengerGenerator::new(poisson.clone(), 1).is_err());
+        assert!(PassengerGenerator::new(poisson.clone(), 0).is_err());
+        assert!(PassengerGenerator::new(poisson, 2).is_ok());
+    }
+
+    #[test]
+    fn rejects_traffic_of_another_building() {
+        // the matrix of the profile is for 4 floors
+        let profile = TrafficProfile::from_file("param/traffic_profile.yaml").unwrap();
+        let traffic = parameters(ArrivalProcess::Traffic(profile));
+        assert!(PassengerGenerator::new(traffic.clone(), 3).is_err());
+        assert!(PassengerGenerator::new(traffic, 4).is_ok());
+    }
+
+    #[test]
+    fn poisson_rate() {
+        let mut generator = PassengerGenerator::new(parameters(ArrivalProcess::Poisson { rate: 600. }), 10).unwrap();
+        let passengers = generator.poll(36000.);
+        // 6000 expected in ten hours
+        assert!((passengers.len() as f32 - 6000.).abs() < 300.);
+
+        for passenger in &passengers {
+            assert!(passenger.origin != passenger.destination);
+            assert!(passenger.destination < 10);
+            assert!(passenger.mass > 25. && passenger.mass < 225.);
+        }
+        let mean_mass = passengers.iter().map(|p| p.mass).sum::<f32>() / passengers.len() as f32;
+        assert!((mean_mass - 75.).abs() < 1.);
+    }
+
+    #[test]
+    fn poll_in_steps() {
+        let mut generator = PassengerGenerator::new(parameters(ArrivalProcess::Periodic { interval: 10. }), 4).unwrap();
+        assert!(generator.poll(9.).is_empty());
+        let passengers = generator.poll(30.);
+        assert!(passengers.len() == 3);
+        assert!(passengers[2].spawn_time == 30. && passengers[2].id == 2);
+    }
+
+    #[test]
+    fn working_day() {
+        let mut parameters = parameters(ArrivalProcess::Preset { preset: TrafficPreset::OfficeDay, peak_rate: 300. });
+        parameters.start_hour = 6.;
+        let profile = TrafficPreset::OfficeDay.profile(300.);
+        let mut generator = PassengerGenerator::new(parameters, 8).unwrap();
+        // 6:00 to 20:00
+        let passengers = generator.poll(14. * 3600.);
+
+        let expected = profile.rate.expected_passengers(6., 20.);
+        assert!((passengers.len() as f32 - expected).abs() < expected * 0.1);
+
+        // nobody comes before the building opens
+        assert!(passengers[0].spawn_time > 3600.);
+        let between = |from: f32, to: f32| passengers.iter()
+            .filter(|p| (from..to).contains(&generator.hour(p.spawn_time)))
+            .collect::<Vec<_>>();
+        let morning = between(8., 10.);
+        let evening = between(17., 19.);
+        assert!(morning.iter().filter(|p| p.origin == 0).count() > morning.len() * 3 / 4);
+        assert!(evening.iter().filter(|p| p.destination == 0).count() > evening.len() * 3 / 4);
+    }
+
+    #[test]
+    fn traffic_from_yaml() {
+        let lunch: PopulationParameters = serde_yaml::from_str("
+            arrival_process:
+                type: preset
+                preset: lunch
+                peak_rate: 200.
+            mass_mean: 75.
+            mass_std: 12.
+            start_hour: 11.
+        ").unwrap();
+        let mut generator = PassengerGenerator::new(lunch, 5).unwrap();
+        assert!(!generator.poll(3. * 3600.).is_empty());
+
+        let profile = TrafficProfile::from_file("param/traffic_profile.yaml").unwrap();
+        let mut parameters = parameters(ArrivalProcess::Traffic(profile));
+        parameters.start_hour = 11.;
+        let mut generator = PassengerGenerator::new(parameters, 4).unwrap();
+        let passengers = generator.poll(3. * 3600.);
+        // 13 of the 16 weights of the lunch matrix are trips from or to the lobby
+        let lobby_trips = passengers.iter().filter(|p| p.origin == 0 || p.destination == 0).count();
+        assert!(lobby_trips as f32 > passengers.len() as f32 * 0.7);
+        assert!(passengers.iter().all(|p| p.origin != p.destination));
+    }
+
+    #[test]
+    fn demographics() {
+        let mut parameters = parameters(ArrivalProcess::Poisson { rate: 600. });
+        parameters.demographics = Some(Demographics::from_file("param/demographics.yaml").unwrap());
+        let mut generator = PassengerGenerator::new(parameters, 6).unwrap();
+        let passengers = generator.poll(36000.);
+
+        // children pull the mean down, the luggage pushes it up
+        let mean_mass = passengers.iter().map(|p| p.mass).sum::<f32>() / passengers.len() as f32;
+        assert!(mean_mass > 65. && mean_mass < 80., "{}", mean_mass);
+        let slow = passengers.iter().filter(|p| p.boarding_duration > 4.).count();
+        assert!(slow > 0 && slow < passengers.len() / 2);
+        assert!(passengers.iter().all(|p| p.boarding_duration > p.alighting_duration));
+
+        // nobody to sample
+        let mut nobody = generator.parameters.clone();
+        nobody.demographics = Some(Demographics { groups: Vec::new(), ..Default::default() });
+        assert!(PassengerGenerator::new(nobody, 6).is_err());
+    }
+
+    #[test]
+    fn same_seed_same_passengers() {
+        let mut first = PassengerGenerator::new(parameters(ArrivalProcess::Poisson { rate: 100. }), 8).unwrap();
+        let mut second = PassengerGenerator::new(parameters(ArrivalProcess::Poisson { rate: 100. }), 8).unwrap();
+        assert!(first.poll(3600.) == second.poll(3600.));
+    }
+}
@@ -0,0 +1,304 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Service quality of the elevators for the passengers. For every passenger
+// the hall call, the boarding and the arrival time are recorded, from them:
+//   waiting  hall call until the car came, the people still waiting count
+//            with the time they waited so far
+//   journey  hall call until the destination (time to destination), only the
+//            ones who arrived
+//   ride     in the car
+// The mean, the 50th/90th/95th/99th percentiles and the maximum of them and
+// the share of the people who waited longer than long_wait (60 s, a common
+// limit of good service) are given for everyone, for every origin floor,
+// for every car and for every time band of the day.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::machine::elevator_system::ElevatorSystem;
+use super::population::Passenger;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct MetricsParameters {
+    pub long_wait: f64,    // seconds
+    pub band_minutes: u32, // length of a time band
+    pub start_hour: f32,   // hour of the day at the start of the simulation
+}
+
+impl Default for MetricsParameters {
+    fn default() -> Self {
+        Self { long_wait: 60., band_minutes: 15, start_hour: 0. }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PassengerRecord {
+    pub id: u64,
+    pub origin: usize,
+    pub destination: usize,
+    pub car: Option<usize>, // the car the passenger got in
+    pub hall_call_time: f64,
+    pub board_time: Option<f64>,
+    pub arrival_time: Option<f64>,
+}
+
+impl From<&Passenger> for PassengerRecord {
+    fn from(passenger: &Passenger) -> Self {
+        Self {
+            id: passenger.id,
+            origin: passenger.origin,
+            destination: passenger.destination,
+            car: passenger.board_time.and(passenger.assigned_car),
+            hall_call_time: passenger.spawn_time,
+            board_time: passenger.board_time,
+            arrival_time: passenger.arrival_time,
+        }
+    }
+}
+
+impl PassengerRecord {
+    // until the car came, or until now for the ones still waiting
+    pub fn waiting_time(&self, now: f64) -> f64 {
+        self.board_time.unwrap_or(now) - self.hall_call_time
+    }
+
+    pub fn journey_time(&self) -> Option<f64> {
+        self.arrival_time.map(|time| time - self.hall_call_time)
+    }
+
+    pub fn ride_time(&self) -> Option<f64> {
+        Some(self.arrival_time? - self.board_time?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct Statistics {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Statistics {
+    // zeros without values
+    pub fn new(values: &[f64]) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        Self {
+            count: sorted.len(),
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: percentile(&sorted, 50.),
+            p90: percentile(&sorted, 90.),
+            p95: percentile(&sorted, 95.),
+            p99: percentile(&sorted, 99.),
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+// linear between the two closest ranks, of sorted values
+pub fn percentile(sorted: &[f64], percent: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.;
+    }
+    let rank = percent.clamp(0., 100.) / 100. * (sorted.len() - 1) as f64;
+    let (low, high) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[low] + (sorted[high] - sorted[low]) * (rank - low as f64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct ServiceKpis {
+    pub passengers: usize,
+    pub boarded: usize,
+    pub arrived: usize,
+    pub waiting: Statistics,
+    pub journey: Statistics,
+    pub ride: Statistics,
+    pub long_wait_share: f64, // of everyone, waited or waits longer than long_wait
+}
+
+impl ServiceKpis {
+    pub fn new(records: &[&PassengerRecord], now: f64, long_wait: f64) -> Self {
+        let waiting: Vec<f64> = records.iter().map(|record| record.waiting_time(now)).collect();
+        let journey: Vec<f64> = records.iter().filter_map(|record| record.journey_time()).collect();
+        let ride: Vec<f64> = records.iter().filter_map(|record| record.ride_time()).collect();
+        let long = waiting.iter().filter(|time| **time > long_wait).count();
+        Self {
+            passengers: records.len(),
+            boarded: records.iter().filter(|record| record.board_time.is_some()).count(),
+            arrived: journey.len(),
+            waiting: Statistics::new(&waiting),
+            journey: Statistics::new(&journey),
+            ride: Statistics::new(&ride),
+            long_wait_share: if records.is_empty() { 0. } else { long as f64 / records.len() as f64 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServiceReport {
+    pub time: f64,
+    pub overall: ServiceKpis,
+    pub per_floor: BTreeMap<usize, ServiceKpis>, // of the origin
+    pub per_car: BTreeMap<usize, ServiceKpis>,   // of the ones who got in
+    pub per_band: BTreeMap<String, ServiceKpis>, // hh:mm at the start of the band of the hall call
+}
+
+impl ServiceReport {
+    pub fn to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServiceMetrics {
+    pub parameters: MetricsParameters,
+    pub records: BTreeMap<usize, PassengerRecord>, // by the index in ElevatorSystem::passengers
+}
+
+impl ServiceMetrics {
+    pub fn new(parameters: MetricsParameters) -> Self {
+        Self { parameters, records: BTreeMap::new() }
+    }
+
+    // every passenger of the system so far
+    pub fn from_system(parameters: MetricsParameters, system: &ElevatorSystem) -> Self {
+        let mut metrics = Self::new(parameters);
+        metrics.record_all(&system.passengers);
+        metrics
+    }
+
+    // the times of the passengers, a passenger recorded before is updated
+    pub fn record_all(&mut self, passengers: &[Passenger]) {
+        for (index, passenger) in passengers.iter().enumerate() {
+            self.record(index, passenger);
+        }
+    }
+
+    pub fn record(&mut self, index: usize, passenger: &Passenger) {
+        self.records.insert(index, PassengerRecord::from(passenger));
+    }
+
+    // hh:mm of the start of the band of the time
+    pub fn band_of(&self, time: f64) -> String {
+        let band = self.parameters.band_minutes.max(1) as f64;
+        let minutes = self.parameters.start_hour as f64 * 60. + time / 60.;
+        let start = (minutes / band).floor() * band;
+        let start = start.rem_euclid(24. * 60.) as u32;
+        format!("{:02}:{:02}", start / 60, start % 60)
+    }
+
+    fn kpis(&self, records: &[&PassengerRecord], now: f64) -> ServiceKpis {
+        ServiceKpis::new(records, now, self.parameters.long_wait)
+    }
+
+    fn grouped<K: Ord>(&self, now: f64, key: impl Fn(&PassengerRecord) -> Option<K>) -> BTreeMap<K, ServiceKpis> {
+        let mut groups: BTreeMap<K, Vec<&PassengerRecord>> = BTreeMap::new();
+        for record in self.records.values() {
+            if let Some(key) = key(record) {
+                groups.entry(key).or_default().push(record);
+            }
+        }
+        groups.into_iter().map(|(key, records)| (key, self.kpis(&records, now))).collect()
+    }
+
+    // the people still waiting count until now
+    pub fn report(&self, now: f64) -> ServiceReport {
+        let all: Vec<&PassengerRecord> = self.records.values().collect();
+        ServiceReport {
+            time: now,
+            overall: self.kpis(&all, now),
+            per_floor: self.grouped(now, |record| Some(record.origin)),
+            per_car: self.grouped(now, |record| record.car),
+            per_band: self.grouped(now, |record| Some(self.band_of(record.hall_call_time))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{floors, population, temp_path};
+    use crate::population::generator::{ArrivalProcess, PassengerGenerator};
+    use crate::population::traffic::TrafficPreset;
+
+    #[test]
+    fn percentiles() {
+        let values: Vec<f64> = (1..=101).map(|value| value as f64).collect();
+        let statistics = Statistics::new(&values);
+        assert!(statistics.count == 101 && statistics.mean == 51.);
+        assert!(statistics.p50 == 51. && statistics.p90 == 91. && statistics.p99 == 100. && statistics.max == 101.);
+        assert!((percentile(&[10., 20.], 95.) - 19.5).abs() < 1e-9);
+        assert!(Statistics::new(&[]) == Statistics::default());
+    }
+
+    #[test]
+    fn groups_and_long_waits() {
+        let mut passengers = vec![
+            Passenger::new(0, 0, 3, 75., 10.),
+            Passenger::new(1, 0, 2, 75., 20.),
+            Passenger::new(2, 2, 0, 75., 950.),
+            Passenger::new(3, 1, 0, 75., 960.),
+        ];
+        passengers[0].board(0, 20.);
+        passengers[0].alight(40.);
+        passengers[1].board(1, 100.);
+        passengers[2].board(1, 960.);
+        let mut metrics = ServiceMetrics::new(MetricsParameters { start_hour: 8., ..Default::default() });
+        metrics.record_all(&passengers);
+        // recorded again after the ride, not twice
+        passengers[2].alight(980.);
+        metrics.record_all(&passengers);
+        assert!(metrics.records.len() == 4);
+
+        let report = metrics.report(1030.);
+        let overall = report.overall;
+        assert!(overall.passengers == 4 && overall.boarded == 3 && overall.arrived == 2);
+        // 10, 80, 10 and 70 s still waiting
+        assert!(overall.waiting.mean == 42.5 && overall.waiting.max == 80.);
+        assert!(overall.long_wait_share == 0.5);
+        assert!(overall.journey.max == 30. && overall.ride.mean == 20.);
+
+        assert!(report.per_floor[&0].passengers == 2 && report.per_floor[&0].waiting.mean == 45.);
+        assert!(report.per_car.len() == 2 && report.per_car[&1].passengers == 2);
+        assert!(report.per_band.keys().cloned().collect::<Vec<_>>() == vec!["08:00".to_string(), "08:15".to_string()]);
+        assert!(report.per_band["08:15"].long_wait_share == 0.5);
+    }
+
+    #[test]
+    fn report_of_a_simulation() {
+        let parameters = population(ArrivalProcess::Preset { preset: TrafficPreset::UpPeak, peak_rate: 600. }, 3, 8.5);
+        let mut system = ElevatorSystem::new(2, floors(6));
+        system.set_generator(PassengerGenerator::new(parameters, 6).unwrap());
+        system.run_for(600.);
+        system.generator = None;
+        system.run_for(300.);
+
+        let metrics = ServiceMetrics::from_system(MetricsParameters { start_hour: 8.5, ..Default::default() }, &system);
+        let report = metrics.report(system.now());
+        let overall = report.overall;
+        assert!(overall.passengers == system.passengers.len() && overall.arrived == overall.passengers);
+        assert!(overall.waiting.p50 <= overall.waiting.p90 && overall.waiting.p99 <= overall.waiting.max);
+        assert!(overall.journey.mean > overall.waiting.mean);
+        // up peak, almost everyone from the lobby
+        assert!(report.per_floor[&0].passengers * 2 > overall.passengers);
+        assert!(report.per_car.values().map(|kpis| kpis.passengers).sum::<usize>() == overall.passengers);
+        assert!(report.per_band.keys().next().map(String::as_str) == Some("08:30"));
+
+        let file_path = temp_path("elevator_service_report.yaml");
+        report.to_file(&file_path).unwrap();
+        std::fs::remove_file(file_path).unwrap();
+    }
+}
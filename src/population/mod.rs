@@ -1,2 +1,10 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
-// Copyright (C) 2024 Tuna Gül
\ No newline at end of file
+// Copyright (C) 2024 Tuna Gül
+
+#[allow(clippy::module_inception)]
+pub mod population;
+pub mod generator;
+pub mod traffic;
+pub mod demographics;
+pub mod trace;
+pub mod metrics;
@@ -1,2 +1,161 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
-// Copyright (C) 2024 Tuna Gül
\ No newline at end of file
+// Copyright (C) 2024 Tuna Gül
+
+// A person using the elevators. A passenger waits at the origin floor until a
+// car opens its doors there, rides it to the destination and leaves. The
+// times of every step are kept for the statistics.
+// The age group and the luggage (demographics.rs) decide how heavy the
+// passenger is and how long the doors have to stay open for them.
+// With destination control the hall shows the passenger the letter of a car,
+// only that car takes them.
+
+use super::demographics::{AgeGroup, Luggage, PassengerTraits};
+use crate::control_algorithms::Direction;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PassengerState {
+    Waiting,
+    Riding { car: usize },
+    Arrived,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Passenger {
+    pub id: u64,
+    pub origin: usize,      // floor index
+    pub destination: usize, // floor index
+    pub mass: f32,          // kg with the luggage, the car carries it while riding
+    pub spawn_time: f64,    // the hall call is made at this time
+    pub age_group: AgeGroup,
+    pub luggage: Luggage,
+    pub boarding_duration: f32,  // seconds from the hall into the car
+    pub alighting_duration: f32, // seconds out of the car
+    pub state: PassengerState,
+    // shown on the keypad with destination control, the car the passenger
+    // got in once boarded
+    pub assigned_car: Option<usize>,
+    pub board_time: Option<f64>,
+    pub arrival_time: Option<f64>,
+}
+
+impl Passenger {
+    pub fn new(id: u64, origin: usize, destination: usize, mass: f32, spawn_time: f64) -> Self {
+        Self {
+            id,
+            origin,
+            destination,
+            mass,
+            spawn_time,
+            // an adult without luggage next to the doors
+            age_group: AgeGroup::Adult,
+            luggage: Luggage::None,
+            boarding_duration: 1.,
+            alighting_duration: 1.,
+            state: PassengerState::Waiting,
+            assigned_car: None,
+            board_time: None,
+            arrival_time: None,
+        }
+    }
+
+    pub fn from_traits(id: u64, origin: usize, destination: usize, traits: &PassengerTraits, spawn_time: f64) -> Self {
+        Self {
+            age_group: traits.age_group,
+            luggage: traits.luggage,
+            boarding_duration: traits.boarding_duration,
+            alighting_duration: traits.alighting_duration,
+            ..Self::new(id, origin, destination, traits.mass, spawn_time)
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        Direction::of_trip(self.origin, self.destination)
+    }
+
+    pub fn assign_car(&mut self, car: Option<usize>) {
+        self.assigned_car = car;
+    }
+
+    // the letter of the car on the keypad, A for the first car
+    pub fn car_letter(&self) -> Option<char> {
+        self.assigned_car.and_then(car_letter)
+    }
+
+    // without a letter any car going the right way will do
+    pub fn can_board(&self, car: usize) -> bool {
+        self.assigned_car.is_none_or(|assigned| assigned == car)
+    }
+
+    pub fn board(&mut self, car: usize, time: f64) {
+        self.state = PassengerState::Riding { car };
+        self.assigned_car = Some(car);
+        self.board_time = Some(time);
+    }
+
+    pub fn alight(&mut self, time: f64) {
+        self.state = PassengerState::Arrived;
+        self.arrival_time = Some(time);
+    }
+
+    // time from the hall call until the car came
+    pub fn waiting_time(&self) -> Option<f64> {
+        self.board_time.map(|time| time - self.spawn_time)
+    }
+
+    // time from the hall call until the destination
+    pub fn journey_time(&self) -> Option<f64> {
+        self.arrival_time.map(|time| time - self.spawn_time)
+    }
+}
+
+// a keypad has the letters A to Z, None for the 27th car and above
+pub fn car_letter(car: usize) -> Option<char> {
+    (car < 26).then(|| (b'A' + car as u8) as char)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trip() {
+        let mut passenger = Passenger::new(0, 3, 1, 80., 10.);
+        assert!(passenger.direction() == Direction::Down);
+        assert!(passenger.waiting_time().is_none());
+
+        passenger.board(1, 25.);
+        assert!(passenger.state == PassengerState::Riding { car: 1 });
+        passenger.alight(40.);
+        assert!(passenger.state == PassengerState::Arrived);
+        assert!(passenger.waiting_time() == Some(15.));
+        assert!(passenger.journey_time() == Some(30.));
+    }
+
+    #[test]
+    fn keeps_the_car() {
+        let mut passenger = Passenger::new(0, 3, 1, 80., 10.);
+        assert!(passenger.assigned_car.is_none());
+        passenger.board(1, 25.);
+        passenger.alight(40.);
+        // the car stays after the ride, for the statistics per car
+        assert!(passenger.assigned_car == Some(1));
+    }
+
+    #[test]
+    fn keypad_letter() {
+        let mut passenger = Passenger::new(0, 0, 5, 80., 0.);
+        assert!(passenger.car_letter().is_none() && passenger.can_board(3));
+        passenger.assign_car(Some(2));
+        assert!(passenger.car_letter() == Some('C'));
+        assert!(passenger.can_board(2) && !passenger.can_board(0));
+    }
+
+    #[test]
+    fn no_letter_past_z() {
+        assert!(car_letter(25) == Some('Z') && car_letter(26).is_none());
+        let mut passenger = Passenger::new(0, 0, 5, 80., 0.);
+        passenger.assign_car(Some(30));
+        assert!(passenger.car_letter().is_none() && passenger.can_board(30));
+    }
+}
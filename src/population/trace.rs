@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Recorded passengers (lobby counters, turnstiles) played back into the
+// simulation. Every record is a timestamp in seconds from the start of the
+// simulation, the origin and the destination floor and maybe the mass. The
+// passengers appear exactly at the recorded times, so two dispatchers can be
+// compared on the same real demand. The trace is read from csv
+// (data/lobby_trace.csv) or from a json list of records (data/lobby_trace.json).
+
+use serde::Deserialize;
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+
+use super::population::Passenger;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct TraceRecord {
+    pub timestamp: f64,
+    pub origin: usize,
+    pub destination: usize,
+    #[serde(default)]
+    pub mass: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub records: Vec<TraceRecord>, // in time order, same times keep the file order
+}
+
+impl Trace {
+    pub fn new(mut records: Vec<TraceRecord>) -> Self {
+        records.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+        Self { records }
+    }
+
+    pub fn from_csv(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(file_path)?;
+        let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(file);
+        let mut records = Vec::new();
+        for result in rdr.deserialize() {
+            let record: TraceRecord = result?;
+            records.push(record);
+        }
+        Ok(Self::new(records))
+    }
+
+    pub fn from_json(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(file_path)?;
+        let records: Vec<TraceRecord> = serde_json::from_reader(file)?;
+        Ok(Self::new(records))
+    }
+
+    // csv or json from the extension of the file
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let extension = Path::new(file_path).extension().and_then(|extension| extension.to_str());
+        match extension.map(|extension| extension.to_ascii_lowercase()).as_deref() {
+            Some("csv") => Self::from_csv(file_path),
+            Some("json") => Self::from_json(file_path),
+            _ => Err(format!("{} is not a csv or json trace", file_path).into()),
+        }
+    }
+
+    // the records have to fit the building
+    pub fn validate(&self, num_floors: usize) -> Result<(), Box<dyn Error>> {
+        for (i, record) in self.records.iter().enumerate() {
+            if !record.timestamp.is_finite() || record.timestamp < 0. {
+                return Err(format!("record {}: timestamp {} is not a time of the simulation", i, record.timestamp).into());
+            }
+            if record.origin >= num_floors || record.destination >= num_floors {
+                return Err(format!("record {}: floor {} or {} is not in the building", i, record.origin, record.destination).into());
+            }
+            if record.origin == record.destination {
+                return Err(format!("record {}: origin and destination are both {}", i, record.origin).into());
+            }
+            if record.mass.is_some_and(|mass| mass <= 0.) {
+                return Err(format!("record {}: mass {:?} is not positive", i, record.mass).into());
+            }
+        }
+        Ok(())
+    }
+
+    // simulated seconds from the first to the last passenger
+    pub fn duration(&self) -> f64 {
+        match (self.records.first(), self.records.last()) {
+            (Some(first), Some(last)) => last.timestamp - first.timestamp,
+            _ => 0.,
+        }
+    }
+}
+
+// ids of the replayed passengers start here, the generator counts up from 0
+// so the two never give the same id
+pub const REPLAY_FIRST_ID: u64 = 1 << 32;
+
+// gives the passengers of a trace as the simulated time passes, the same way
+// the generator does
+#[derive(Debug, Clone)]
+pub struct TraceReplay {
+    pub trace: Trace,
+    pub default_mass: f32, // for the records without a mass
+    next: usize,
+}
+
+impl TraceReplay {
+    pub fn new(trace: Trace, default_mass: f32) -> Self {
+        Self { trace, default_mass, next: 0 }
+    }
+
+    pub fn next_spawn_time(&self) -> Option<f64> {
+        self.trace.records.get(self.next).map(|record| record.timestamp)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.trace.records.len()
+    }
+
+    // every recorded passenger until the given time, with the recorded time
+    // as the spawn time. The ids are the places in the trace after REPLAY_FIRST_ID
+    pub fn poll(&mut self, now: f64) -> Vec<Passenger> {
+        let mut passengers = Vec::new();
+        while let Some(record) = self.trace.records.get(self.next) {
+            if record.timestamp > now {
+                break;
+            }
+            let mass = record.mass.unwrap_or(self.default_mass);
+            passengers.push(Passenger::new(REPLAY_FIRST_ID + self.next as u64, record.origin, record.destination, mass, record.timestamp));
+            self.next += 1;
+        }
+        passengers
+    }
+
+    // plays the trace again from the start
+    pub fn rewind(&mut self) {
+        self.next = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_and_json_agree() {
+        let csv = Trace::from_file("data/lobby_trace.csv").unwrap();
+        let json = Trace::from_file("data/lobby_trace.json").unwrap();
+        assert!(csv == json);
+        assert!(csv.records.len() == 5);
+        assert!(csv.records[1].mass.is_none() && csv.records[2].mass == Some(64.));
+        assert!(csv.validate(4).is_ok());
+        assert!(csv.validate(3).is_err());
+        assert!((csv.duration() - 28.25).abs() < 1e-9);
+        assert!(Trace::from_file("param/demographics.yaml").is_err());
+    }
+
+    #[test]
+    fn replay_as_recorded() {
+        let record = |timestamp, origin, destination| TraceRecord { timestamp, origin, destination, mass: None };
+        // out of order in the file
+        let trace = Trace::new(vec![record(5., 0, 2), record(1.5, 1, 0), record(5., 3, 0)]);
+        let mut replay = TraceReplay::new(trace, 70.);
+        assert!(replay.poll(1.).is_empty());
+        assert!(replay.next_spawn_time() == Some(1.5));
+
+        // a late poll keeps the recorded times
+        let passengers = replay.poll(10.);
+        assert!(passengers.len() == 3);
+        assert!(passengers[0].spawn_time == 1.5 && passengers[0].mass == 70.);
+        assert!(passengers[1].origin == 0 && passengers[2].origin == 3);
+        assert!(passengers[2].id == REPLAY_FIRST_ID + 2);
+        assert!(replay.is_finished());
+
+        replay.rewind();
+        assert!(replay.poll(10.) == passengers);
+    }
+}
@@ -0,0 +1,330 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Traffic of a building over the day (insan yoğunluğu/saat). The arrival rate
+// is a curve over the hours of the day, given with points and interpolated
+// between them. The day is divided into time bands, every band has its own
+// origin/destination matrix: in the morning everyone comes from the lobby,
+// in the evening everyone goes down to it.
+// The presets are the classic office patterns, custom profiles are read from
+// yaml (param/traffic_profile.yaml).
+
+use serde::Deserialize;
+use std::error::Error;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    Step, // the rate of a point holds until the next point
+    #[default]
+    Linear,
+    Spline, // Catmull-Rom through the points, never below zero
+}
+
+// passengers per hour over the hours of the day, zero outside the points
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RateCurve {
+    pub points: Vec<(f32, f32)>, // (hour, passengers per hour), hours increasing
+    #[serde(default)]
+    pub interpolation: Interpolation,
+}
+
+impl RateCurve {
+    pub fn rate_at(&self, hour: f32) -> f32 {
+        let points = &self.points;
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            return 0.;
+        };
+        if hour < first.0 || hour > last.0 {
+            return 0.;
+        }
+        // the segment the hour is in
+        let i = points.iter().rposition(|point| point.0 <= hour).unwrap_or(0).min(points.len().saturating_sub(2));
+        if points.len() == 1 {
+            return first.1;
+        }
+        let (h0, r0) = points[i];
+        let (h1, r1) = points[i + 1];
+        let t = if h1 > h0 { ((hour - h0) / (h1 - h0)).clamp(0., 1.) } else { 0. };
+
+        match self.interpolation {
+            Interpolation::Step => if t < 1. { r0 } else { r1 },
+            Interpolation::Linear => r0 + (r1 - r0) * t,
+            Interpolation::Spline => {
+                // the tangents come from the neighbours, the ends are mirrored
+                let before = if i > 0 { points[i - 1].1 } else { 2. * r0 - r1 };
+                let after = if i + 2 < points.len() { points[i + 2].1 } else { 2. * r1 - r0 };
+                let t2 = t * t;
+                let t3 = t2 * t;
+                let rate = 0.5 * (2. * r0
+                    + (r1 - before) * t
+                    + (2. * before - 5. * r0 + 4. * r1 - after) * t2
+                    + (3. * r0 - before - 3. * r1 + after) * t3);
+                rate.max(0.)
+            },
+        }
+    }
+
+    // the highest rate of the day, checked every minute
+    pub fn max_rate(&self) -> f32 {
+        let points = self.points.iter().map(|point| point.1);
+        let minutes = (0..=24 * 60).map(|minute| self.rate_at(minute as f32 / 60.));
+        points.chain(minutes).fold(0., f32::max)
+    }
+
+    // passengers expected between the two hours
+    pub fn expected_passengers(&self, start_hour: f32, end_hour: f32) -> f32 {
+        let steps = ((end_hour - start_hour) * 60.).ceil().max(1.) as usize;
+        let step = (end_hour - start_hour) / steps as f32;
+        (0..steps).map(|i| self.rate_at(start_hour + (i as f32 + 0.5) * step) * step).sum()
+    }
+}
+
+// where the passengers of a time band come from and go to
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OdPattern {
+    // shares of the three kinds of trips, for any number of floors
+    Mix {
+        incoming: f32,   // lobby to the upper floors
+        outgoing: f32,   // upper floors to the lobby
+        interfloor: f32, // between the upper floors
+        #[serde(default)]
+        lobby: usize,
+    },
+    // weight of every origin (row) and destination (column) pair
+    Matrix { weights: Vec<Vec<f32>> },
+}
+
+impl OdPattern {
+    // the weights of every pair for the building, the diagonal is zero. A
+    // building without floors has an empty matrix
+    pub fn matrix(&self, num_floors: usize) -> Vec<Vec<f32>> {
+        match self {
+            OdPattern::Mix { incoming, outgoing, interfloor, lobby } => {
+                let upper_floors = num_floors.saturating_sub(1) as f32;
+                let mut matrix = vec![vec![0.; num_floors]; num_floors];
+                for (origin, row) in matrix.iter_mut().enumerate() {
+                    for (destination, weight) in row.iter_mut().enumerate() {
+                        if origin == destination {
+                            continue;
+                        }
+                        *weight = if origin == *lobby {
+                            incoming / upper_floors
+                        } else if destination == *lobby {
+                            outgoing / upper_floors
+                        } else if num_floors > 2 {
+                            interfloor / (upper_floors * (upper_floors - 1.))
+                        } else {
+                            0.
+                        };
+                    }
+                }
+                matrix
+            },
+            OdPattern::Matrix { weights } => {
+                let mut matrix = weights.clone();
+                for (i, row) in matrix.iter_mut().enumerate() {
+                    if let Some(weight) = row.get_mut(i) {
+                        *weight = 0.;
+                    }
+                }
+                matrix
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TimeBand {
+    pub start_hour: f32,
+    pub end_hour: f32,
+    pub pattern: OdPattern,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TrafficProfile {
+    #[serde(default)]
+    pub name: String,
+    pub rate: RateCurve,
+    // the first band the hour is in is used, uniform trips outside the bands
+    pub bands: Vec<TimeBand>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrafficPreset {
+    UpPeak,
+    Lunch,
+    DownPeak,
+    Interfloor,
+    OfficeDay, // all of the above from the morning to the evening
+}
+
+impl TrafficPreset {
+    pub const ALL: [TrafficPreset; 5] = [
+        TrafficPreset::UpPeak,
+        TrafficPreset::Lunch,
+        TrafficPreset::DownPeak,
+        TrafficPreset::Interfloor,
+        TrafficPreset::OfficeDay,
+    ];
+
+    // the rate curve goes up to peak_rate passengers per hour
+    pub fn profile(&self, peak_rate: f32) -> TrafficProfile {
+        let mix = |incoming, outgoing, interfloor| OdPattern::Mix { incoming, outgoing, interfloor, lobby: 0 };
+        let band = |start_hour, end_hour, pattern| TimeBand { start_hour, end_hour, pattern };
+        let curve = |points: &[(f32, f32)]| RateCurve {
+            points: points.iter().map(|(hour, share)| (*hour, share * peak_rate)).collect(),
+            interpolation: Interpolation::Linear,
+        };
+
+        let up_peak = mix(0.85, 0.05, 0.1);
+        let lunch = mix(0.45, 0.45, 0.1);
+        let down_peak = mix(0.05, 0.85, 0.1);
+        let interfloor = mix(0.1, 0.1, 0.8);
+
+        let (name, rate, bands) = match self {
+            TrafficPreset::UpPeak => (
+                "up_peak",
+                curve(&[(7., 0.1), (8., 0.6), (8.75, 1.), (9.25, 0.8), (10., 0.2), (11., 0.1)]),
+                vec![band(0., 24., up_peak)],
+            ),
+            TrafficPreset::Lunch => (
+                "lunch",
+                curve(&[(11.5, 0.2), (12., 0.8), (12.5, 1.), (13., 0.9), (13.5, 0.6), (14., 0.2)]),
+                vec![band(0., 24., lunch)],
+            ),
+            TrafficPreset::DownPeak => (
+                "down_peak",
+                curve(&[(16., 0.2), (17., 0.8), (17.5, 1.), (18., 0.7), (19., 0.1)]),
+                vec![band(0., 24., down_peak)],
+            ),
+            TrafficPreset::Interfloor => (
+                "interfloor",
+                curve(&[(0., 0.3), (24., 0.3)]),
+                vec![band(0., 24., interfloor)],
+            ),
+            TrafficPreset::OfficeDay => (
+                "office_day",
+                curve(&[
+                    (7., 0.1), (8., 0.6), (8.75, 1.), (9.25, 0.8), (10., 0.2),
+                    (11.5, 0.2), (12., 0.5), (12.5, 0.6), (13., 0.55), (13.5, 0.4), (14., 0.2),
+                    (16., 0.2), (17., 0.8), (17.5, 1.), (18., 0.7), (19., 0.1),
+                ]),
+                vec![
+                    band(0., 11., up_peak),
+                    band(11., 14., lunch),
+                    band(14., 16., interfloor),
+                    band(16., 24., down_peak),
+                ],
+            ),
+        };
+
+        TrafficProfile { name: name.to_string(), rate, bands }
+    }
+}
+
+impl TrafficProfile {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(file_path)?;
+        let result = serde_yaml::from_reader(file)?;
+        Ok(result)
+    }
+
+    pub fn band_at(&self, hour: f32) -> Option<&TimeBand> {
+        self.bands.iter().find(|band| band.start_hour <= hour && hour < band.end_hour)
+    }
+
+    // the matrices have to fit the building
+    pub fn validate(&self, num_floors: usize) -> Result<(), Box<dyn Error>> {
+        if num_floors == 0 {
+            return Err("the building has no floors".into());
+        }
+        for band in &self.bands {
+            if let OdPattern::Matrix { weights } = &band.pattern {
+                if weights.len() != num_floors || weights.iter().any(|row| row.len() != num_floors) {
+                    return Err(format!(
+                        "the matrix of the band {}-{} is not {}x{}",
+                        band.start_hour, band.end_hour, num_floors, num_floors,
+                    ).into());
+                }
+            }
+            if let OdPattern::Mix { lobby, .. } = &band.pattern {
+                if *lobby >= num_floors {
+                    return Err(format!("lobby {} is not a floor", lobby).into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolation() {
+        let mut curve = RateCurve {
+            points: vec![(8., 100.), (9., 300.), (10., 100.)],
+            interpolation: Interpolation::Linear,
+        };
+        assert!(curve.rate_at(7.) == 0.);
+        assert!((curve.rate_at(8.5) - 200.).abs() < 1e-3);
+        assert!(curve.rate_at(10.5) == 0.);
+        assert!((curve.expected_passengers(8., 10.) - 400.).abs() < 1.);
+
+        curve.interpolation = Interpolation::Step;
+        assert!(curve.rate_at(8.9) == 100.);
+
+        // the spline goes through the points and is smooth around the peak
+        curve.interpolation = Interpolation::Spline;
+        assert!((curve.rate_at(9.) - 300.).abs() < 1e-3);
+        assert!(curve.rate_at(8.9) > 280. && curve.rate_at(9.1) > 280.);
+        assert!(curve.max_rate() >= 300.);
+    }
+
+    #[test]
+    fn mix_matrix() {
+        let matrix = TrafficPreset::UpPeak.profile(100.).bands[0].pattern.matrix(5);
+        let total: f32 = matrix.iter().flatten().sum();
+        assert!((total - 1.).abs() < 1e-4);
+        let from_lobby: f32 = matrix[0].iter().sum();
+        assert!((from_lobby - 0.85).abs() < 1e-4);
+        for (i, row) in matrix.iter().enumerate() {
+            assert!(row[i] == 0.);
+        }
+
+        // no floors or only the lobby, there is no trip
+        let pattern = &TrafficPreset::UpPeak.profile(100.).bands[0].pattern;
+        assert!(pattern.matrix(0).is_empty());
+        assert!(pattern.matrix(1) == vec![vec![0.]]);
+        assert!(TrafficPreset::UpPeak.profile(100.).validate(0).is_err());
+    }
+
+    #[test]
+    fn presets() {
+        for preset in TrafficPreset::ALL {
+            let profile = preset.profile(200.);
+            assert!(profile.validate(6).is_ok());
+            assert!((profile.rate.max_rate() - if preset == TrafficPreset::Interfloor { 60. } else { 200. }).abs() < 1e-3);
+        }
+        // the morning comes up, the evening goes down
+        let day = TrafficPreset::OfficeDay.profile(200.);
+        let morning = day.band_at(8.5).unwrap().pattern.matrix(6);
+        let evening = day.band_at(17.5).unwrap().pattern.matrix(6);
+        assert!(morning[0].iter().sum::<f32>() > 0.5);
+        assert!(evening.iter().map(|row| row[0]).sum::<f32>() > 0.5);
+    }
+
+    #[test]
+    fn read() {
+        let profile = TrafficProfile::from_file("param/traffic_profile.yaml").unwrap();
+        assert!(profile.validate(4).is_ok());
+        assert!(profile.rate.max_rate() > 0.);
+        // a wrong building is found
+        assert!(profile.validate(3).is_err());
+    }
+}
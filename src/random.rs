@@ -0,0 +1,15 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Random draws shared by the passenger generator, the optimizers and the
+// networks.
+
+use rand::rngs::StdRng;
+use rand::Rng;
+
+// Box-Muller, rand 0.8 does not have a normal distribution without rand_distr
+pub fn normal(rng: &mut StdRng, mean: f32, std: f32) -> f32 {
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.);
+    let u2: f32 = rng.gen();
+    mean + std * (-2. * u1.ln()).sqrt() * (2. * std::f32::consts::PI * u2).cos()
+}
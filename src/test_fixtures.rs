@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Tuna Gül
+
+// Shared fixtures of the tests: floors 3 m apart, cars of the same machine as
+// ElevatorSystem::new, dispatch states without calls and seeded people.
+
+use crate::control_algorithms::{CarState, DispatchState};
+use crate::machine::elevator::Elevator;
+use crate::machine::elevator_system::CarStatus;
+use crate::population::generator::{ArrivalProcess, PopulationParameters};
+
+pub fn floors(num_floors: usize) -> Vec<f32> {
+    (0..num_floors).map(|floor| floor as f32 * 3.).collect()
+}
+
+// a car standing on the floor
+pub fn car(floors: &[f32], floor: usize) -> Elevator {
+    let mut elevator = Elevator::standard(floors.to_vec());
+    elevator.current_height = floors[floor];
+    elevator.profile.reset(floors[floor]);
+    elevator
+}
+
+// the given cars stand empty without a direction, nobody calls them
+pub fn dispatch_state<'a>(floors: &'a [f32], cars: &[&'a Elevator]) -> DispatchState<'a> {
+    DispatchState {
+        now: 0.,
+        floors,
+        dwell_time: 3.,
+        cars: cars.iter()
+            .map(|elevator| CarState { elevator, status: CarStatus::Idle, direction: None, passengers: 0, riding: &[] })
+            .collect(),
+        hall_calls: &[],
+        destination_calls: &[],
+        car_calls: &[],
+        passengers: &[],
+        waiting: &[],
+    }
+}
+
+// people of 75 ± 12 kg without demographics
+pub fn population(arrival_process: ArrivalProcess, seed: u64, start_hour: f32) -> PopulationParameters {
+    PopulationParameters {
+        arrival_process,
+        mass_mean: 75.,
+        mass_std: 12.,
+        demographics: None,
+        seed,
+        start_hour,
+    }
+}
+
+// a file in the temp directory that other test runs do not write at the same time
+pub fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+    path.to_str().unwrap().to_string()
+}